@@ -0,0 +1,229 @@
+// Tunables for the bot. Change them here and redeploy.
+
+use screeps::ResourceType;
+
+use crate::roles::harvester::HarvestMode;
+use crate::roles::role::Role;
+
+/// Percentage of builders that work on construction sites, the rest upgrade the controller.
+pub const BUILD_ENERGY_PERCENT: usize = 70;
+
+/// Name of the flag marking the room warriors should raid.
+pub const ATTACK_FLAG: &str = "attack";
+
+/// Whether harvesters are spawned with a carry part and upgrade the controller once every source
+/// container is taken or full, instead of idling.
+pub const HARVESTER_UPGRADE_WHEN_SATURATED: bool = true;
+
+/// Whether harvesters are spawned with a carry part and haul or upgrade while every source of
+/// their room is depleted, instead of waiting for the regeneration.
+pub const HARVESTER_HELP_WHEN_DEPLETED: bool = true;
+
+/// Roles whose bodies are sized to the room's energy capacity, so the spawn holds until it's
+/// full. Every other role is sized to the energy available and spawns right away.
+pub const WAIT_FOR_CAPACITY_ROLES: &[Role] = &[Role::Harvester, Role::Builder];
+
+/// Below this percentage of hits a friendly creep gets healed by a tower during combat instead
+/// of that tower attacking.
+pub const TOWER_CRITICAL_HITS_PERCENT: u32 = 40;
+
+/// Name of the flag marking the source keeper room warriors should clear.
+pub const SOURCE_KEEPER_FLAG: &str = "keeper";
+
+/// A source with a keeper this close is not safe to mine.
+pub const KEEPER_SAFE_RANGE: u32 = 5;
+
+/// A source is only mined while its lair needs more than this many ticks to spawn a new keeper.
+pub const KEEPER_LAIR_SAFE_TICKS: u32 = 50;
+
+/// A spawn holding less energy than this, what the cheapest worker body (work, carry, move) costs,
+/// is starving.
+pub const SPAWN_STARVING_ENERGY: u32 = 200;
+
+/// Consecutive starving ticks after which any creep carrying energy is sent to feed the spawn.
+pub const SPAWN_STARVING_TICKS: u32 = 50;
+
+/// Whether a hauler plans a chain of spawn/extension fills to empty its store in one trip.
+pub const FILL_PLAN_ENABLED: bool = true;
+
+/// Spawns and extensions further than this from the hauler are left out of its fill plan.
+pub const FILL_PLAN_RANGE: u32 = 10;
+
+/// Towers filled below this percentage get refilled by haulers.
+pub const TOWER_REFILL_PERCENT: u32 = 95;
+
+/// A tower needs at least this much free capacity to be worth a trip.
+pub const TOWER_MIN_DEPOSIT: u32 = 50;
+
+/// Whether creep births, role assignments, task changes and deaths are traced.
+pub const TRACE_LIFECYCLE: bool = false;
+
+/// How many lifecycle events are kept around for inspection.
+pub const LIFECYCLE_LOG_SIZE: usize = 100;
+
+/// Most energy all creeps and towers together spend repairing one structure in a tick.
+pub const MAX_REPAIR_ENERGY_PER_TICK: u32 = 20;
+
+/// Whether bodies can count on roads, where they need half the move parts they need on plains.
+pub const BODIES_ON_ROADS: bool = false;
+
+/// Containers this close to a source belong to it. Anything further, like the controller's
+/// container, is never used by harvesters.
+pub const SOURCE_CONTAINER_RANGE: u32 = 2;
+
+/// Ticks between two rounds of market trading.
+pub const MARKET_INTERVAL: u32 = 100;
+
+/// The game refuses more than 10 deals a tick.
+pub const MAX_DEALS_PER_TICK: usize = 10;
+
+/// Flags whose name starts with this are patrol waypoints, visited in name order.
+pub const PATROL_FLAG_PREFIX: &str = "patrol";
+
+/// Whether neutral walls get repaired.
+pub const REPAIR_WALLS: bool = true;
+
+/// Whether our ramparts get repaired.
+pub const REPAIR_RAMPARTS: bool = true;
+
+/// Energy that must remain after a renewal, on top of what the pending spawn needs.
+pub const RENEW_ENERGY_RESERVE: u32 = 100;
+
+/// Creeps next to a spawn get renewed below this many ticks to live.
+pub const RENEW_BELOW_TTL: u32 = 300;
+
+/// Bodies cheaper than this aren't worth renewing, a fresh one is as good.
+pub const RENEW_MIN_BODY_COST: u32 = 600;
+
+/// Whether harvesters pick the source with the most energy left among those about as close as
+/// the closest one, rather than just the closest.
+pub const PREFER_RICHER_SOURCES: bool = true;
+
+/// Sources at most this many tiles further than the closest count as about as close.
+pub const SOURCE_DISTANCE_TOLERANCE: u32 = 5;
+
+/// Rooms get a container next to the controller from this level on.
+pub const CONTROLLER_CONTAINER_MIN_RCL: u8 = 2;
+
+/// A container this close to the controller counts as the controller's.
+pub const CONTROLLER_CONTAINER_RANGE: u32 = 3;
+
+/// The storage link tops up the controller link once it's below this percentage.
+pub const LINK_CONTROLLER_REFILL_PERCENT: u32 = 50;
+
+/// Percentage of the energy a link sends that is lost on the way, as set by the game.
+pub const LINK_LOSS_PERCENT: u32 = 3;
+
+/// Name of the flag that draws suggested tower spots in its room.
+pub const TOWER_PLAN_FLAG: &str = "towers";
+
+/// How many tower spots get suggested.
+pub const TOWER_SUGGESTION_COUNT: usize = 6;
+
+/// Suggested tower spots are at most this far from the middle of the base.
+pub const TOWER_SUGGESTION_RADIUS: u32 = 6;
+
+/// Harvesters, haulers and builders flee hostiles this close.
+pub const FLEE_RANGE: u32 = 3;
+
+/// Builders keep repairing a structure until it's back to this percentage of its hits.
+pub const BUILDER_REPAIR_UNTIL_PERCENT: u32 = 90;
+
+/// Memory segment holding the room snapshots, kept out of the memory parsed every tick.
+pub const INTEL_SEGMENT: u8 = 1;
+
+/// Memory segment holding the stats.
+pub const STATS_SEGMENT: u8 = 2;
+
+/// Whether harvesters sit on a container next to their source, built when missing, or drop mine.
+pub const HARVEST_MODE: HarvestMode = HarvestMode::Static;
+
+/// Whether upgraders get their work parts boosted at a lab before heading to the controller.
+pub const BOOST_UPGRADERS: bool = false;
+
+/// Compound upgraders get boosted with, ghodium acid upgrades faster.
+pub const UPGRADE_BOOST: ResourceType = ResourceType::GhodiumAcid;
+
+/// Rooms storing more energy than this, storage and terminal together, send some to poorer ones.
+pub const TERMINAL_ENERGY_SURPLUS_ABOVE: u32 = 150_000;
+
+/// Rooms storing less energy than this get some from richer ones.
+pub const TERMINAL_ENERGY_DEFICIT_BELOW: u32 = 50_000;
+
+/// Energy moved by a single balancing send, the transfer cost comes on top.
+pub const TERMINAL_BALANCE_AMOUNT: u32 = 10_000;
+
+/// Towers only shoot hostiles further than this when they have surplus energy.
+pub const TOWER_EFFECTIVE_RANGE: u32 = 20;
+
+/// Towers filled to at least this percentage have energy to spare on far hostiles.
+pub const TOWER_SURPLUS_ENERGY_PERCENT: u32 = 90;
+
+/// Ticks a creep follows the path it found before searching a new one, 0 or 1 searches on every
+/// move.
+pub const MOVE_REUSE_PATH: u32 = 10;
+
+/// Whether a reserver gets spawned in place of a claimer once the GCL allows no more rooms.
+pub const RESERVE_WHEN_GCL_MAXED: bool = true;
+
+/// Most claim parts a reserver gets.
+pub const RESERVER_MAX_CLAIM_PARTS: u32 = 4;
+
+/// Fewest claim parts a reserver gets. A single one only makes up for the reservation's decay, so
+/// rooms that can't afford more don't spawn reservers.
+pub const RESERVER_MIN_CLAIM_PARTS: u32 = 2;
+
+/// Haulers go for tombstones and ruins holding at least this much energy, ahead of containers.
+pub const SCAVENGE_MIN_ENERGY: u32 = 100;
+
+/// Idle haulers move energy between storage and terminal to keep the terminal at its target.
+pub const BALANCE_STORAGE_TERMINAL: bool = true;
+
+/// Energy kept in a terminal on top of its reserve, for sends and deals.
+pub const TERMINAL_ENERGY_BUFFER: u32 = 20_000;
+
+/// Haulers leave the terminal alone while its energy is this close to the target.
+pub const TERMINAL_BALANCE_TOLERANCE: u32 = 2_000;
+
+/// Containers filled to this percentage, with energy spilling next to them, count as overflowing.
+pub const CONTAINER_OVERFLOW_PERCENT: u32 = 90;
+
+/// Energy on the ground around a nearly full container for it to count as overflowing.
+pub const CONTAINER_OVERFLOW_DROPPED: u32 = 100;
+
+/// Haulers spawned beyond the desired count while a room's containers overflow.
+pub const EMERGENCY_HAULERS_MAX: usize = 2;
+
+/// A reservation of a remote room is refreshed once it has fewer ticks left than this.
+pub const RESERVATION_REFRESH_BELOW: u32 = 1_000;
+
+/// Creeps with fewer ticks to live than this head to a spawn to be recycled, if they make it.
+pub const RECYCLE_BELOW_TTL: u32 = 60;
+
+/// Ticks between two checks of whether a dying creep can make it to a spawn.
+pub const RECYCLE_CHECK_INTERVAL: u32 = 5;
+
+/// Construction sites this close to one another are worked on as one project.
+pub const BUILD_CLUSTER_RANGE: u32 = 5;
+
+/// Draws each spawn's queue, role deficits and energy next to it, for tuning.
+pub const SHOW_SPAWN_QUEUE: bool = false;
+
+/// The first spawn of a claimed room is placed at least this far from its sources and controller.
+pub const SPAWN_LANDMARK_CLEARANCE: u8 = 3;
+
+/// A spawn busy spawning and short of energy is refilled before any extension, however far.
+pub const SPAWNING_SPAWN_FIRST: bool = true;
+
+/// Source containers go on the free tile closest to the spawn, or the storage, to shorten hauls.
+pub const SOURCE_CONTAINER_TOWARD_SPAWN: bool = true;
+
+/// Hostiles bringing this many attack, ranged attack and heal parts in all make a major threat.
+pub const THREAT_MAJOR_PARTS: u32 = 10;
+
+/// Defenders a room spawns against a major threat, one is enough for a minor one.
+pub const DEFENDERS_MAX: usize = 3;
+
+/// With this many construction sites in a peaceful room, towers stop repairing and leave the
+/// energy to the builders. 0 keeps them repairing.
+pub const TOWER_HOLD_REPAIR_SITES: usize = 5;
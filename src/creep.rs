@@ -1,14 +1,22 @@
 use crate::{
+    config::{
+        FLEE_RANGE, MOVE_REUSE_PATH, SPAWNING_SPAWN_FIRST, TOWER_MIN_DEPOSIT, TOWER_REFILL_PERCENT,
+    },
+    recycle::run_recycle,
     roles::harvester::Harvester,
-    roles::{builder::Builder, hauler::Hauler, role::Role},
+    roles::{
+        builder::Builder, hauler::Hauler, mineral_harvester::MineralHarvester, reserver::Reserver,
+        role::Role, warrior::Warrior,
+    },
     storage::*,
 };
 use log::*;
 use screeps::{
-    find, game, prelude::*, rooms, ConstructionSite, MoveToOptions, ObjectId, Part, PolyStyle,
-    Resource, ResourceType, ReturnCode, Room, RoomObject, RoomObjectProperties, RoomPosition,
-    Source, StructureContainer, StructureController, StructureExtension, StructureObject,
-    StructureTower, StructureType,
+    find, game, look, prelude::*, rooms, ConstructionSite, FindConstant, MoveToOptions, ObjectId,
+    Part, PolyStyle, Position, Resource, ResourceType, ReturnCode, Room, RoomCoordinate, RoomName,
+    RoomObject, RoomObjectProperties, RoomPosition, Source, StructureContainer,
+    StructureController, StructureExtension, StructureObject, StructureSpawn, StructureTower,
+    StructureType,
 };
 use std::collections::HashMap;
 use wasm_bindgen::prelude::*;
@@ -75,7 +83,7 @@ impl<'a> Creep<'a> {
     where
         T: HasPosition,
     {
-        let mut options = MoveToOptions::new();
+        let mut options = move_options();
         let mut poly_style = PolyStyle::default();
         poly_style = poly_style
             .fill("transparent")
@@ -84,6 +92,7 @@ impl<'a> Creep<'a> {
             .stroke_width(0.15)
             .line_style(screeps::LineDrawStyle::Dashed);
         options = options.visualize_path_style(poly_style);
+        remember_destination(&self.name(), target.pos());
         self.inner_creep.move_to_with_options(target, Some(options))
     }
     pub fn harvest<T>(&self, target: &T) -> ReturnCode
@@ -105,7 +114,7 @@ impl<'a> Creep<'a> {
         self.inner_creep.room()
     }
     pub fn pick_closest_energy_source(&self) -> Option<ObjectId<screeps::Source>> {
-        let source = self.pos().find_closest_by_path(find::SOURCES_ACTIVE);
+        let source = find_closest(self.pos(), find::SOURCES_ACTIVE);
 
         if let Some(val) = source {
             return Some(val.id());
@@ -113,7 +122,7 @@ impl<'a> Creep<'a> {
         None
     }
     pub fn get_value_to_transfer(&self, target_store: &screeps::Store) -> u32 {
-        let mut value_to_transfer = self
+        let carried = self
             .inner_creep
             .store()
             .get_used_capacity(Some(ResourceType::Energy));
@@ -121,11 +130,7 @@ impl<'a> Creep<'a> {
             .get_free_capacity(Some(ResourceType::Energy))
             .try_into()
             .expect("could not convert i32 to u32");
-
-        if target_free_store < value_to_transfer {
-            value_to_transfer = target_free_store;
-        }
-        value_to_transfer
+        transfer_amount(carried, target_free_store)
     }
 
     /// Will find the nearest unfilled extension
@@ -212,7 +217,7 @@ impl<'a> Creep<'a> {
         let spawn = spawns
             .iter()
             .filter(|s| s.store().get_free_capacity(Some(ResourceType::Energy)) > 0)
-            .last();
+            .max_by_key(|s| spawn_goes_first(s));
         let structures = room.find(find::MY_STRUCTURES);
         let storage = room.storage();
         let container_obj = structures
@@ -246,10 +251,14 @@ impl<'a> Creep<'a> {
         // TODO: Add container
         if let Some(ext) = self.find_unfilled_extension() {
             if let Some(s) = spawn {
-                if ext.pos().get_range_to(creep_pos) < s.pos().get_range_to(creep_pos) {
-                    Some(StructureObject::StructureExtension(ext))
-                } else {
+                if spawn_before_extension(
+                    spawn_goes_first(s),
+                    s.pos().get_range_to(creep_pos),
+                    ext.pos().get_range_to(creep_pos),
+                ) {
                     Some(StructureObject::StructureSpawn(s.clone()))
+                } else {
+                    Some(StructureObject::StructureExtension(ext))
                 }
             } else {
                 Some(StructureObject::StructureExtension(ext))
@@ -266,18 +275,110 @@ impl<'a> Creep<'a> {
             }
         }
     }
-    pub fn run(&self, creep_targets: &mut HashMap<String, CreepTarget>, has_hostiles: bool) {
+    /// Sends a worker with a hostile within `FLEE_RANGE` to the closest spawn or rampart out of
+    /// the hostiles' reach, or straight away from the closest hostile when there's none, dropping
+    /// its task. Returns whether it fled.
+    fn flee_from_hostiles(&self, creep_targets: &mut HashMap<String, CreepTarget>) -> bool {
+        let room = self.room().unwrap();
+        let hostiles = room.find(find::HOSTILE_CREEPS);
+        let ranges: Vec<u32> = hostiles
+            .iter()
+            .map(|h| h.pos().get_range_to(self.pos()))
+            .collect();
+        if !should_flee(self.role(), &ranges) {
+            return false;
+        }
+        creep_targets.remove(&self.name());
+        self.say("FLEE", false);
+        let out_of_reach = |pos: screeps::Position| {
+            hostiles
+                .iter()
+                .all(|h| h.pos().get_range_to(pos) > FLEE_RANGE)
+        };
+        let refuge = room
+            .find(find::MY_STRUCTURES)
+            .into_iter()
+            .filter(|s| {
+                matches!(
+                    s.structure_type(),
+                    StructureType::Spawn | StructureType::Rampart
+                )
+            })
+            .filter(|s| out_of_reach(s.pos()))
+            .min_by_key(|s| s.pos().get_range_to(self.pos()));
+        match refuge {
+            Some(s) => {
+                self.move_to(s.pos());
+            }
+            None => {
+                let closest = hostiles
+                    .iter()
+                    .min_by_key(|h| h.pos().get_range_to(self.pos()))
+                    .unwrap();
+                if let Some(dir) = closest.pos().get_direction_to(self.pos()) {
+                    self.inner_creep.move_direction(dir);
+                }
+            }
+        }
+        true
+    }
+
+    /// Heads to the room the creep was spawned in. Returns whether it's still on its way.
+    fn return_home(&self) -> bool {
+        let home = match home_room(&self.name()) {
+            Some(h) => h,
+            None => return false,
+        };
+        if self.room().map(|r| r.name()) == Some(home) {
+            return false;
+        }
+        let center = RoomCoordinate::new(25).unwrap();
+        self.move_to(Position::new(center, center, home));
+        self.say("HOME", false);
+        true
+    }
+
+    /// While `paused` builders stay put and warriors only defend.
+    pub fn run(
+        &self,
+        creep_targets: &mut HashMap<String, CreepTarget>,
+        has_hostiles: bool,
+        paused: bool,
+    ) {
         let name = self.name();
         if self.spawning() {
             return;
         }
+        if has_hostiles && self.flee_from_hostiles(creep_targets) {
+            return;
+        }
+        if run_recycle(self.inner_creep, creep_targets) {
+            return;
+        }
+        // workers of a room come back to it when they wander off, e.g. fleeing through an exit
+        if returns_home(self.role()) && self.return_home() {
+            return;
+        }
         let room = self.room().unwrap();
+        if let Some(CreepTarget::FeedSpawn(spawn_id)) = creep_targets.get(&name).cloned() {
+            let keep_target = match spawn_id.resolve() {
+                Some(spawn) => match self.deposit(spawn) {
+                    DepositCode::NotNear | DepositCode::NotDone => true,
+                    _ => false,
+                },
+                None => continue_to_unseen_room(self.inner_creep),
+            };
+            if !keep_target {
+                creep_targets.remove(&name);
+            }
+            return;
+        }
         match self.role() {
             Role::Harvester => {
                 let harvester = Harvester {
                     creep: self.inner_creep,
                 };
-                harvester.run();
+                harvester.run(creep_targets);
                 return;
             }
             Role::Hauler => {
@@ -292,10 +393,35 @@ impl<'a> Creep<'a> {
                 return;
             }
             Role::Builder => {
+                if paused {
+                    self.say("PAUSED", false);
+                    return;
+                }
                 let builder = Builder {
                     creep: self.inner_creep,
                 };
-                builder.run();
+                builder.run(creep_targets);
+                return;
+            }
+            Role::MineralHarvester => {
+                let mineral_harvester = MineralHarvester {
+                    creep: self.inner_creep,
+                };
+                mineral_harvester.run();
+                return;
+            }
+            Role::Reserver => {
+                let reserver = Reserver {
+                    creep: self.inner_creep,
+                };
+                reserver.run(creep_targets);
+                return;
+            }
+            Role::Warrior => {
+                let warrior = Warrior {
+                    creep: self.inner_creep,
+                };
+                warrior.run(creep_targets, paused);
                 return;
             }
             _ => {}
@@ -604,48 +730,41 @@ fn rnd_source_idx(max: usize) -> usize {
     js_sys::Math::floor(js_sys::Math::random() * max as f64) as usize
 }
 
-/// Finds the most empty tower with at least 150 energy free.
+/// Finds the least filled tower among those needing a refill, see `tower_needs_refill`.
 pub fn find_tower(room: Room) -> Option<StructureTower> {
     let structures = room.find(find::MY_STRUCTURES);
-    let tower_obj = structures
+    structures
         .into_iter()
-        .filter(|s| s.structure_type() == StructureType::Tower)
+        .filter_map(|s| match s {
+            StructureObject::StructureTower(t) => Some(t),
+            _ => None,
+        })
         .filter(|t| {
-            t.as_has_store()
-                .unwrap()
-                .store()
-                .get_free_capacity(Some(ResourceType::Energy))
-                > 0
+            let store = t.store();
+            tower_needs_refill(
+                store.get_used_capacity(Some(ResourceType::Energy)),
+                store.get_capacity(Some(ResourceType::Energy)),
+            )
         })
         .reduce(|res, next_t| {
-            if next_t
-                .as_has_store()
-                .unwrap()
-                .store()
-                .get_free_capacity(Some(ResourceType::Energy))
-                < res
-                    .as_has_store()
-                    .unwrap()
-                    .store()
-                    .get_free_capacity(Some(ResourceType::Energy))
-            {
+            let fill = |t: &StructureTower| {
+                let store = t.store();
+                store.get_used_capacity(Some(ResourceType::Energy)) as u64 * 100
+                    / store.get_capacity(Some(ResourceType::Energy)).max(1) as u64
+            };
+            if fill(&next_t) < fill(&res) {
                 next_t
             } else {
                 res
             }
         })
-        .take();
+}
 
-    match tower_obj {
-        Some(t_obj) => match t_obj {
-            StructureObject::StructureTower(t) => Some(t),
-            _ => {
-                warn!("expected only a tower here");
-                None
-            }
-        },
-        None => None,
-    }
+/// A tower needs a refill when it's below the refill percentage, as long as there's enough room
+/// left to be worth a deposit.
+pub fn tower_needs_refill(used: u32, capacity: u32) -> bool {
+    let free = capacity.saturating_sub(used);
+    used as u64 * 100 < capacity as u64 * TOWER_REFILL_PERCENT as u64 && free >= TOWER_MIN_DEPOSIT
 }
 
 pub fn obj_to_container(obj: &StructureObject) -> Option<StructureContainer> {
@@ -657,3 +776,298 @@ pub fn obj_to_container(obj: &StructureObject) -> Option<StructureContainer> {
         }
     }
 }
+
+/// Options every creep moves with. Paths are kept in the creep's memory and reused for a few
+/// ticks instead of being searched again on every move.
+pub fn move_options() -> MoveToOptions {
+    MoveToOptions::new().reuse_path(MOVE_REUSE_PATH)
+}
+
+/// Gives the creep `target` unless its body lacks a working part the task needs, in which case
+/// its current target is dropped too so the role falls back on something it can do. Returns
+/// whether the target was given.
+pub fn assign_target(
+    creep_targets: &mut HashMap<String, CreepTarget>,
+    creep: &screeps::Creep,
+    target: CreepTarget,
+) -> bool {
+    if let Some(part) = target.required_part() {
+        if !has_active_part(creep, part) {
+            warn!(
+                "{} can't take {} without a working {:?} part",
+                creep.name(),
+                target.name(),
+                part
+            );
+            creep_targets.remove(&creep.name());
+            return false;
+        }
+    }
+    creep_targets.insert(creep.name(), target);
+    true
+}
+
+/// Workers run once a hostile is within `FLEE_RANGE`, fighters stay.
+pub fn should_flee(role: &Role, hostile_ranges: &[u32]) -> bool {
+    let is_worker = matches!(role, Role::Harvester | Role::Hauler | Role::Builder);
+    is_worker && hostile_ranges.iter().any(|r| *r <= FLEE_RANGE)
+}
+
+pub fn has_active_part(creep: &screeps::Creep, part: Part) -> bool {
+    creep
+        .body()
+        .iter()
+        .any(|p| p.part() == part && p.hits() > 0)
+}
+
+/// Roles that walk back to their home room whenever they're out of it.
+pub fn returns_home(role: &Role) -> bool {
+    matches!(role, Role::Harvester | Role::Hauler | Role::Claimer)
+}
+
+/// Room the creep was spawned in, when known.
+pub fn home_room(name: &str) -> Option<RoomName> {
+    CREEPS_HOME.with(|creeps_home_refcell| creeps_home_refcell.borrow().get(name).copied())
+}
+
+/// The structure of one of the `expected` types on the tile. Targets kept by position go through
+/// this every tick, since what stands there can change, e.g. a destroyed container overbuilt
+/// with a rampart.
+pub fn structure_at(
+    room: &Room,
+    pos: Position,
+    expected: &[StructureType],
+) -> Option<StructureObject> {
+    let structures = room.look_for_at(look::STRUCTURES, &pos);
+    let found: Vec<StructureType> = structures.iter().map(|s| s.structure_type()).collect();
+    let idx = matching_structure(expected, &found);
+    if idx.is_none() && !found.is_empty() {
+        debug!(
+            "expected {:?} at {:?}, found {:?}, dropping the target",
+            expected, pos, found
+        );
+    }
+    idx.map(|i| structures.into_iter().nth(i).unwrap())
+}
+
+/// Index of the first structure type found on a tile that the target still expects.
+pub fn matching_structure(expected: &[StructureType], found: &[StructureType]) -> Option<usize> {
+    found.iter().position(|t| expected.contains(t))
+}
+
+/// Closest object by path, or by range when the path search comes back empty. The pathfinder
+/// gives up once it runs out of operations, in large or cluttered rooms even though a path
+/// exists, which would leave the creep idle.
+pub fn find_closest<T>(pos: Position, ty: T) -> Option<T::Item>
+where
+    T: FindConstant + Copy,
+{
+    or_closest_by_range(pos.find_closest_by_path(ty), || {
+        let closest = pos.find_closest_by_range(ty);
+        if closest.is_some() {
+            debug!("no path found from {:?}, going by range", pos);
+        }
+        closest
+    })
+}
+
+pub fn or_closest_by_range<T>(
+    by_path: Option<T>,
+    by_range: impl FnOnce() -> Option<T>,
+) -> Option<T> {
+    by_path.or_else(by_range)
+}
+
+/// Energy a transfer can move, worked out right before it happens: never more than the creep
+/// carries nor than the target has room for, either would fail the transfer.
+pub fn transfer_amount(carried: u32, target_free: u32) -> u32 {
+    carried.min(target_free)
+}
+
+/// Moves toward `pos`, remembering it as where the creep is headed, see `remember_destination`.
+pub fn move_towards(creep: &screeps::Creep, pos: Position) -> ReturnCode {
+    remember_destination(&creep.name(), pos);
+    creep.move_to_with_options(pos, Some(move_options()))
+}
+
+/// Keeps where the creep was last sent, so it carries on there on ticks the target's room can't
+/// be looked at and the target can't be resolved again.
+pub fn remember_destination(name: &str, pos: Position) {
+    CREEPS_DESTINATION.with(|creeps_destination_refcell| {
+        creeps_destination_refcell
+            .borrow_mut()
+            .insert(name.to_string(), pos);
+    });
+}
+
+pub fn destination(name: &str) -> Option<Position> {
+    CREEPS_DESTINATION
+        .with(|creeps_destination_refcell| creeps_destination_refcell.borrow().get(name).copied())
+}
+
+/// For a target that couldn't be resolved: when the creep was headed to a room out of sight, it
+/// keeps going there and the target is kept. Returns whether it did.
+pub fn continue_to_unseen_room(creep: &screeps::Creep) -> bool {
+    let dest = match unseen_destination(&creep.name(), |room| game::rooms().get(room).is_some()) {
+        Some(d) => d,
+        None => return false,
+    };
+    let _ = move_towards(creep, dest);
+    true
+}
+
+/// Where the creep was last sent, as long as that room is out of sight.
+pub fn unseen_destination(name: &str, visible: impl Fn(RoomName) -> bool) -> Option<Position> {
+    destination(name).filter(|d| !visible(d.room_name()))
+}
+
+/// Whether a spawn is filled ahead of the extensions: it's in the middle of spawning and not full,
+/// so the next creep isn't held up waiting on energy.
+pub fn spawn_goes_first(spawn: &StructureSpawn) -> bool {
+    spawning_priority(
+        spawn.spawning().is_some(),
+        spawn.store().get_free_capacity(Some(ResourceType::Energy)) as u32,
+    )
+}
+
+/// Whether the spawn is filled before an extension: it goes first, or it's no further away.
+pub fn spawn_before_extension(spawn_first: bool, spawn_range: u32, ext_range: u32) -> bool {
+    spawn_first || spawn_range <= ext_range
+}
+
+/// Turned off by `SPAWNING_SPAWN_FIRST`, spawns go by range like the extensions.
+pub fn spawning_priority(spawning: bool, energy_free: u32) -> bool {
+    SPAWNING_SPAWN_FIRST && spawning && energy_free > 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nearly_full_towers_are_left_alone() {
+        // the old threshold was any free capacity at all, sending haulers for a few energy
+        assert!(!tower_needs_refill(990, 1000));
+        assert!(!tower_needs_refill(1000, 1000));
+    }
+
+    #[test]
+    fn towers_below_the_refill_percentage_get_energy() {
+        assert!(tower_needs_refill(0, 1000));
+        assert!(tower_needs_refill(900, 1000));
+    }
+
+    #[test]
+    fn refill_needs_room_for_a_worthwhile_deposit() {
+        let capacity = TOWER_MIN_DEPOSIT * 2;
+        assert!(!tower_needs_refill(
+            capacity - TOWER_MIN_DEPOSIT + 1,
+            capacity
+        ));
+    }
+
+    #[test]
+    fn worker_with_an_adjacent_hostile_flees() {
+        assert!(should_flee(&Role::Harvester, &[1]));
+        assert!(should_flee(&Role::Hauler, &[30, FLEE_RANGE]));
+        assert!(!should_flee(&Role::Builder, &[FLEE_RANGE + 1]));
+        assert!(!should_flee(&Role::Builder, &[]));
+    }
+
+    #[test]
+    fn fighters_hold_their_ground() {
+        assert!(!should_flee(&Role::Warrior, &[1]));
+        assert!(!should_flee(&Role::Healer, &[1]));
+    }
+
+    #[test]
+    fn remote_workers_and_claimers_return_home() {
+        assert!(returns_home(&Role::Harvester));
+        assert!(returns_home(&Role::Hauler));
+        assert!(returns_home(&Role::Claimer));
+        assert!(!returns_home(&Role::Warrior));
+        assert!(!returns_home(&Role::Reserver));
+    }
+
+    #[test]
+    fn overbuilt_container_no_longer_matches() {
+        // the container was destroyed and a rampart built on its tile
+        assert_eq!(
+            matching_structure(&[StructureType::Container], &[StructureType::Rampart]),
+            None
+        );
+        assert_eq!(matching_structure(&[StructureType::Container], &[]), None);
+    }
+
+    #[test]
+    fn expected_structure_is_found_among_others_on_the_tile() {
+        assert_eq!(
+            matching_structure(
+                &[StructureType::Container],
+                &[StructureType::Rampart, StructureType::Container]
+            ),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn failed_path_search_falls_back_to_range() {
+        assert_eq!(or_closest_by_range(None, || Some(7)), Some(7));
+        assert_eq!(or_closest_by_range::<u32>(None, || None), None);
+    }
+
+    #[test]
+    fn found_path_skips_the_range_search() {
+        let closest = or_closest_by_range(Some(3), || -> Option<u32> {
+            panic!("searched by range although a path was found")
+        });
+        assert_eq!(closest, Some(3));
+    }
+
+    #[test]
+    fn transfer_is_capped_by_what_the_creep_still_carries() {
+        // selected with 100 carried, 60 left by the time of the transfer
+        assert_eq!(transfer_amount(60, 200), 60);
+        assert_eq!(transfer_amount(100, 40), 40);
+        assert_eq!(transfer_amount(0, 40), 0);
+    }
+
+    fn pos_in(room: &str) -> Position {
+        Position::new(
+            RoomCoordinate::new(25).unwrap(),
+            RoomCoordinate::new(25).unwrap(),
+            RoomName::new(room).unwrap(),
+        )
+    }
+
+    #[test]
+    fn creep_without_vision_keeps_its_cached_destination() {
+        remember_destination("hauler-1", pos_in("W2N1"));
+        assert_eq!(destination("hauler-1"), Some(pos_in("W2N1")));
+        assert_eq!(
+            unseen_destination("hauler-1", |_| false),
+            Some(pos_in("W2N1"))
+        );
+    }
+
+    #[test]
+    fn visible_or_unknown_destination_is_not_continued() {
+        remember_destination("hauler-2", pos_in("W2N1"));
+        assert_eq!(unseen_destination("hauler-2", |_| true), None);
+        assert_eq!(unseen_destination("hauler-3", |_| false), None);
+    }
+
+    #[test]
+    fn spawning_spawn_outranks_a_closer_extension() {
+        assert!(spawning_priority(true, 100));
+        assert!(spawn_before_extension(spawning_priority(true, 100), 10, 1));
+    }
+
+    #[test]
+    fn idle_or_full_spawn_goes_by_range() {
+        assert!(!spawning_priority(false, 100));
+        assert!(!spawning_priority(true, 0));
+        assert!(!spawn_before_extension(false, 10, 1));
+        assert!(spawn_before_extension(false, 3, 3));
+    }
+}
@@ -0,0 +1,106 @@
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{
+    find, game, prelude::*, ObjectId, Position, Room, RoomName, Structure, StructureType,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::storage::*;
+
+/// Owned structures of a room as seen on a given tick.
+#[derive(Serialize, Deserialize)]
+pub struct RoomSnapshot {
+    structures: HashMap<ObjectId<Structure>, (StructureType, Position)>,
+}
+
+impl RoomSnapshot {
+    pub fn take(room: &Room) -> Self {
+        let structures = room
+            .find(find::MY_STRUCTURES)
+            .iter()
+            .map(|s| (s.as_structure().id(), (s.structure_type(), s.pos())))
+            .collect();
+        Self { structures }
+    }
+
+    /// Structures that were in `previous` but are gone from this snapshot.
+    pub fn lost_since(&self, previous: &RoomSnapshot) -> Vec<(StructureType, Position)> {
+        previous
+            .structures
+            .iter()
+            .filter(|(id, _)| !self.structures.contains_key(id))
+            .map(|(_, lost)| *lost)
+            .collect()
+    }
+}
+
+/// Diffs every visible room against last tick's snapshot and reports the structures we lost.
+/// Rooms we had no vision of last tick are only snapshotted, never reported.
+pub fn track_structure_losses() {
+    ROOMS_SNAPSHOT.with(|rooms_snapshot_refcell| {
+        let mut rooms_snapshot = rooms_snapshot_refcell.borrow_mut();
+        let mut current = HashMap::<RoomName, RoomSnapshot>::new();
+        for room in game::rooms().values() {
+            let snapshot = RoomSnapshot::take(&room);
+            if let Some(previous) = rooms_snapshot.get(&room.name()) {
+                let lost = snapshot.lost_since(previous);
+                for (structure_type, pos) in lost.iter() {
+                    warn!("lost {:?} at {:?} in {}", structure_type, pos, room.name());
+                }
+                if lost.len() > 0 {
+                    STATS.with(|stats_refcell| {
+                        stats_refcell.borrow_mut().structures_lost += lost.len() as u32;
+                    });
+                }
+            }
+            current.insert(room.name(), snapshot);
+        }
+        *rooms_snapshot = current;
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screeps::RoomCoordinate;
+
+    fn snapshot(structures: &[(&str, StructureType, u8)]) -> RoomSnapshot {
+        let room = RoomName::new("W1N1").unwrap();
+        let structures = structures
+            .iter()
+            .map(|(id, structure_type, x)| {
+                let pos = Position::new(
+                    RoomCoordinate::new(*x).unwrap(),
+                    RoomCoordinate::new(25).unwrap(),
+                    room,
+                );
+                (id.parse().unwrap(), (*structure_type, pos))
+            })
+            .collect();
+        RoomSnapshot { structures }
+    }
+
+    #[test]
+    fn reports_structures_that_disappeared() {
+        let previous = snapshot(&[
+            ("5bbcab1d9099fc012e632f01", StructureType::Tower, 10),
+            ("5bbcab1d9099fc012e632f02", StructureType::Spawn, 20),
+        ]);
+        let current = snapshot(&[("5bbcab1d9099fc012e632f02", StructureType::Spawn, 20)]);
+        let lost = current.lost_since(&previous);
+        assert_eq!(lost.len(), 1);
+        assert_eq!(lost[0].0, StructureType::Tower);
+        assert_eq!(lost[0].1.x().u8(), 10);
+    }
+
+    #[test]
+    fn new_structures_are_not_losses() {
+        let previous = snapshot(&[("5bbcab1d9099fc012e632f01", StructureType::Tower, 10)]);
+        let current = snapshot(&[
+            ("5bbcab1d9099fc012e632f01", StructureType::Tower, 10),
+            ("5bbcab1d9099fc012e632f02", StructureType::Extension, 11),
+        ]);
+        assert!(current.lost_since(&previous).is_empty());
+    }
+}
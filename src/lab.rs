@@ -0,0 +1,169 @@
+use log::*;
+use screeps::{
+    find, prelude::*, ObjectId, Part, ResourceType, ReturnCode, StructureLab, StructureObject,
+};
+
+use crate::creep::{continue_to_unseen_room, move_towards};
+
+/// Compound a lab spends on each boosted part.
+const BOOST_COMPOUND_PER_PART: u32 = 30;
+
+/// Energy a lab spends on each boosted part.
+const BOOST_ENERGY_PER_PART: u32 = 20;
+
+/// Closest lab of the creep's room holding enough of `compound`, and of energy, to boost all of
+/// the creep's unboosted `part`s. `None` when boosting isn't `enabled`, there's nothing to boost
+/// or no lab can afford it.
+pub fn find_boost_lab(
+    creep: &screeps::Creep,
+    enabled: bool,
+    compound: ResourceType,
+    part: Part,
+) -> Option<StructureLab> {
+    if !enabled {
+        return None;
+    }
+    let parts = creep
+        .body()
+        .iter()
+        .filter(|p| p.part() == part && p.boost().is_none())
+        .count() as u32;
+    // the room's labs aren't worth looking up with nothing to boost
+    if parts == 0 {
+        return None;
+    }
+    let creep_pos = creep.pos();
+    let mut labs: Vec<StructureLab> = creep
+        .room()
+        .unwrap()
+        .find(find::MY_STRUCTURES)
+        .into_iter()
+        .filter_map(|s| match s {
+            StructureObject::StructureLab(l) => Some(l),
+            _ => None,
+        })
+        .collect();
+    let stocks: Vec<LabStock> = labs
+        .iter()
+        .map(|l| {
+            let store = l.store();
+            LabStock {
+                mineral: l.mineral_type(),
+                compound: store.get_used_capacity(Some(compound)),
+                energy: store.get_used_capacity(Some(ResourceType::Energy)),
+                range: l.pos().get_range_to(creep_pos),
+            }
+        })
+        .collect();
+    pick_boost_lab(enabled, compound, parts, &stocks).map(|i| labs.swap_remove(i))
+}
+
+/// What a lab holds and how far it is from the creep to boost.
+#[derive(Debug, Clone, Copy)]
+pub struct LabStock {
+    pub mineral: Option<ResourceType>,
+    pub compound: u32,
+    pub energy: u32,
+    pub range: u32,
+}
+
+/// Index of the closest of the `labs` able to boost `parts` parts with `compound`, when boosting
+/// is `enabled` and there's a part to boost.
+pub fn pick_boost_lab(
+    enabled: bool,
+    compound: ResourceType,
+    parts: u32,
+    labs: &[LabStock],
+) -> Option<usize> {
+    if !enabled || parts == 0 {
+        return None;
+    }
+    labs.iter()
+        .enumerate()
+        .filter(|(_, l)| l.mineral == Some(compound))
+        .filter(|(_, l)| can_boost(l.compound, l.energy, parts))
+        .min_by_key(|(_, l)| l.range)
+        .map(|(i, _)| i)
+}
+
+/// Whether a lab holding this much compound and energy can boost `parts` parts.
+pub fn can_boost(compound: u32, energy: u32, parts: u32) -> bool {
+    compound >= parts * BOOST_COMPOUND_PER_PART && energy >= parts * BOOST_ENERGY_PER_PART
+}
+
+/// Walks the creep to the lab and gets it boosted. Returns whether it still has to get there.
+pub fn run_boost(creep: &screeps::Creep, lab_id: ObjectId<StructureLab>) -> bool {
+    let lab = match lab_id.resolve() {
+        Some(l) => l,
+        None => return continue_to_unseen_room(creep),
+    };
+    if !creep.pos().is_near_to(lab.pos()) {
+        let r = move_towards(creep, lab.pos());
+        if r != ReturnCode::Ok && r != ReturnCode::Tired {
+            warn!("couldn't move to lab: {:?}", r);
+        }
+        return true;
+    }
+    let r = lab.boost_creep(creep, None);
+    if r == ReturnCode::Ok {
+        info!("boosted {}", creep.name());
+    } else {
+        // the compound may have run out meanwhile, carry on unboosted
+        warn!("couldn't boost {}: {:?}", creep.name(), r);
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lab_needs_enough_compound_and_energy_for_every_part() {
+        let parts = 5;
+        let compound = parts * BOOST_COMPOUND_PER_PART;
+        let energy = parts * BOOST_ENERGY_PER_PART;
+        assert!(can_boost(compound, energy, parts));
+        assert!(!can_boost(compound - 1, energy, parts));
+        assert!(!can_boost(compound, energy - 1, parts));
+    }
+
+    fn stocked(range: u32) -> LabStock {
+        LabStock {
+            mineral: Some(ResourceType::GhodiumAcid),
+            compound: 3000,
+            energy: 2000,
+            range,
+        }
+    }
+
+    #[test]
+    fn upgrader_with_boosting_enabled_goes_to_the_closest_stocked_lab() {
+        let empty = LabStock {
+            compound: 0,
+            ..stocked(1)
+        };
+        let other = LabStock {
+            mineral: Some(ResourceType::Oxygen),
+            ..stocked(2)
+        };
+        let labs = [empty, other, stocked(9), stocked(5)];
+        assert_eq!(
+            pick_boost_lab(true, ResourceType::GhodiumAcid, 10, &labs),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn no_boost_when_disabled_or_nothing_to_boost() {
+        let labs = [stocked(1)];
+        assert_eq!(
+            pick_boost_lab(false, ResourceType::GhodiumAcid, 10, &labs),
+            None
+        );
+        assert_eq!(
+            pick_boost_lab(true, ResourceType::GhodiumAcid, 0, &labs),
+            None
+        );
+    }
+}
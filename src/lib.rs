@@ -1,26 +1,48 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
+use config::{MARKET_INTERVAL, RESERVE_WHEN_GCL_MAXED, SHOW_SPAWN_QUEUE};
 use creep::*;
+use lifecycle::LifecycleEvent;
 use log::*;
-use roles::role::Role;
+use roles::harvester::assign_source;
+use roles::hauler::containers_overflowing;
+use roles::mineral_harvester::room_mineral_role;
+use roles::reserver::{self, reserver_affordable, reserver_wanted};
+use roles::role::{claimer_or_reserver, BodyPlan, Role};
+use roles::warrior::{self, room_threat, spawned_for_threat};
 use screeps::{
-    find, game, look, prelude::*, ObjectId, Part, RawMemory, ReturnCode, RoomObjectProperties,
-    Source, StructureObject,
+    find, game, look, prelude::*, ObjectId, Part, Position, RawMemory, ReturnCode, RoomName,
+    RoomObjectProperties, Source, StructureObject,
 };
 use storage::*;
 use tower::*;
 use wasm_bindgen::prelude::*;
 
+mod config;
 mod creep;
+mod intel;
+mod lab;
+mod lifecycle;
+mod link;
 mod logging;
+mod market;
+mod planner;
+mod rebalance;
+mod recycle;
+mod renew;
+mod repair;
 mod roles;
+mod segments;
 mod storage;
+mod terminal;
 mod tower;
+mod visuals;
+mod watchdog;
 
 // add wasm_bindgen to any function you would like to expose for call from js
 #[wasm_bindgen]
 pub fn setup() {
-    logging::setup_logging(logging::Info);
+    logging::setup_logging(logging::Info, HashMap::new());
 }
 
 // to use a reserved name as a function name, use `js_name`:
@@ -28,34 +50,129 @@ pub fn setup() {
 pub fn game_loop() {
     let time = screeps::game::time();
 
+    segments::request_active();
+    segments::restore();
+
+    // Memory is parsed once a tick, for the cleanup, the config reads and the spawns
+    let mut db = Database::init();
+    // While paused the colony only harvests, hauls and defends itself
+    let paused = db.as_ref().map(|d| d.data.paused).unwrap_or(false);
+    let run = Activities::for_tick(paused);
+
     if time % 32 == 3 {
-        let mut db = Database::init().expect("could not init database");
-        db.assign_roles();
-        info!("running memory cleanup");
-        db.clean_up();
+        if let Some(d) = db.as_mut() {
+            d.assign_roles();
+            info!("running memory cleanup");
+            d.clean_up();
+        }
+        clean_up_targets();
+        let reassigned = rebalance::rebalance_roles();
+        if let Some(d) = db.as_mut() {
+            // the persisted role wins over the name after a reset, keep it in sync
+            for (name, role) in reassigned {
+                if let Some(m) = d.get_mut_creep_memory(&name) {
+                    m.role = Some(role);
+                }
+            }
+            // one write for the cleanup and the reassigned roles
+            d.update_memory();
+        }
+        if run.planning {
+            planner::plan_containers();
+            planner::plan_first_spawns();
+        }
+        segments::persist();
     }
 
-    let mut num_creeps = 0;
-    // If a creep does not have a role, find the appropriate role and add it to the local storage
+    intel::track_structure_losses();
+    planner::show_tower_suggestions();
+
+    // creeps still in the spawn don't work yet, spawn decisions only count the others
+    let num_spawned = count_spawned(game::creeps().values().map(|c| c.spawning()));
+    // If a creep does not have a role, find the appropriate role and add it to the local storage.
+    // The role persisted in memory and the one in the name of creeps we spawned both survive a
+    // global reset, so they're tried before guessing from a body that may have lost parts.
+    let mut persisted_roles = false;
     CREEPS_ROLE.with(|creeps_role_refcell| {
         let mut creeps_role = creeps_role_refcell.borrow_mut();
         for creep in game::creeps().values() {
-            num_creeps += 1;
             let creep_name = creep.name();
             if let None = creeps_role.get(&creep.name()) {
-                let role = Role::find_role(&creep);
-                if let Some(r) = role {
-                    creeps_role.insert(creep_name, r.clone());
-                } else {
-                    creeps_role.insert(creep_name, Role::General);
+                let memory_role = db
+                    .as_ref()
+                    .and_then(|d| d.get_creep_memory(&creep_name))
+                    .and_then(|m| m.role.clone());
+                let role = Role::recover(memory_role.clone(), &creep_name, || {
+                    creep.body().iter().map(|p| p.part()).collect()
+                });
+                if memory_role.is_none() {
+                    if let Some(m) = db
+                        .as_mut()
+                        .and_then(|d| d.get_mut_creep_memory(&creep_name))
+                    {
+                        m.role = Some(role.clone());
+                        persisted_roles = true;
+                    }
+                }
+                lifecycle::emit(&creep_name, LifecycleEvent::RoleAssigned(role.clone()));
+                let memory = db.as_ref().and_then(|d| d.get_creep_memory(&creep_name));
+                if let Some(home) = memory.and_then(|m| m.home_room()) {
+                    CREEPS_HOME.with(|creeps_home_refcell| {
+                        creeps_home_refcell
+                            .borrow_mut()
+                            .insert(creep_name.clone(), home);
+                    });
+                }
+                if let Some(source) = memory.and_then(|m| m.source()) {
+                    CREEPS_SOURCE.with(|creeps_source_refcell| {
+                        creeps_source_refcell
+                            .borrow_mut()
+                            .insert(creep_name.clone(), source);
+                    });
+                }
+                if memory.map(|m| m.threat_spawned).unwrap_or(false) {
+                    THREAT_DEFENDERS.with(|threat_defenders_refcell| {
+                        threat_defenders_refcell
+                            .borrow_mut()
+                            .insert(creep_name.clone());
+                    });
                 }
+                creeps_role.insert(creep_name, role);
             }
         }
     });
+    if persisted_roles {
+        if let Some(d) = db.as_ref() {
+            d.update_memory();
+        }
+    }
+
+    if paused {
+        info!("paused, not spawning, building or raiding");
+    }
+    if let Some(d) = db.as_ref() {
+        logging::apply_filters(&d.data.log_filters);
+        terminal::set_reserves(&d.data.terminal_reserves);
+        reserver::set_remote_rooms(&d.data.remote_rooms);
+        warrior::set_garrisons(&d.data.garrisons);
+        if time % MARKET_INTERVAL == 0 && run.trading {
+            market::auto_trade(&d.data.market, &d.data.terminal_reserves);
+        }
+        // halfway between trading rounds, deals leave the terminals on cooldown
+        if time % MARKET_INTERVAL == MARKET_INTERVAL / 2 && run.trading {
+            terminal::balance_energy(&d.data.terminal_reserves);
+        }
+        link::route_links(&d.data.links);
+    }
 
-    let mut roles = Vec::<Role>::new();
+    let mut creep_rooms = Vec::<(RoomName, Role)>::new();
     CREEPS_TARGET.with(|creeps_target_refcell| {
         let mut creeps_target = creeps_target_refcell.borrow_mut();
+        if let Some(d) = db.as_mut() {
+            if watchdog::check_spawn_starvation(&mut d.data.spawn_starvation, &mut creeps_target) {
+                d.update_memory();
+            }
+        }
         for creep in game::creeps().values() {
             let mut creep = Creep::new(&creep);
             CREEPS_ROLE.with(|creeps_role_refcell| {
@@ -63,34 +180,57 @@ pub fn game_loop() {
                 let r = creeps_role.get(&creep.name()).cloned();
                 creep.set_role(r.clone());
                 if let Some(val) = r {
-                    roles.push(val);
+                    if let Some(room) = creep.room() {
+                        creep_rooms.push((room.name(), val));
+                    }
                 }
             });
-            let hostiles = creep.room().unwrap().find(find::HOSTILE_CREEPS);
-            creep.run(&mut creeps_target, hostiles.len() > 0);
+            let has_hostiles = creep
+                .room()
+                .map(|r| r.find(find::HOSTILE_CREEPS).len() > 0)
+                .unwrap_or(false);
+            let task_name = |t: Option<&CreepTarget>| t.map(|t| t.name().to_string());
+            let task_before = task_name(creeps_target.get(&creep.name()));
+            creep.run(&mut creeps_target, has_hostiles, paused);
+            let task_after = task_name(creeps_target.get(&creep.name()));
+            if task_before != task_after {
+                lifecycle::emit(
+                    &creep.name(),
+                    LifecycleEvent::TaskChanged(task_before, task_after),
+                );
+            }
         }
     });
+    // roles are tallied per room so each spawn only looks at its own room's needs
+    let roles = Role::tally_by_room(creep_rooms);
 
     TOWERS_TARGET.with(|towers_target_refcell| {
+        if !run.towers {
+            return;
+        }
         let mut towers_target = towers_target_refcell.borrow_mut();
         for room in game::rooms().values() {
             let hostiles = room.find(find::HOSTILE_CREEPS);
+            // during combat one tower saves a dying defender while the others keep attacking
+            let heal_target = if hostiles.len() > 0 {
+                find_critically_wounded(&room)
+            } else {
+                None
+            };
             let structures = room.find(find::MY_STRUCTURES);
-            let towers: Vec<&StructureObject> = structures
+            let towers: Vec<Tower> = structures
                 .iter()
-                .filter(|s| s.structure_type() == screeps::StructureType::Tower)
+                .filter_map(|s| match s {
+                    StructureObject::StructureTower(t) => Some(Tower::new(t)),
+                    _ => None,
+                })
                 .collect();
-            for tower in towers {
-                match tower {
-                    StructureObject::StructureTower(screeps_t) => {
-                        let t = Tower::new(screeps_t);
-                        t.run(&mut towers_target, hostiles.clone());
-                    }
-                    _ => {
-                        warn!("expected a tower here");
-                    }
-                }
-            }
+            triage(
+                &towers,
+                heal_target.as_ref(),
+                |t, wounded| t.save(wounded),
+                |t| t.run(&mut towers_target, hostiles.clone()),
+            );
         }
     });
 
@@ -102,43 +242,243 @@ pub fn game_loop() {
     //
     // They are returned as wasm_bindgen::JsValue references, which we can safely
     // assume are StructureSpawn objects as returned from js without checking first
+    if !run.spawning {
+        info!("done! cpu: {}", game::cpu::get_used());
+        return;
+    }
+
     let mut additional = 0;
+    // A role set in memory with `force_spawn` jumps the queue for a single spawn
+    let mut forced_role = db.as_ref().and_then(|d| d.data.force_spawn.clone());
+    let owned_rooms = game::rooms()
+        .values()
+        .filter(|r| r.controller().map(|c| c.my()).unwrap_or(false))
+        .count() as u32;
     for spawn in game::spawns().values() {
         debug!("running spawn {}", String::from(spawn.name()));
 
         let name_base = game::time();
         let mut name = format!("{}-{}", name_base, additional);
+        let is_forced = forced_role.is_some();
+        let role_to_spawn = forced_role.clone().or_else(|| {
+            let room = spawn.room().unwrap();
+            let room_roles = roles.get(&room.name()).cloned().unwrap_or_default();
+            let disabled = db
+                .as_ref()
+                .map(|d| d.disabled_roles(room.name()))
+                .unwrap_or_default();
+            Role::find_role_to_spawn(
+                &room_roles,
+                num_spawned,
+                containers_overflowing(&room),
+                room_threat(&room),
+                room.controller().map(|c| c.level() == 8).unwrap_or(false),
+                &disabled,
+            )
+            .or_else(|| room_mineral_role(&room, &room_roles))
+            // remote rooms are only looked after once the room has all it needs
+            .or_else(|| {
+                let username = spawn.owner().unwrap().username();
+                reserver_wanted(room.name(), &username).then(|| Role::Reserver)
+            })
+            .filter(|r| !disabled.contains(r))
+        });
+        // claiming fails once the GCL allows no more rooms
+        let role_to_spawn = match role_to_spawn {
+            Some(Role::Claimer) => {
+                claimer_or_reserver(game::gcl::level(), owned_rooms, RESERVE_WHEN_GCL_MAXED)
+            }
+            other => other,
+        };
+        let role_to_spawn = role_to_spawn.filter(|r| {
+            *r != Role::Reserver
+                || reserver_affordable(spawn.room().unwrap().energy_capacity_available())
+        });
+        // energy the room still has to gather for the creep it wants next
+        let mut pending_cost = None;
         // TODO: Have a phase on the game that will influence the body part
-        if let Some(role_needed) = Role::find_role_to_spawn(&roles, num_creeps) {
+        if let Some(role_needed) = role_to_spawn.clone() {
             let energy_available = spawn.room().unwrap().energy_available();
             let capacity = spawn.room().unwrap().energy_capacity_available();
 
-            let b = role_needed.get_body(energy_available, capacity, num_creeps);
-
-            if let Some(val) = b {
-                if energy_available >= val.iter().map(|p| p.cost()).sum() {
+            // a preset set in memory for the role beats the generated body
+            let preset = db
+                .as_ref()
+                .and_then(|d| d.body_preset(spawn.room().unwrap().name(), &role_needed));
+            let plan = match preset {
+                Some(body) => BodyPlan::for_body(body, energy_available),
+                None => role_needed.get_body(energy_available, capacity, num_spawned),
+            };
+            match plan {
+                BodyPlan::WaitForEnergy(cost) => {
+                    debug!(
+                        "{} waiting for energy: {}/{}",
+                        role_needed.to_string(),
+                        energy_available,
+                        cost
+                    );
+                    pending_cost = Some(cost);
+                }
+                BodyPlan::Spawn(val) => {
                     name = format!("{}-{}", role_needed.to_string(), name);
                     let res = spawn.spawn_creep(&val, &name);
                     if res != ReturnCode::Ok {
                         warn!("couldn't spawn: {:?}", res);
                     } else {
                         additional += 1;
+                        if is_forced {
+                            info!("spawned forced {}", role_needed.to_string());
+                            forced_role = None;
+                            if let Some(d) = db.as_mut() {
+                                d.clear_force_spawn();
+                            }
+                        }
+                        lifecycle::emit(&name, LifecycleEvent::Born(role_needed.clone()));
+                        // remembered so the creep finds its way back from other rooms
+                        let home = spawn.room().unwrap().name();
+                        let mut memory = CreepMemory::new(role_needed.clone(), home);
+                        // harvesters stick to one source for good, so they don't all swarm one
+                        if role_needed == Role::Harvester {
+                            if let Some(source) = assign_source(&spawn.room().unwrap()) {
+                                memory.source = Some(source.to_string());
+                                CREEPS_SOURCE.with(|creeps_source_refcell| {
+                                    creeps_source_refcell
+                                        .borrow_mut()
+                                        .insert(name.clone(), source);
+                                });
+                            }
+                        }
+                        // defenders above the peace time count go once the attack is over
+                        if role_needed == Role::Warrior {
+                            let warriors = roles
+                                .get(&home)
+                                .map(|r| r.iter().filter(|r| **r == Role::Warrior).count())
+                                .unwrap_or(0);
+                            memory.threat_spawned = spawned_for_threat(is_forced, warriors);
+                            if memory.threat_spawned {
+                                THREAT_DEFENDERS.with(|threat_defenders_refcell| {
+                                    threat_defenders_refcell.borrow_mut().insert(name.clone());
+                                });
+                            }
+                        }
+                        if let Some(d) = db.as_mut() {
+                            d.data.creeps.insert(name.clone(), memory);
+                            d.update_memory();
+                        }
+                        CREEPS_HOME.with(|creeps_home_refcell| {
+                            creeps_home_refcell.borrow_mut().insert(name.clone(), home);
+                        });
                         CREEPS_ROLE.with(|creeps_role_refcell| {
                             let mut creeps_role = creeps_role_refcell.borrow_mut();
                             creeps_role.insert(name, role_needed);
                         });
+                        continue;
                     }
                 }
             }
-            continue;
-        } else {
-            continue;
         }
+        if SHOW_SPAWN_QUEUE {
+            let room = spawn.room().unwrap();
+            let room_roles = roles.get(&room.name()).cloned().unwrap_or_default();
+            let overlay = visuals::SpawnOverlay {
+                deficits: Role::deficits(&room_roles),
+                next: role_to_spawn.clone(),
+                energy_needed: pending_cost,
+                energy_available: room.energy_available(),
+            };
+            visuals::show_spawn_queue(&spawn, &overlay);
+        }
+        renew::try_renew(&spawn, pending_cost);
     }
 
     info!("done! cpu: {}", game::cpu::get_used())
 }
 
+/// Drops targets, homes, sources, destinations and threat tags of creeps that died, and targets of towers that were destroyed.
+fn clean_up_targets() {
+    let living_creeps: HashSet<String> = game::creeps().values().map(|c| c.name()).collect();
+    let towers: HashSet<Position> = game::rooms()
+        .values()
+        .flat_map(|room| room.find(find::MY_STRUCTURES))
+        .filter(|s| s.structure_type() == screeps::StructureType::Tower)
+        .map(|s| s.pos())
+        .collect();
+    drop_orphaned_targets(&living_creeps, &towers);
+}
+
+/// What the colony does this tick. Paused, it stops spawning, planning and trading but the
+/// towers keep defending it.
+#[derive(Debug, PartialEq)]
+pub struct Activities {
+    pub spawning: bool,
+    pub planning: bool,
+    pub trading: bool,
+    pub towers: bool,
+}
+
+impl Activities {
+    pub fn for_tick(paused: bool) -> Self {
+        Activities {
+            spawning: !paused,
+            planning: !paused,
+            trading: !paused,
+            towers: true,
+        }
+    }
+}
+
+/// Creeps out of the spawn, given whether each creep is still spawning.
+fn count_spawned(spawning: impl Iterator<Item = bool>) -> u32 {
+    spawning.filter(|s| !s).count() as u32
+}
+
+fn drop_orphaned_targets(living_creeps: &HashSet<String>, towers: &HashSet<Position>) {
+    CREEPS_TARGET.with(|creeps_target_refcell| {
+        let mut creeps_target = creeps_target_refcell.borrow_mut();
+        let before = creeps_target.len();
+        creeps_target.retain(|name, _| living_creeps.contains(name));
+        if creeps_target.len() != before {
+            info!(
+                "removed {} orphaned creep targets",
+                before - creeps_target.len()
+            );
+        }
+    });
+    CREEPS_HOME.with(|creeps_home_refcell| {
+        creeps_home_refcell
+            .borrow_mut()
+            .retain(|name, _| living_creeps.contains(name));
+    });
+    CREEPS_SOURCE.with(|creeps_source_refcell| {
+        creeps_source_refcell
+            .borrow_mut()
+            .retain(|name, _| living_creeps.contains(name));
+    });
+    CREEPS_DESTINATION.with(|creeps_destination_refcell| {
+        creeps_destination_refcell
+            .borrow_mut()
+            .retain(|name, _| living_creeps.contains(name));
+    });
+    THREAT_DEFENDERS.with(|threat_defenders_refcell| {
+        threat_defenders_refcell
+            .borrow_mut()
+            .retain(|name| living_creeps.contains(name));
+    });
+    TOWERS_TARGET.with(|towers_target_refcell| {
+        let mut towers_target = towers_target_refcell.borrow_mut();
+        let before = towers_target.len();
+        towers_target.retain(|pos, _| towers.contains(pos));
+        if towers_target.len() != before {
+            info!(
+                "removed {} orphaned tower targets",
+                before - towers_target.len()
+            );
+        }
+    });
+}
+
+const JSON_LOG_PREFIX_LEN: usize = 80;
+
 struct Database {
     data: Root,
 }
@@ -146,10 +486,63 @@ struct Database {
 impl Database {
     fn init() -> Option<Self> {
         let root_json_string: String = RawMemory::get().into();
-        match serde_json::from_str(root_json_string.as_str()) {
-            Ok::<Root, _>(root_json) => Some(Self { data: root_json }),
+        Self::parse(&root_json_string)
+    }
+
+    fn parse(root_json_string: &str) -> Option<Self> {
+        let mut root_value: serde_json::Value = match serde_json::from_str(root_json_string) {
+            Ok(v) => v,
+            Err(e) => {
+                info!(
+                    "could not deserialize root_json ({} bytes, starts with {:?}): {}",
+                    root_json_string.len(),
+                    json_prefix(root_json_string),
+                    e
+                );
+                return None;
+            }
+        };
+
+        // Creeps are parsed one by one so a single malformed entry doesn't take the whole root
+        // down with it
+        let mut creeps = HashMap::<String, CreepMemory>::new();
+        if let Some(obj) = root_value.as_object_mut() {
+            if let Some(serde_json::Value::Object(entries)) = obj.remove("creeps") {
+                for (name, value) in entries {
+                    let value_string = value.to_string();
+                    match serde_json::from_value::<CreepMemory>(value) {
+                        Ok(creep_memory) => {
+                            creeps.insert(name, creep_memory);
+                        }
+                        Err(e) => {
+                            info!(
+                                "skipping memory of creep {} ({:?}): {}",
+                                name,
+                                json_prefix(&value_string),
+                                e
+                            );
+                        }
+                    }
+                }
+            }
+            obj.insert(
+                "creeps".to_string(),
+                serde_json::Value::Object(serde_json::Map::new()),
+            );
+        }
+
+        match serde_json::from_value::<Root>(root_value) {
+            Ok(mut root_json) => {
+                root_json.creeps = creeps;
+                Some(Self { data: root_json })
+            }
             Err(e) => {
-                info!("could not deserialize root_json: {}", e);
+                info!(
+                    "could not deserialize root_json ({} bytes, starts with {:?}): {}",
+                    root_json_string.len(),
+                    json_prefix(root_json_string),
+                    e
+                );
                 None
             }
         }
@@ -160,6 +553,7 @@ impl Database {
             if let None = creep.role {
                 if name == "34656950-0" {
                     creep.role = Some(Role::Harvester);
+                    lifecycle::emit(name, LifecycleEvent::RoleAssigned(Role::Harvester));
                 }
             }
         }
@@ -184,6 +578,7 @@ impl Database {
         }
 
         for name in to_remove.iter() {
+            lifecycle::emit(name, LifecycleEvent::Died);
             let removed_creep_js = self.data.creeps.remove(name);
             if let None = removed_creep_js {
                 info!(
@@ -192,8 +587,6 @@ impl Database {
                 );
             }
         }
-
-        self.update_memory();
     }
 
     fn update_memory(&self) {
@@ -203,11 +596,44 @@ impl Database {
             }
             Err(e) => {
                 info!("could not serialize root_json: {}", e);
+                for (name, creep) in self.data.creeps.iter() {
+                    if let Err(e) = serde_json::to_string(creep) {
+                        info!("could not serialize memory of creep {}: {}", name, e);
+                    }
+                }
                 info!("mutation did not persist to screeps memory");
             }
         }
     }
 
+    fn clear_force_spawn(&mut self) {
+        self.data.force_spawn = None;
+        self.update_memory();
+    }
+
+    /// Roles turned off for the room.
+    fn disabled_roles(&self, room_name: RoomName) -> Vec<Role> {
+        self.data
+            .disabled_roles
+            .get(&room_name.to_string())
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Body preset configured for the role in the room, if any.
+    fn body_preset(&self, room_name: RoomName, role: &Role) -> Option<Vec<Part>> {
+        let preset_name = self
+            .data
+            .room_body_presets
+            .get(&room_name.to_string())?
+            .get(role)?;
+        let body = self.data.body_presets.get(preset_name);
+        if body.is_none() {
+            warn!("unknown body preset {}", preset_name);
+        }
+        body.cloned()
+    }
+
     fn get_creep_memory(&self, name: &str) -> Option<&CreepMemory> {
         self.data.creeps.get(name)
     }
@@ -216,3 +642,165 @@ impl Database {
         self.data.creeps.get_mut(name)
     }
 }
+
+/// Start of a json string, short enough to be logged.
+fn json_prefix(json: &str) -> String {
+    json.chars().take(JSON_LOG_PREFIX_LEN).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::warrior::ThreatLevel;
+    use screeps::RoomCoordinate;
+
+    #[test]
+    fn malformed_creep_memory_only_drops_that_creep() {
+        let json = r#"{
+            "creeps": {
+                "good": {"role": "Hauler"},
+                "bad": {"role": "NotARole"}
+            },
+            "paused": true
+        }"#;
+        let db = Database::parse(json).unwrap();
+        assert_eq!(db.data.creeps.len(), 1);
+        assert_eq!(db.data.creeps["good"].role, Some(Role::Hauler));
+        assert!(db.data.paused);
+    }
+
+    #[test]
+    fn unreadable_root_is_rejected() {
+        assert!(Database::parse("{not json").is_none());
+        assert!(Database::parse(r#"{"creeps": {}, "paused": "yes"}"#).is_none());
+    }
+
+    #[test]
+    fn logged_json_is_truncated() {
+        let long = "x".repeat(JSON_LOG_PREFIX_LEN * 2);
+        assert_eq!(json_prefix(&long).len(), JSON_LOG_PREFIX_LEN);
+        assert_eq!(json_prefix("{}"), "{}");
+    }
+
+    fn pos(x: u8, y: u8) -> Position {
+        Position::new(
+            RoomCoordinate::new(x).unwrap(),
+            RoomCoordinate::new(y).unwrap(),
+            RoomName::new("W1N1").unwrap(),
+        )
+    }
+
+    #[test]
+    fn targets_of_dead_creeps_are_dropped() {
+        CREEPS_TARGET.with(|creeps_target_refcell| {
+            let mut creeps_target = creeps_target_refcell.borrow_mut();
+            creeps_target.insert("alive".to_string(), CreepTarget::HarvestSpot(pos(10, 10)));
+            creeps_target.insert("dead".to_string(), CreepTarget::HarvestSpot(pos(11, 10)));
+        });
+        let living_creeps: HashSet<String> = ["alive".to_string()].into_iter().collect();
+        drop_orphaned_targets(&living_creeps, &HashSet::new());
+        CREEPS_TARGET.with(|creeps_target_refcell| {
+            let creeps_target = creeps_target_refcell.borrow();
+            assert!(creeps_target.contains_key("alive"));
+            assert!(!creeps_target.contains_key("dead"));
+        });
+    }
+
+    #[test]
+    fn targets_of_destroyed_towers_are_dropped() {
+        let id: ObjectId<screeps::Structure> = "5bbcab1d9099fc012e632f01".parse().unwrap();
+        TOWERS_TARGET.with(|towers_target_refcell| {
+            let mut towers_target = towers_target_refcell.borrow_mut();
+            towers_target.insert(pos(20, 20), TowerTarget::Repair(id));
+            towers_target.insert(pos(30, 30), TowerTarget::Repair(id));
+        });
+        let towers: HashSet<Position> = [pos(20, 20)].into_iter().collect();
+        drop_orphaned_targets(&HashSet::new(), &towers);
+        TOWERS_TARGET.with(|towers_target_refcell| {
+            let towers_target = towers_target_refcell.borrow();
+            assert!(towers_target.contains_key(&pos(20, 20)));
+            assert!(!towers_target.contains_key(&pos(30, 30)));
+        });
+    }
+
+    #[test]
+    fn rooms_spawn_the_preset_configured_for_the_role() {
+        let json = r#"{
+            "creeps": {},
+            "body_presets": {"big_hauler": ["carry", "carry", "move", "move"]},
+            "room_body_presets": {"W1N1": {"Hauler": "big_hauler", "Builder": "missing"}}
+        }"#;
+        let db = Database::parse(json).unwrap();
+        let room = RoomName::new("W1N1").unwrap();
+        assert_eq!(
+            db.body_preset(room, &Role::Hauler),
+            Some(vec![Part::Carry, Part::Carry, Part::Move, Part::Move])
+        );
+        assert_eq!(db.body_preset(room, &Role::Builder), None);
+        assert_eq!(db.body_preset(room, &Role::Harvester), None);
+        assert_eq!(
+            db.body_preset(RoomName::new("W2N1").unwrap(), &Role::Hauler),
+            None
+        );
+    }
+
+    #[test]
+    fn spawning_creeps_are_not_counted() {
+        assert_eq!(count_spawned([false, true, false, true].into_iter()), 2);
+    }
+
+    #[test]
+    fn harvesters_wait_for_three_spawned_creeps() {
+        let spawned = count_spawned([false, false, true, true].into_iter());
+        assert_eq!(
+            Role::find_role_to_spawn(&vec![], spawned, false, ThreatLevel::None, false, &[]),
+            Some(Role::Hauler)
+        );
+        assert_eq!(
+            Role::find_role_to_spawn(&vec![], spawned + 1, false, ThreatLevel::None, false, &[]),
+            Some(Role::Harvester)
+        );
+    }
+
+    #[test]
+    fn disabled_builders_are_not_spawned_in_the_room() {
+        let json = r#"{"creeps": {}, "disabled_roles": {"W1N1": ["Builder"]}}"#;
+        let db = Database::parse(json).unwrap();
+        let disabled = db.disabled_roles(RoomName::new("W1N1").unwrap());
+        assert_eq!(disabled, vec![Role::Builder]);
+        assert!(db.disabled_roles(RoomName::new("W2N1").unwrap()).is_empty());
+
+        let mut roles = vec![Role::Harvester; 2];
+        roles.extend(vec![Role::Hauler; 5]);
+        assert_eq!(
+            Role::find_role_to_spawn(&roles, 10, false, ThreatLevel::None, false, &[]),
+            Some(Role::Builder)
+        );
+        assert_eq!(
+            Role::find_role_to_spawn(&roles, 10, false, ThreatLevel::None, false, &disabled),
+            None
+        );
+    }
+
+    #[test]
+    fn paused_colony_stops_spawning_and_planning_but_defends() {
+        let run = Activities::for_tick(true);
+        assert!(!run.spawning);
+        assert!(!run.planning);
+        assert!(!run.trading);
+        assert!(run.towers);
+    }
+
+    #[test]
+    fn running_colony_does_everything() {
+        assert_eq!(
+            Activities::for_tick(false),
+            Activities {
+                spawning: true,
+                planning: true,
+                trading: true,
+                towers: true,
+            }
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use log::*;
+
+use crate::config::{LIFECYCLE_LOG_SIZE, TRACE_LIFECYCLE};
+use crate::roles::role::Role;
+
+thread_local! {
+    static LIFECYCLE_LOG: RefCell<VecDeque<(u32, String, LifecycleEvent)>> =
+        RefCell::new(VecDeque::new());
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LifecycleEvent {
+    Born(Role),
+    RoleAssigned(Role),
+    // previous and new task, by `CreepTarget` name
+    TaskChanged(Option<String>, Option<String>),
+    Died,
+}
+
+/// Records an event for the creep when tracing is on, keeping only the latest ones.
+pub fn emit(creep_name: &str, event: LifecycleEvent) {
+    if !TRACE_LIFECYCLE {
+        return;
+    }
+    record(screeps::game::time(), creep_name, event);
+}
+
+fn record(time: u32, creep_name: &str, event: LifecycleEvent) {
+    debug!("[{}] {}: {:?}", time, creep_name, event);
+    if event == LifecycleEvent::Died {
+        debug!("{} lived through {:?}", creep_name, events_for(creep_name));
+    }
+    LIFECYCLE_LOG.with(|log_refcell| {
+        let mut log = log_refcell.borrow_mut();
+        if log.len() >= LIFECYCLE_LOG_SIZE {
+            log.pop_front();
+        }
+        log.push_back((time, creep_name.to_string(), event));
+    });
+}
+
+/// Events recorded for the creep, oldest first.
+fn events_for(creep_name: &str) -> Vec<(u32, LifecycleEvent)> {
+    LIFECYCLE_LOG.with(|log_refcell| {
+        log_refcell
+            .borrow()
+            .iter()
+            .filter(|(_, name, _)| name == creep_name)
+            .map(|(time, _, event)| (*time, event.clone()))
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_are_kept_per_creep_in_order() {
+        record(1, "a", LifecycleEvent::Born(Role::Hauler));
+        record(2, "b", LifecycleEvent::Born(Role::Builder));
+        record(3, "a", LifecycleEvent::Died);
+        assert_eq!(
+            events_for("a"),
+            vec![
+                (1, LifecycleEvent::Born(Role::Hauler)),
+                (3, LifecycleEvent::Died)
+            ]
+        );
+    }
+
+    #[test]
+    fn only_the_latest_events_are_kept() {
+        for time in 0..LIFECYCLE_LOG_SIZE as u32 + 5 {
+            record(time, "a", LifecycleEvent::RoleAssigned(Role::Hauler));
+        }
+        let events = events_for("a");
+        assert_eq!(events.len(), LIFECYCLE_LOG_SIZE);
+        assert_eq!(events[0].0, 5);
+    }
+}
@@ -0,0 +1,236 @@
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{
+    game, look, prelude::*, Position, ResourceType, ReturnCode, RoomCoordinate, RoomName,
+    StructureLink, StructureObject,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{LINK_CONTROLLER_REFILL_PERCENT, LINK_LOSS_PERCENT};
+
+/// What a link is for, which decides where its energy goes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LinkRole {
+    // next to a source, filled by harvesters
+    Source,
+    // next to the storage, filled and emptied by haulers
+    Storage,
+    // next to the controller, drained by upgraders
+    Controller,
+}
+
+/// A link's role, read from the `links` key in memory, by room then position.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkConfig {
+    pub x: u8,
+    pub y: u8,
+    pub role: LinkRole,
+}
+
+/// What the router needs to know about a link this tick.
+#[derive(Debug, Clone)]
+pub struct LinkState {
+    pub role: LinkRole,
+    pub energy: u32,
+    pub capacity: u32,
+    pub cooldown: u32,
+}
+
+/// A transfer of `amount` energy from the link at index `from` to the one at index `to`.
+#[derive(Debug, PartialEq)]
+pub struct LinkSend {
+    pub from: usize,
+    pub to: usize,
+    pub amount: u32,
+}
+
+/// Moves energy along the configured links of every room.
+pub fn route_links(layouts: &HashMap<String, Vec<LinkConfig>>) {
+    for (room_name, configs) in layouts.iter() {
+        let room = match room_name
+            .parse::<RoomName>()
+            .ok()
+            .and_then(|n| game::rooms().get(n))
+        {
+            Some(r) => r,
+            // no vision
+            None => continue,
+        };
+        let mut links = Vec::<(StructureLink, LinkRole)>::new();
+        for config in configs.iter() {
+            let pos = match (RoomCoordinate::new(config.x), RoomCoordinate::new(config.y)) {
+                (Ok(x), Ok(y)) => Position::new(x, y, room.name()),
+                _ => {
+                    warn!("invalid link position {:?} in {}", config, room_name);
+                    continue;
+                }
+            };
+            let link = room
+                .look_for_at(look::STRUCTURES, &pos)
+                .into_iter()
+                .find_map(|s| match s {
+                    StructureObject::StructureLink(l) => Some(l),
+                    _ => None,
+                });
+            match link {
+                Some(l) => links.push((l, config.role)),
+                None => debug!("no link at {:?}", pos),
+            }
+        }
+        let states: Vec<LinkState> = links
+            .iter()
+            .map(|(l, role)| LinkState {
+                role: *role,
+                energy: l.store().get_used_capacity(Some(ResourceType::Energy)),
+                capacity: l.store().get_capacity(Some(ResourceType::Energy)),
+                cooldown: l.cooldown(),
+            })
+            .collect();
+        for send in decide_sends(&states) {
+            let (from, _) = &links[send.from];
+            let (to, _) = &links[send.to];
+            let r = from.transfer_energy(to, Some(send.amount));
+            if r != ReturnCode::Ok {
+                warn!(
+                    "couldn't send {} energy between links: {:?}",
+                    send.amount, r
+                );
+            }
+        }
+    }
+}
+
+/// Full source links empty into the controller link, or the storage link once the controller's
+/// has no room left. The storage link tops up the controller link when it runs low. A link on
+/// cooldown sends nothing, and a receiving link can only take what fits.
+pub fn decide_sends(links: &[LinkState]) -> Vec<LinkSend> {
+    let mut free: Vec<u32> = links.iter().map(|l| l.capacity - l.energy).collect();
+    let mut energy: Vec<u32> = links.iter().map(|l| l.energy).collect();
+    let index_of = |role: LinkRole| links.iter().position(|l| l.role == role);
+    let controller = index_of(LinkRole::Controller);
+    let storage = index_of(LinkRole::Storage);
+    let mut sends = Vec::new();
+    let mut send = |from: usize, to: usize, free: &mut Vec<u32>, energy: &mut Vec<u32>| {
+        let amount = energy[from].min(free[to]);
+        if amount == 0 {
+            return false;
+        }
+        energy[from] -= amount;
+        free[to] -= amount - link_loss(amount);
+        energy[to] += amount - link_loss(amount);
+        sends.push(LinkSend { from, to, amount });
+        true
+    };
+
+    for (i, link) in links.iter().enumerate() {
+        if link.role != LinkRole::Source || link.cooldown > 0 || free[i] > 0 {
+            continue;
+        }
+        let sent = controller
+            .map(|c| send(i, c, &mut free, &mut energy))
+            .unwrap_or(false);
+        if !sent {
+            if let Some(s) = storage {
+                send(i, s, &mut free, &mut energy);
+            }
+        }
+    }
+
+    if let (Some(s), Some(c)) = (storage, controller) {
+        let controller_link = &links[c];
+        let low = energy[c] * 100 < controller_link.capacity * LINK_CONTROLLER_REFILL_PERCENT;
+        if low && links[s].cooldown == 0 {
+            send(s, c, &mut free, &mut energy);
+        }
+    }
+    sends
+}
+
+/// Energy lost on the way when a link sends `amount`.
+pub fn link_loss(amount: u32) -> u32 {
+    (amount * LINK_LOSS_PERCENT + 99) / 100
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(role: LinkRole, energy: u32) -> LinkState {
+        LinkState {
+            role,
+            energy,
+            capacity: 800,
+            cooldown: 0,
+        }
+    }
+
+    #[test]
+    fn full_source_link_empties_into_the_controller_link() {
+        let links = [
+            link(LinkRole::Source, 800),
+            link(LinkRole::Controller, 600),
+            link(LinkRole::Storage, 0),
+        ];
+        assert_eq!(
+            decide_sends(&links),
+            vec![LinkSend {
+                from: 0,
+                to: 1,
+                amount: 200
+            }]
+        );
+    }
+
+    #[test]
+    fn source_link_falls_back_to_storage_when_the_controller_is_full() {
+        let links = [
+            link(LinkRole::Source, 800),
+            link(LinkRole::Controller, 800),
+            link(LinkRole::Storage, 0),
+        ];
+        assert_eq!(
+            decide_sends(&links),
+            vec![LinkSend {
+                from: 0,
+                to: 2,
+                amount: 800
+            }]
+        );
+    }
+
+    #[test]
+    fn storage_link_tops_up_a_low_controller_link() {
+        let links = [
+            link(LinkRole::Controller, 100),
+            link(LinkRole::Storage, 500),
+        ];
+        assert_eq!(
+            decide_sends(&links),
+            vec![LinkSend {
+                from: 1,
+                to: 0,
+                amount: 500
+            }]
+        );
+    }
+
+    #[test]
+    fn links_on_cooldown_or_not_full_stay_put() {
+        let mut source = link(LinkRole::Source, 800);
+        source.cooldown = 3;
+        let links = [
+            source,
+            link(LinkRole::Source, 700),
+            link(LinkRole::Controller, 700),
+        ];
+        assert!(decide_sends(&links).is_empty());
+    }
+
+    #[test]
+    fn sending_loses_a_share_rounded_up() {
+        assert_eq!(link_loss(100), LINK_LOSS_PERCENT);
+        assert_eq!(link_loss(1), 1);
+        assert_eq!(link_loss(0), 0);
+    }
+}
@@ -1,9 +1,20 @@
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::str::FromStr;
+
 use js_sys::JsString;
+use log::LevelFilter;
 use screeps::game;
 use web_sys::console;
 
 pub use log::LevelFilter::*;
 
+// Levels are kept outside of fern so they can change without setting the logger up again
+thread_local! {
+    static DEFAULT_LEVEL: Cell<LevelFilter> = Cell::new(LevelFilter::Info);
+    static MODULE_FILTERS: RefCell<HashMap<String, LevelFilter>> = RefCell::new(HashMap::new());
+}
+
 struct JsLog;
 struct JsNotify;
 
@@ -26,9 +37,14 @@ impl log::Log for JsNotify {
     fn flush(&self) {}
 }
 
-pub fn setup_logging(verbosity: log::LevelFilter) {
+/// `filters` maps a module path relative to the crate, e.g. `roles::hauler`, to the level it
+/// logs at. Modules without a filter log at `verbosity`.
+pub fn setup_logging(verbosity: log::LevelFilter, filters: HashMap<String, LevelFilter>) {
+    DEFAULT_LEVEL.with(|level| level.set(verbosity));
+    MODULE_FILTERS.with(|module_filters| *module_filters.borrow_mut() = filters);
     fern::Dispatch::new()
-        .level(verbosity)
+        .level(LevelFilter::Trace)
+        .filter(|metadata| metadata.level() <= level_for(metadata.target()))
         .format(|out, message, record| {
             out.finish(format_args!(
                 "({}) {}: {}",
@@ -50,3 +66,83 @@ pub fn setup_logging(verbosity: log::LevelFilter) {
         .apply()
         .expect("expected setup_logging to only ever be called once per instance");
 }
+
+/// Replaces the per-module filters, given as module to level name (`"creep": "debug"`).
+/// Entries with an unknown level are ignored.
+pub fn apply_filters(filters: &HashMap<String, String>) {
+    let parsed: HashMap<String, LevelFilter> = filters
+        .iter()
+        .filter_map(|(module, level)| match LevelFilter::from_str(level) {
+            Ok(l) => Some((module.clone(), l)),
+            Err(_) => {
+                log::warn!("unknown log level {} for {}", level, module);
+                None
+            }
+        })
+        .collect();
+    let changed = MODULE_FILTERS.with(|module_filters| {
+        let mut module_filters = module_filters.borrow_mut();
+        if *module_filters == parsed {
+            false
+        } else {
+            *module_filters = parsed;
+            true
+        }
+    });
+    if changed {
+        log::info!("log filters now {:?}", filters);
+    }
+}
+
+/// Level of the most specific filter matching the log target, the default level otherwise.
+fn level_for(target: &str) -> LevelFilter {
+    let module = target
+        .strip_prefix(env!("CARGO_CRATE_NAME"))
+        .and_then(|t| t.strip_prefix("::"))
+        .unwrap_or(target);
+    MODULE_FILTERS.with(|module_filters| {
+        module_filters
+            .borrow()
+            .iter()
+            .filter(|(m, _)| module == m.as_str() || module.starts_with(&format!("{}::", m)))
+            .max_by_key(|(m, _)| m.len())
+            .map(|(_, level)| *level)
+            .unwrap_or_else(|| DEFAULT_LEVEL.with(|level| level.get()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(entries: &[(&str, &str)]) -> HashMap<String, String> {
+        entries
+            .iter()
+            .map(|(m, l)| (m.to_string(), l.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn most_specific_filter_wins() {
+        apply_filters(&filters(&[("roles", "warn"), ("roles::hauler", "debug")]));
+        let crate_target = |module: &str| format!("{}::{}", env!("CARGO_CRATE_NAME"), module);
+        assert_eq!(level_for(&crate_target("roles::hauler")), Debug);
+        assert_eq!(level_for(&crate_target("roles::builder")), Warn);
+        assert_eq!(level_for(&crate_target("tower")), Info);
+    }
+
+    #[test]
+    fn filters_match_whole_module_names() {
+        apply_filters(&filters(&[("tower", "error")]));
+        assert_eq!(level_for("tower"), Error);
+        assert_eq!(level_for("tower::targets"), Error);
+        assert_eq!(level_for("towers"), Info);
+    }
+
+    #[test]
+    fn unknown_levels_are_ignored() {
+        apply_filters(&filters(&[("tower", "loud"), ("creep", "trace")]));
+        assert_eq!(level_for("tower"), Info);
+        assert_eq!(level_for("creep"), Trace);
+    }
+}
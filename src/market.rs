@@ -0,0 +1,430 @@
+use std::collections::HashMap;
+
+use js_sys::{Object, Reflect};
+use log::*;
+use screeps::{
+    game, prelude::*, Order, OrderType, ResourceType, ReturnCode, Room, RoomName, StructureTerminal,
+};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{JsCast, JsValue};
+
+use crate::config::MAX_DEALS_PER_TICK;
+use crate::terminal::{can_send, spare_amount};
+
+/// Trading policy, read from the `market` key in memory.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct MarketConfig {
+    // energy stored above this gets sold, never sold when unset
+    #[serde(default)]
+    pub sell_energy_above: Option<u32>,
+    // resources bought back up to the given amount when the room drops below it
+    #[serde(default)]
+    pub buy_below: HashMap<ResourceType, u32>,
+    // credits never spent below this
+    #[serde(default)]
+    pub min_credits: f64,
+    // biggest amount moved by a single deal
+    #[serde(default = "default_max_deal_amount")]
+    pub max_deal_amount: u32,
+    // never buys above this price per unit, buys at any price when unset
+    #[serde(default)]
+    pub max_buy_price: Option<f64>,
+    // never sells below this price per unit, sells at any price when unset
+    #[serde(default)]
+    pub min_sell_price: Option<f64>,
+}
+
+fn default_max_deal_amount() -> u32 {
+    1000
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TradeDecision {
+    Sell(ResourceType, u32),
+    Buy(ResourceType, u32),
+}
+
+/// Decides what a room should trade given what it has stored (storage and terminal together)
+/// and what is in its terminal, which is the only place sold resources can leave from.
+pub fn decide_trades(
+    config: &MarketConfig,
+    stored: &HashMap<ResourceType, u32>,
+    in_terminal: &HashMap<ResourceType, u32>,
+    reserves: &HashMap<ResourceType, u32>,
+    credits: f64,
+) -> Vec<TradeDecision> {
+    let mut decisions = Vec::new();
+    if let Some(threshold) = config.sell_energy_above {
+        let energy = stored.get(&ResourceType::Energy).copied().unwrap_or(0);
+        let sellable = spare_amount(
+            ResourceType::Energy,
+            in_terminal.get(&ResourceType::Energy).copied().unwrap_or(0),
+            reserves,
+        );
+        let amount = energy
+            .saturating_sub(threshold)
+            .min(sellable)
+            .min(config.max_deal_amount);
+        if amount > 0 {
+            decisions.push(TradeDecision::Sell(ResourceType::Energy, amount));
+        }
+    }
+    if credits > config.min_credits {
+        for (resource, wanted) in config.buy_below.iter() {
+            let have = stored.get(resource).copied().unwrap_or(0);
+            let amount = wanted.saturating_sub(have).min(config.max_deal_amount);
+            if amount > 0 {
+                decisions.push(TradeDecision::Buy(*resource, amount));
+            }
+        }
+    }
+    decisions.truncate(MAX_DEALS_PER_TICK);
+    decisions
+}
+
+/// Whether the order's price is within the configured bounds for the decision.
+pub fn price_acceptable(decision: &TradeDecision, price: f64, config: &MarketConfig) -> bool {
+    match decision {
+        TradeDecision::Sell(_, _) => config.min_sell_price.map_or(true, |min| price >= min),
+        TradeDecision::Buy(_, _) => config.max_buy_price.map_or(true, |max| price <= max),
+    }
+}
+
+/// Runs the trading policy for every room with a terminal ready to send. The terminal's cooldown
+/// lets a single deal through per tick, the first decision that finds an order takes it.
+pub fn auto_trade(config: &MarketConfig, reserves: &HashMap<ResourceType, u32>) {
+    let mut deals_left = MAX_DEALS_PER_TICK;
+    for room in game::rooms().values() {
+        if deals_left == 0 {
+            break;
+        }
+        let terminal = match room.terminal() {
+            Some(t) if t.cooldown() == 0 => t,
+            _ => continue,
+        };
+        let (stored, in_terminal) = room_stock(&room, &terminal);
+        let decisions = decide_trades(
+            config,
+            &stored,
+            &in_terminal,
+            reserves,
+            game::market::credits(),
+        );
+        if decisions
+            .iter()
+            .any(|decision| execute(decision, &terminal, room.name(), config, reserves))
+        {
+            deals_left -= 1;
+        }
+    }
+}
+
+fn room_stock(
+    room: &Room,
+    terminal: &StructureTerminal,
+) -> (HashMap<ResourceType, u32>, HashMap<ResourceType, u32>) {
+    let mut stored = HashMap::new();
+    let mut in_terminal = HashMap::new();
+    for resource in terminal.store().store_types() {
+        let amount = terminal.store().get_used_capacity(Some(resource));
+        in_terminal.insert(resource, amount);
+        *stored.entry(resource).or_insert(0) += amount;
+    }
+    if let Some(storage) = room.storage() {
+        for resource in storage.store().store_types() {
+            *stored.entry(resource).or_insert(0) +=
+                storage.store().get_used_capacity(Some(resource));
+        }
+    }
+    (stored, in_terminal)
+}
+
+/// The part of `amount` whose deal fits in the terminal without going below the reserves, given
+/// `cost`, the energy it takes to move all of it. A sale sends the resource out as well, a
+/// purchase only pays the energy. The cost goes down along with the amount.
+pub fn within_reserves(
+    resource: ResourceType,
+    amount: u32,
+    selling: bool,
+    cost: u32,
+    in_terminal: u32,
+    energy_in_terminal: u32,
+    reserves: &HashMap<ResourceType, u32>,
+) -> u32 {
+    let spare_energy = spare_amount(ResourceType::Energy, energy_in_terminal, reserves) as u64;
+    let (whole, cost) = (amount as u64, cost as u64);
+    let by_energy = if selling && resource == ResourceType::Energy {
+        spare_energy * whole / (whole + cost).max(1)
+    } else if cost == 0 {
+        whole
+    } else {
+        spare_energy * whole / cost
+    };
+    let fits = by_energy.min(whole) as u32;
+    if selling {
+        fits.min(spare_amount(resource, in_terminal, reserves))
+    } else {
+        fits
+    }
+}
+
+/// The market's orders of one type for the resource, filtered by the game rather than pulling
+/// the whole market.
+fn orders_for(resource: ResourceType, order_type: OrderType) -> Vec<Order> {
+    let filter = Object::new();
+    let _ = Reflect::set(
+        &filter,
+        &JsValue::from_str("resourceType"),
+        &resource.into(),
+    );
+    let _ = Reflect::set(&filter, &JsValue::from_str("type"), &order_type.into());
+    game::market::get_all_orders(Some(filter.unchecked_ref()))
+}
+
+/// Takes the best matching order on the market, returns whether a deal went through. The energy
+/// the deal costs comes out of the terminal, so the amount is trimmed to keep the reserves.
+fn execute(
+    decision: &TradeDecision,
+    terminal: &StructureTerminal,
+    room_name: RoomName,
+    config: &MarketConfig,
+    reserves: &HashMap<ResourceType, u32>,
+) -> bool {
+    let (resource, amount, order_type) = match decision {
+        TradeDecision::Sell(r, a) => (*r, *a, OrderType::Buy),
+        TradeDecision::Buy(r, a) => (*r, *a, OrderType::Sell),
+    };
+    let orders = orders_for(resource, order_type).into_iter();
+    // sell to the highest bidder, buy from the cheapest seller
+    let order = match order_type {
+        OrderType::Buy => orders.max_by(|a, b| a.price().total_cmp(&b.price())),
+        OrderType::Sell => orders.min_by(|a, b| a.price().total_cmp(&b.price())),
+    };
+    let order = match order {
+        Some(o) => o,
+        None => {
+            debug!("no order to {:?} {:?}", decision, resource);
+            return false;
+        }
+    };
+    if !price_acceptable(decision, order.price(), config) {
+        debug!(
+            "best price for {:?} is {}, out of bounds",
+            decision,
+            order.price()
+        );
+        return false;
+    }
+    let order_room = match order.room_name() {
+        Some(r) => r,
+        None => return false,
+    };
+    let selling = order_type == OrderType::Buy;
+    let store = terminal.store();
+    let in_terminal = store.get_used_capacity(Some(resource));
+    let energy_in_terminal = store.get_used_capacity(Some(ResourceType::Energy));
+    let amount = amount.min(order.remaining_amount());
+    let cost = game::market::calc_transaction_cost(amount, room_name, order_room);
+    let amount = within_reserves(
+        resource,
+        amount,
+        selling,
+        cost,
+        in_terminal,
+        energy_in_terminal,
+        reserves,
+    );
+    // rounding can still leave the trimmed deal a unit short
+    let cost = game::market::calc_transaction_cost(amount, room_name, order_room);
+    let (sent, sent_amount) = if selling {
+        (resource, amount)
+    } else {
+        (ResourceType::Energy, 0)
+    };
+    if amount == 0
+        || !can_send(
+            sent,
+            sent_amount,
+            cost,
+            in_terminal,
+            energy_in_terminal,
+            reserves,
+        )
+    {
+        info!("not trading {:?}, it would break the reserve", decision);
+        return false;
+    }
+    if order_type == OrderType::Sell
+        && game::market::credits() - order.price() * (amount as f64) < config.min_credits
+    {
+        info!(
+            "not buying {:?}, it would go below the credits limit",
+            resource
+        );
+        return false;
+    }
+    let r = game::market::deal(&order.id(), amount, Some(room_name));
+    if r != ReturnCode::Ok {
+        warn!("deal for {:?} failed: {:?}", decision, r);
+        return false;
+    }
+    info!("{:?} at {} in {}", decision, order.price(), room_name);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> MarketConfig {
+        MarketConfig {
+            sell_energy_above: Some(100_000),
+            buy_below: HashMap::new(),
+            min_credits: 1000.0,
+            max_deal_amount: default_max_deal_amount(),
+            max_buy_price: Some(2.0),
+            min_sell_price: Some(0.5),
+        }
+    }
+
+    fn amounts(entries: &[(ResourceType, u32)]) -> HashMap<ResourceType, u32> {
+        entries.iter().copied().collect()
+    }
+
+    #[test]
+    fn energy_above_the_threshold_is_sold_from_the_terminal() {
+        let stored = amounts(&[(ResourceType::Energy, 100_500)]);
+        let in_terminal = amounts(&[(ResourceType::Energy, 20_000)]);
+        let decisions = decide_trades(&config(), &stored, &in_terminal, &HashMap::new(), 0.0);
+        assert_eq!(
+            decisions,
+            vec![TradeDecision::Sell(ResourceType::Energy, 500)]
+        );
+    }
+
+    #[test]
+    fn sales_are_capped_by_deal_size_and_reserves() {
+        let stored = amounts(&[(ResourceType::Energy, 200_000)]);
+        let in_terminal = amounts(&[(ResourceType::Energy, 20_000)]);
+        let decisions = decide_trades(&config(), &stored, &in_terminal, &HashMap::new(), 0.0);
+        assert_eq!(
+            decisions,
+            vec![TradeDecision::Sell(ResourceType::Energy, 1000)]
+        );
+        let reserves = amounts(&[(ResourceType::Energy, 19_800)]);
+        let decisions = decide_trades(&config(), &stored, &in_terminal, &reserves, 0.0);
+        assert_eq!(
+            decisions,
+            vec![TradeDecision::Sell(ResourceType::Energy, 200)]
+        );
+    }
+
+    #[test]
+    fn buys_only_while_above_the_credits_limit() {
+        let mut config = config();
+        config.sell_energy_above = None;
+        config.buy_below.insert(ResourceType::Hydrogen, 300);
+        let stored = amounts(&[(ResourceType::Hydrogen, 100)]);
+        let decisions = decide_trades(&config, &stored, &HashMap::new(), &HashMap::new(), 5000.0);
+        assert_eq!(
+            decisions,
+            vec![TradeDecision::Buy(ResourceType::Hydrogen, 200)]
+        );
+        let decisions = decide_trades(&config, &stored, &HashMap::new(), &HashMap::new(), 500.0);
+        assert!(decisions.is_empty());
+    }
+
+    #[test]
+    fn deals_stay_within_the_price_bounds() {
+        let sell = TradeDecision::Sell(ResourceType::Energy, 500);
+        assert!(price_acceptable(&sell, 0.5, &config()));
+        assert!(!price_acceptable(&sell, 0.4, &config()));
+        let buy = TradeDecision::Buy(ResourceType::Hydrogen, 200);
+        assert!(price_acceptable(&buy, 2.0, &config()));
+        assert!(!price_acceptable(&buy, 2.1, &config()));
+    }
+
+    #[test]
+    fn unset_bounds_take_any_price() {
+        let config = MarketConfig::default();
+        assert!(price_acceptable(
+            &TradeDecision::Sell(ResourceType::Energy, 1),
+            0.001,
+            &config
+        ));
+        assert!(price_acceptable(
+            &TradeDecision::Buy(ResourceType::Hydrogen, 1),
+            1000.0,
+            &config
+        ));
+    }
+
+    #[test]
+    fn sale_is_trimmed_by_its_transfer_cost() {
+        let reserves = amounts(&[(ResourceType::Energy, 19_000)]);
+        // 1000 spare, selling all of it would cost another 250
+        let amount = within_reserves(
+            ResourceType::Energy,
+            1000,
+            true,
+            250,
+            20_000,
+            20_000,
+            &reserves,
+        );
+        assert_eq!(amount, 800);
+        assert!(can_send(
+            ResourceType::Energy,
+            amount,
+            200,
+            20_000,
+            20_000,
+            &reserves
+        ));
+    }
+
+    #[test]
+    fn purchase_is_trimmed_by_the_energy_it_costs() {
+        let reserves = amounts(&[(ResourceType::Energy, 19_900)]);
+        let amount = within_reserves(
+            ResourceType::Hydrogen,
+            1000,
+            false,
+            200,
+            0,
+            20_000,
+            &reserves,
+        );
+        assert_eq!(amount, 500);
+    }
+
+    #[test]
+    fn mineral_sale_keeps_both_reserves() {
+        let reserves = amounts(&[(ResourceType::Energy, 20_000), (ResourceType::Oxygen, 500)]);
+        assert_eq!(
+            within_reserves(
+                ResourceType::Oxygen,
+                1000,
+                true,
+                100,
+                800,
+                20_000,
+                &reserves
+            ),
+            0
+        );
+        let reserves = amounts(&[(ResourceType::Oxygen, 500)]);
+        assert_eq!(
+            within_reserves(
+                ResourceType::Oxygen,
+                1000,
+                true,
+                100,
+                800,
+                20_000,
+                &reserves
+            ),
+            300
+        );
+    }
+}
@@ -0,0 +1,470 @@
+use log::*;
+use screeps::{
+    find, game, prelude::*, CircleStyle, Position, ReturnCode, Room, RoomCoordinate, RoomVisual,
+    Source, StructureType, Terrain,
+};
+
+use crate::config::{
+    CONTROLLER_CONTAINER_MIN_RCL, CONTROLLER_CONTAINER_RANGE, SPAWN_LANDMARK_CLEARANCE,
+    TOWER_PLAN_FLAG, TOWER_SUGGESTION_COUNT, TOWER_SUGGESTION_RADIUS,
+};
+use crate::roles::harvester::is_source_container;
+use crate::roles::mineral_harvester::extractor;
+
+/// Places the spawn site of every room we claimed that has none yet, for pioneers to build.
+pub fn plan_first_spawns() {
+    for room in game::rooms().values() {
+        let claimed = room.controller().map(|c| c.my()).unwrap_or(false);
+        if !claimed || !room.find(find::MY_SPAWNS).is_empty() {
+            continue;
+        }
+        let site_pending = room
+            .find(find::MY_CONSTRUCTION_SITES)
+            .iter()
+            .any(|s| s.structure_type() == StructureType::Spawn);
+        if site_pending {
+            continue;
+        }
+        plan_first_spawn(&room);
+    }
+}
+
+fn plan_first_spawn(room: &Room) {
+    let controller = match room.controller() {
+        Some(c) => c,
+        None => return,
+    };
+    let mut landmarks: Vec<(u8, u8)> = room
+        .find(find::SOURCES)
+        .iter()
+        .map(|s| (s.pos().x().u8(), s.pos().y().u8()))
+        .collect();
+    landmarks.push((controller.pos().x().u8(), controller.pos().y().u8()));
+    let center = match centroid(&landmarks) {
+        Some(c) => c,
+        None => return,
+    };
+    let terrain = game::map::get_room_terrain(room.name());
+    let walkable = |x: u8, y: u8| terrain.get(x, y) != Terrain::Wall;
+    // room around the spawn for creeps to come out and for the first extensions
+    let mut open = Vec::new();
+    for x in 2..=47u8 {
+        for y in 2..=47u8 {
+            if (x - 1..=x + 1).all(|nx| (y - 1..=y + 1).all(|ny| walkable(nx, ny))) {
+                open.push((x, y));
+            }
+        }
+    }
+    let (x, y) = match pick_spawn_tile(center, &open, &landmarks) {
+        Some(t) => t,
+        None => {
+            warn!("no open area for a spawn in {}", room.name());
+            return;
+        }
+    };
+    let r = room.create_construction_site(x, y, StructureType::Spawn, None);
+    if r == ReturnCode::Ok {
+        info!("placed first spawn site of {} at {}, {}", room.name(), x, y);
+    } else {
+        warn!("couldn't place spawn site in {}: {:?}", room.name(), r);
+    }
+}
+
+/// The `open` tile closest to `center`, between the sources and the controller, keeping clear of
+/// them so their own tiles stay free.
+pub fn pick_spawn_tile(
+    center: (u8, u8),
+    open: &[(u8, u8)],
+    landmarks: &[(u8, u8)],
+) -> Option<(u8, u8)> {
+    let range = |a: (u8, u8), b: (u8, u8)| a.0.abs_diff(b.0).max(a.1.abs_diff(b.1));
+    open.iter()
+        .filter(|t| {
+            landmarks
+                .iter()
+                .all(|l| range(**t, *l) >= SPAWN_LANDMARK_CLEARANCE)
+        })
+        .min_by_key(|t| (range(**t, center), t.1, t.0))
+        .copied()
+}
+
+/// Lays down the containers a room is missing: the controller's and, once it has an extractor, the
+/// mineral's.
+pub fn plan_containers() {
+    for room in game::rooms().values() {
+        plan_controller_container(&room);
+        plan_mineral_container(&room);
+    }
+}
+
+/// Places a container site next to the mineral once the room has an extractor, for the mineral
+/// harvester to mine into.
+fn plan_mineral_container(room: &Room) {
+    let mine = room.controller().map(|c| c.my()).unwrap_or(false);
+    if !mine || extractor(room).is_none() {
+        return;
+    }
+    let mineral = match room.find(find::MINERALS).into_iter().next() {
+        Some(m) => m,
+        None => return,
+    };
+    if container_positions(room)
+        .iter()
+        .any(|p| p.is_near_to(mineral.pos()))
+    {
+        return;
+    }
+    let spawn_pos = room.find(find::MY_SPAWNS).first().map(|s| s.pos());
+    let tile = walkable_tiles_around(mineral.pos())
+        .into_iter()
+        .min_by_key(|p| spawn_pos.map(|s| p.get_range_to(s)).unwrap_or(0));
+    match tile {
+        Some(pos) => {
+            let r = room.create_construction_site(
+                pos.x().u8(),
+                pos.y().u8(),
+                StructureType::Container,
+                None,
+            );
+            if r == ReturnCode::Ok {
+                info!("placed mineral container site at {:?}", pos);
+            } else {
+                warn!("couldn't place mineral container site: {:?}", r);
+            }
+        }
+        None => {
+            warn!("no free tile for a mineral container in {}", room.name());
+        }
+    }
+}
+
+/// Places a container site next to the controller for upgraders to draw from, once the room is
+/// at a high enough level and every source already has its own container.
+fn plan_controller_container(room: &Room) {
+    let controller = match room.controller() {
+        Some(c) if c.my() => c,
+        _ => return,
+    };
+    let sources = room.find(find::SOURCES);
+    if !should_place_controller_container(
+        controller.level(),
+        all_sources_have_container(room, &sources),
+        has_container_near(room, controller.pos()),
+    ) {
+        return;
+    }
+    let spawn_pos = room.find(find::MY_SPAWNS).first().map(|s| s.pos());
+    let tile = walkable_tiles_around(controller.pos())
+        .into_iter()
+        // harvesters would claim a container this close to a source
+        .filter(|p| !sources.iter().any(|s| is_source_container(*p, s.pos())))
+        .min_by_key(|p| spawn_pos.map(|s| p.get_range_to(s)).unwrap_or(0));
+    match tile {
+        Some(pos) => {
+            let r = room.create_construction_site(
+                pos.x().u8(),
+                pos.y().u8(),
+                StructureType::Container,
+                None,
+            );
+            if r == ReturnCode::Ok {
+                info!("placed controller container site at {:?}", pos);
+            } else {
+                warn!("couldn't place controller container site: {:?}", r);
+            }
+        }
+        None => {
+            warn!("no free tile for a controller container in {}", room.name());
+        }
+    }
+}
+
+/// Whether the controller still needs its container. It waits for the source containers, which
+/// keep the economy going, and there's only ever one.
+pub fn should_place_controller_container(
+    rcl: u8,
+    sources_covered: bool,
+    already_placed: bool,
+) -> bool {
+    rcl >= CONTROLLER_CONTAINER_MIN_RCL && sources_covered && !already_placed
+}
+
+fn all_sources_have_container(room: &Room, sources: &[Source]) -> bool {
+    let containers: Vec<Position> = container_positions(room);
+    sources
+        .iter()
+        .all(|s| containers.iter().any(|c| is_source_container(*c, s.pos())))
+}
+
+/// Whether a container, built or just a site, already sits within range of the controller.
+fn has_container_near(room: &Room, controller_pos: Position) -> bool {
+    container_positions(room)
+        .iter()
+        .any(|c| c.get_range_to(controller_pos) <= CONTROLLER_CONTAINER_RANGE)
+}
+
+/// Positions of the room's containers and container construction sites.
+fn container_positions(room: &Room) -> Vec<Position> {
+    let built = room
+        .find(find::STRUCTURES)
+        .into_iter()
+        .filter(|s| s.structure_type() == StructureType::Container)
+        .map(|s| s.pos());
+    let sites = room
+        .find(find::MY_CONSTRUCTION_SITES)
+        .into_iter()
+        .filter(|s| s.structure_type() == StructureType::Container)
+        .map(|s| s.pos());
+    built.chain(sites).collect()
+}
+
+/// The tiles adjacent to `pos` that aren't natural walls.
+pub fn walkable_tiles_around(pos: Position) -> Vec<Position> {
+    let terrain = game::map::get_room_terrain(pos.room_name());
+    tiles_around(pos)
+        .into_iter()
+        .filter(|p| terrain.get(p.x().u8(), p.y().u8()) != Terrain::Wall)
+        .collect()
+}
+
+/// The tiles adjacent to `pos`, leaving out the room's edges.
+fn tiles_around(pos: Position) -> Vec<Position> {
+    let mut tiles = Vec::new();
+    for dx in -1i16..=1 {
+        for dy in -1i16..=1 {
+            if dx == 0 && dy == 0 {
+                continue;
+            }
+            let x = pos.x().u8() as i16 + dx;
+            let y = pos.y().u8() as i16 + dy;
+            if x < 1 || x > 48 || y < 1 || y > 48 {
+                continue;
+            }
+            if let (Ok(x), Ok(y)) = (RoomCoordinate::new(x as u8), RoomCoordinate::new(y as u8)) {
+                tiles.push(Position::new(x, y, pos.room_name()));
+            }
+        }
+    }
+    tiles
+}
+
+/// Draws the suggested tower spots in every room holding the tower planning flag, for them to be
+/// built by hand.
+pub fn show_tower_suggestions() {
+    for flag in game::flags().values() {
+        if flag.name() != TOWER_PLAN_FLAG {
+            continue;
+        }
+        let room = match flag.room() {
+            Some(r) => r,
+            None => continue,
+        };
+        let visual = RoomVisual::new(Some(room.name()));
+        for (x, y) in suggest_tower_positions(&room) {
+            debug!("suggested tower spot in {}: {}, {}", room.name(), x, y);
+            visual.circle(
+                x as f32,
+                y as f32,
+                Some(CircleStyle::default().radius(0.4).fill("#ff5555")),
+            );
+        }
+    }
+}
+
+/// Free tiles close to the middle of the base that keep our walls and ramparts within the
+/// shortest range, where towers hit hardest.
+pub fn suggest_tower_positions(room: &Room) -> Vec<(u8, u8)> {
+    let structures = room.find(find::STRUCTURES);
+    let base: Vec<(u8, u8)> = room
+        .find(find::MY_STRUCTURES)
+        .iter()
+        .filter(|s| {
+            matches!(
+                s.structure_type(),
+                StructureType::Spawn | StructureType::Storage | StructureType::Extension
+            )
+        })
+        .map(|s| (s.pos().x().u8(), s.pos().y().u8()))
+        .collect();
+    let center = match centroid(&base) {
+        Some(c) => c,
+        None => return Vec::new(),
+    };
+    let defenses: Vec<(u8, u8)> = structures
+        .iter()
+        .filter(|s| {
+            matches!(
+                s.structure_type(),
+                StructureType::Wall | StructureType::Rampart
+            )
+        })
+        .map(|s| (s.pos().x().u8(), s.pos().y().u8()))
+        .collect();
+    let taken: Vec<(u8, u8)> = structures
+        .iter()
+        .filter(|s| s.structure_type() != StructureType::Road)
+        .map(|s| (s.pos().x().u8(), s.pos().y().u8()))
+        .collect();
+    let terrain = game::map::get_room_terrain(room.name());
+    let radius = TOWER_SUGGESTION_RADIUS as i16;
+    let mut free = Vec::new();
+    for dx in -radius..=radius {
+        for dy in -radius..=radius {
+            let x = center.0 as i16 + dx;
+            let y = center.1 as i16 + dy;
+            if x < 2 || x > 47 || y < 2 || y > 47 {
+                continue;
+            }
+            let tile = (x as u8, y as u8);
+            if terrain.get(tile.0, tile.1) != Terrain::Wall && !taken.contains(&tile) {
+                free.push(tile);
+            }
+        }
+    }
+    pick_tower_tiles(center, &free, &defenses, TOWER_SUGGESTION_COUNT)
+}
+
+/// Average position of `points`.
+pub fn centroid(points: &[(u8, u8)]) -> Option<(u8, u8)> {
+    if points.is_empty() {
+        return None;
+    }
+    let n = points.len() as u32;
+    let sum_x: u32 = points.iter().map(|p| p.0 as u32).sum();
+    let sum_y: u32 = points.iter().map(|p| p.1 as u32).sum();
+    Some(((sum_x / n) as u8, (sum_y / n) as u8))
+}
+
+/// Picks up to `count` of the `free` tiles, favoring those whose furthest defense is closest,
+/// then those closest to `center`. Picked tiles are never next to each other, leaving room to
+/// walk between towers.
+pub fn pick_tower_tiles(
+    center: (u8, u8),
+    free: &[(u8, u8)],
+    defenses: &[(u8, u8)],
+    count: usize,
+) -> Vec<(u8, u8)> {
+    let range = |a: (u8, u8), b: (u8, u8)| {
+        (a.0 as i16 - b.0 as i16)
+            .abs()
+            .max((a.1 as i16 - b.1 as i16).abs()) as u32
+    };
+    let mut ranked: Vec<(u32, u32, (u8, u8))> = free
+        .iter()
+        .map(|t| {
+            let furthest_defense = defenses.iter().map(|d| range(*t, *d)).max().unwrap_or(0);
+            (furthest_defense, range(*t, center), *t)
+        })
+        .collect();
+    ranked.sort();
+    let mut picked: Vec<(u8, u8)> = Vec::new();
+    for (_, _, tile) in ranked {
+        if picked.len() >= count {
+            break;
+        }
+        if picked.iter().all(|p| range(*p, tile) > 1) {
+            picked.push(tile);
+        }
+    }
+    picked
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screeps::RoomName;
+
+    #[test]
+    fn controller_container_waits_for_the_source_containers() {
+        assert!(!should_place_controller_container(
+            CONTROLLER_CONTAINER_MIN_RCL,
+            false,
+            false
+        ));
+        assert!(should_place_controller_container(
+            CONTROLLER_CONTAINER_MIN_RCL,
+            true,
+            false
+        ));
+    }
+
+    #[test]
+    fn controller_container_is_placed_once_from_its_level() {
+        assert!(!should_place_controller_container(
+            CONTROLLER_CONTAINER_MIN_RCL - 1,
+            true,
+            false
+        ));
+        assert!(!should_place_controller_container(8, true, true));
+    }
+
+    #[test]
+    fn towers_go_where_every_defense_is_in_reach() {
+        let defenses = [(10, 10), (30, 10)];
+        let free = [(12, 10), (20, 10), (28, 10)];
+        assert_eq!(
+            pick_tower_tiles((20, 20), &free, &defenses, 1),
+            vec![(20, 10)]
+        );
+    }
+
+    #[test]
+    fn towers_are_never_placed_side_by_side() {
+        let free = [(20, 20), (21, 20), (22, 20)];
+        assert_eq!(
+            pick_tower_tiles((20, 20), &free, &[], 3),
+            vec![(20, 20), (22, 20)]
+        );
+    }
+
+    #[test]
+    fn towers_center_on_the_landmarks() {
+        assert_eq!(centroid(&[(10, 10), (20, 30)]), Some((15, 20)));
+        assert_eq!(centroid(&[]), None);
+    }
+
+    fn pos(x: u8, y: u8) -> Position {
+        Position::new(
+            RoomCoordinate::new(x).unwrap(),
+            RoomCoordinate::new(y).unwrap(),
+            RoomName::new("W1N1").unwrap(),
+        )
+    }
+
+    #[test]
+    fn drop_miners_can_stand_on_any_tile_around_the_source() {
+        let tiles = tiles_around(pos(10, 10));
+        assert_eq!(tiles.len(), 8);
+        assert!(tiles.iter().all(|t| t.get_range_to(pos(10, 10)) == 1));
+    }
+
+    #[test]
+    fn tiles_on_the_room_edge_are_left_out() {
+        assert_eq!(tiles_around(pos(1, 10)).len(), 5);
+        assert_eq!(tiles_around(pos(1, 1)).len(), 3);
+    }
+
+    fn open_area() -> Vec<(u8, u8)> {
+        (2..=47u8)
+            .flat_map(|x| (2..=47u8).map(move |y| (x, y)))
+            .collect()
+    }
+
+    #[test]
+    fn spawn_site_goes_between_sources_and_controller() {
+        let landmarks = [(10, 10), (30, 10), (20, 30)];
+        let center = centroid(&landmarks).unwrap();
+        assert_eq!(
+            pick_spawn_tile(center, &open_area(), &landmarks),
+            Some((20, 16))
+        );
+    }
+
+    #[test]
+    fn spawn_site_keeps_clear_of_the_landmarks() {
+        let landmarks = [(20, 20)];
+        let tile = pick_spawn_tile((20, 20), &open_area(), &landmarks).unwrap();
+        assert_eq!(tile, (17, 17));
+        assert_eq!(pick_spawn_tile((20, 20), &[(21, 21)], &landmarks), None);
+    }
+}
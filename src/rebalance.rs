@@ -0,0 +1,125 @@
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{find, game, prelude::*, Part};
+
+use crate::lifecycle::{self, LifecycleEvent};
+use crate::roles::role::Role;
+use crate::storage::*;
+
+/// Room by room, moves creeps from roles with more creeps than desired over to roles with fewer,
+/// as long as their body can do the new job. Saves waiting on the spawn after losing a role.
+/// Returns the creeps that changed role.
+pub fn rebalance_roles() -> Vec<(String, Role)> {
+    let desired = Role::desired_counts();
+    let mut reassigned = Vec::new();
+    CREEPS_ROLE.with(|creeps_role_refcell| {
+        let mut creeps_role = creeps_role_refcell.borrow_mut();
+        for room in game::rooms().values() {
+            let mut candidates: Vec<(String, Role, Vec<Part>)> = room
+                .find(find::MY_CREEPS)
+                .iter()
+                .filter(|c| !c.spawning())
+                .filter_map(|c| {
+                    let role = creeps_role.get(&c.name())?.clone();
+                    let body = c.body().iter().map(|p| p.part()).collect();
+                    Some((c.name(), role, body))
+                })
+                .collect();
+            while let Some((name, new_role)) = pick_reassignment(&candidates, &desired) {
+                info!(
+                    "{} reassigned to {} in {}",
+                    name,
+                    new_role.to_string(),
+                    room.name()
+                );
+                lifecycle::emit(&name, LifecycleEvent::RoleAssigned(new_role.clone()));
+                creeps_role.insert(name.clone(), new_role.clone());
+                reassigned.push((name.clone(), new_role.clone()));
+                for candidate in candidates.iter_mut() {
+                    if candidate.0 == name {
+                        candidate.1 = new_role.clone();
+                    }
+                }
+            }
+        }
+    });
+    reassigned
+}
+
+/// Picks a creep, among `(name, role, body)`, whose role has a surplus and whose body supports
+/// the most urgent role in deficit.
+pub fn pick_reassignment(
+    creeps: &[(String, Role, Vec<Part>)],
+    desired: &HashMap<Role, usize>,
+) -> Option<(String, Role)> {
+    let count = |role: &Role| creeps.iter().filter(|(_, r, _)| r == role).count();
+    let wanted = |role: &Role| desired.get(role).copied().unwrap_or(0);
+    for short_role in Role::spawn_order() {
+        if count(&short_role) >= wanted(&short_role) {
+            continue;
+        }
+        // roles spawned outside of the desired counts, like mineral harvesters, are left alone
+        let donor = creeps.iter().find(|(_, role, body)| {
+            *role != short_role
+                && desired.contains_key(role)
+                && count(role) > wanted(role)
+                && short_role.body_supports(body)
+        });
+        if let Some((name, _, _)) = donor {
+            return Some((name.clone(), short_role));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desired() -> HashMap<Role, usize> {
+        [(Role::Harvester, 1), (Role::Hauler, 1), (Role::Builder, 1)]
+            .into_iter()
+            .collect()
+    }
+
+    fn creep(name: &str, role: Role, body: &[Part]) -> (String, Role, Vec<Part>) {
+        (name.to_string(), role, body.to_vec())
+    }
+
+    #[test]
+    fn surplus_creep_fills_a_role_its_body_supports() {
+        let creeps = vec![
+            creep("h1", Role::Harvester, &[Part::Work, Part::Move]),
+            creep("b1", Role::Builder, &[Part::Work, Part::Carry, Part::Move]),
+            creep("b2", Role::Builder, &[Part::Work, Part::Carry, Part::Move]),
+        ];
+        assert_eq!(
+            pick_reassignment(&creeps, &desired()),
+            Some(("b1".to_string(), Role::Hauler))
+        );
+    }
+
+    #[test]
+    fn bodies_unfit_for_the_missing_role_are_not_moved() {
+        let creeps = vec![
+            creep("h1", Role::Harvester, &[Part::Work, Part::Move]),
+            creep("h2", Role::Harvester, &[Part::Work, Part::Move]),
+            creep("b1", Role::Builder, &[Part::Work, Part::Carry, Part::Move]),
+        ];
+        assert_eq!(pick_reassignment(&creeps, &desired()), None);
+    }
+
+    #[test]
+    fn roles_at_their_count_keep_their_creeps() {
+        let creeps = vec![
+            creep(
+                "h1",
+                Role::Harvester,
+                &[Part::Work, Part::Carry, Part::Move],
+            ),
+            creep("b1", Role::Builder, &[Part::Work, Part::Carry, Part::Move]),
+        ];
+        assert_eq!(pick_reassignment(&creeps, &desired()), None);
+    }
+}
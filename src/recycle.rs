@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{
+    find,
+    pathfinder::{self, SearchOptions},
+    prelude::*,
+    Part, ReturnCode, StructureSpawn,
+};
+
+use crate::config::{RECYCLE_BELOW_TTL, RECYCLE_CHECK_INTERVAL, RENEW_MIN_BODY_COST};
+use crate::creep::{assign_target, continue_to_unseen_room, find_closest, move_towards};
+use crate::roles::role;
+use crate::storage::CreepTarget;
+
+/// Walks creeps near the end of their life to the closest spawn by path to get some of their
+/// cost back, as long as they make it there alive. Bodies worth renewing are left to the spawn.
+/// Returns whether the creep is on its way to be recycled.
+pub fn run_recycle(
+    creep: &screeps::Creep,
+    creep_targets: &mut HashMap<String, CreepTarget>,
+) -> bool {
+    let name = creep.name();
+    let spawn = match creep_targets.get(&name) {
+        Some(CreepTarget::Recycle(id)) => match id.resolve() {
+            Some(s) => s,
+            None => {
+                if continue_to_unseen_room(creep) {
+                    return true;
+                }
+                creep_targets.remove(&name);
+                return false;
+            }
+        },
+        _ => match recycle_spawn(creep) {
+            Some(s) => {
+                if !assign_target(creep_targets, creep, CreepTarget::Recycle(s.id())) {
+                    return false;
+                }
+                s
+            }
+            None => return false,
+        },
+    };
+    if creep.pos().is_near_to(spawn.pos()) {
+        let r = spawn.recycle_creep(creep);
+        if r != ReturnCode::Ok {
+            warn!("couldn't recycle {}: {:?}", name, r);
+            creep_targets.remove(&name);
+            return false;
+        }
+    } else {
+        let _ = move_towards(creep, spawn.pos());
+    }
+    true
+}
+
+/// Sends the creep to the closest spawn to be recycled whatever its ticks to live, e.g. a
+/// defender no longer needed. Returns whether it's on its way.
+pub fn recycle_now(
+    creep: &screeps::Creep,
+    creep_targets: &mut HashMap<String, CreepTarget>,
+) -> bool {
+    let spawn = match find_closest(creep.pos(), find::MY_SPAWNS) {
+        Some(s) => s,
+        None => return false,
+    };
+    assign_target(creep_targets, creep, CreepTarget::Recycle(spawn.id()))
+        && run_recycle(creep, creep_targets)
+}
+
+/// The spawn the creep should be recycled at, if its time has come and the trip is worth it.
+fn recycle_spawn(creep: &screeps::Creep) -> Option<StructureSpawn> {
+    let ttl = creep.ticks_to_live()?;
+    // the path search isn't cheap, it's only done every few ticks
+    if ttl >= RECYCLE_BELOW_TTL || ttl % RECYCLE_CHECK_INTERVAL != 0 {
+        return None;
+    }
+    let body: Vec<Part> = creep.body().iter().map(|p| p.part()).collect();
+    if role::body_cost(&body) >= RENEW_MIN_BODY_COST {
+        return None;
+    }
+    let spawn = find_closest(creep.pos(), find::MY_SPAWNS)?;
+    if worth_recycling(creep, &spawn) {
+        Some(spawn)
+    } else {
+        debug!(
+            "{} can't reach {} in time, working on",
+            creep.name(),
+            spawn.name()
+        );
+        None
+    }
+}
+
+/// Whether the creep reaches the spawn before dying, walking the path at its body's pace.
+pub fn worth_recycling(creep: &screeps::Creep, spawn: &StructureSpawn) -> bool {
+    let ttl = match creep.ticks_to_live() {
+        Some(t) => t,
+        None => return false,
+    };
+    let search = pathfinder::search(creep.pos(), spawn.pos(), 1, Some(SearchOptions::default()));
+    if search.incomplete() {
+        return false;
+    }
+    let body: Vec<Part> = creep.body().iter().map(|p| p.part()).collect();
+    reaches_in_time(ttl, search.path().len() as u32, &body)
+}
+
+/// A tile takes a tick per move part's worth of other parts, at least one, as on plains.
+pub fn reaches_in_time(ticks_to_live: u32, path_len: u32, body: &[Part]) -> bool {
+    let moves = body.iter().filter(|p| **p == Part::Move).count() as u32;
+    if moves == 0 {
+        return path_len == 0;
+    }
+    let others = body.len() as u32 - moves;
+    let ticks_per_tile = ((others + moves - 1) / moves).max(1);
+    path_len * ticks_per_tile < ticks_to_live
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn creep_reaches_spawn_at_its_body_pace() {
+        let fast = [Part::Work, Part::Move];
+        assert!(reaches_in_time(11, 10, &fast));
+        assert!(!reaches_in_time(10, 10, &fast));
+        let slow = [Part::Work, Part::Work, Part::Carry, Part::Move];
+        assert!(reaches_in_time(31, 10, &slow));
+        assert!(!reaches_in_time(30, 10, &slow));
+    }
+
+    #[test]
+    fn creep_without_move_parts_only_recycles_in_place() {
+        assert!(reaches_in_time(5, 0, &[Part::Work]));
+        assert!(!reaches_in_time(500, 1, &[Part::Work]));
+    }
+}
@@ -0,0 +1,70 @@
+use log::*;
+use screeps::{find, prelude::*, Part, ReturnCode, StructureSpawn};
+
+use crate::config::{RENEW_BELOW_TTL, RENEW_ENERGY_RESERVE, RENEW_MIN_BODY_COST};
+use crate::roles::role;
+
+/// Renews the most worn out valuable creep next to the spawn, if the room can spare the energy
+/// once the pending spawn, if any, is paid for.
+pub fn try_renew(spawn: &StructureSpawn, pending_spawn_cost: Option<u32>) {
+    if spawn.spawning().is_some() {
+        return;
+    }
+    let candidate = spawn
+        .pos()
+        .find_in_range(find::MY_CREEPS, 1)
+        .into_iter()
+        .filter(|c| body_cost(c) >= RENEW_MIN_BODY_COST)
+        .filter(|c| {
+            c.ticks_to_live()
+                .map(|t| t < RENEW_BELOW_TTL)
+                .unwrap_or(false)
+        })
+        .min_by_key(|c| c.ticks_to_live().unwrap_or(0));
+    let creep = match candidate {
+        Some(c) => c,
+        None => return,
+    };
+    let energy_available = spawn.room().unwrap().energy_available();
+    if !can_renew(energy_available, renew_cost(&creep), pending_spawn_cost) {
+        debug!("deferring renewal of {}", creep.name());
+        return;
+    }
+    let r = spawn.renew_creep(&creep);
+    if r != ReturnCode::Ok {
+        warn!("couldn't renew {}: {:?}", creep.name(), r);
+    }
+}
+
+pub fn can_renew(energy_available: u32, renew_cost: u32, pending_spawn_cost: Option<u32>) -> bool {
+    energy_available >= renew_cost + pending_spawn_cost.unwrap_or(0) + RENEW_ENERGY_RESERVE
+}
+
+fn body_cost(creep: &screeps::Creep) -> u32 {
+    let body: Vec<Part> = creep.body().iter().map(|p| p.part()).collect();
+    role::body_cost(&body)
+}
+
+/// What a single renewal costs, as the game computes it.
+fn renew_cost(creep: &screeps::Creep) -> u32 {
+    let parts = creep.body().len().max(1) as f64;
+    (body_cost(creep) as f64 / 2.5 / parts).ceil() as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renewal_leaves_the_reserve_untouched() {
+        assert!(can_renew(50 + RENEW_ENERGY_RESERVE, 50, None));
+        assert!(!can_renew(49 + RENEW_ENERGY_RESERVE, 50, None));
+    }
+
+    #[test]
+    fn pending_spawn_is_paid_for_first() {
+        let energy = 300 + 50 + RENEW_ENERGY_RESERVE;
+        assert!(can_renew(energy, 50, Some(300)));
+        assert!(!can_renew(energy, 50, Some(301)));
+    }
+}
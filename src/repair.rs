@@ -0,0 +1,145 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use screeps::{game, prelude::*, ObjectId, Structure, StructureObject, StructureType};
+
+use crate::config::{MAX_REPAIR_ENERGY_PER_TICK, REPAIR_RAMPARTS, REPAIR_WALLS};
+
+/// Energy a tower spends on a single repair.
+pub const TOWER_REPAIR_COST: u32 = 10;
+
+// energy spent repairing each structure, only for the tick it was recorded on
+thread_local! {
+    static REPAIR_SPEND: RefCell<(u32, HashMap<ObjectId<Structure>, u32>)> =
+        RefCell::new((0, HashMap::new()));
+}
+
+fn with_spend<R>(f: impl FnOnce(&mut HashMap<ObjectId<Structure>, u32>) -> R) -> R {
+    with_spend_at(game::time(), f)
+}
+
+fn with_spend_at<R>(time: u32, f: impl FnOnce(&mut HashMap<ObjectId<Structure>, u32>) -> R) -> R {
+    REPAIR_SPEND.with(|spend_refcell| {
+        let mut spend = spend_refcell.borrow_mut();
+        if spend.0 != time {
+            *spend = (time, HashMap::new());
+        }
+        f(&mut spend.1)
+    })
+}
+
+/// Whether the structure can still take repairs this tick.
+pub fn has_repair_budget(id: ObjectId<Structure>) -> bool {
+    with_spend(|spend| budget_left(spend, id))
+}
+
+fn budget_left(spend: &HashMap<ObjectId<Structure>, u32>, id: ObjectId<Structure>) -> bool {
+    spend.get(&id).copied().unwrap_or(0) < MAX_REPAIR_ENERGY_PER_TICK
+}
+
+/// Index of the `(structure, key)` candidate with the lowest key, its range or its hits, among
+/// those that can still take repairs this tick. A structure that used up its budget leaves the
+/// repairer to the next one.
+pub fn pick_within_budget(candidates: &[(ObjectId<Structure>, u32)]) -> Option<usize> {
+    with_spend(|spend| lowest_within_budget(spend, candidates))
+}
+
+fn lowest_within_budget(
+    spend: &HashMap<ObjectId<Structure>, u32>,
+    candidates: &[(ObjectId<Structure>, u32)],
+) -> Option<usize> {
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, (id, _))| budget_left(spend, *id))
+        .min_by_key(|(_, (_, key))| *key)
+        .map(|(i, _)| i)
+}
+
+pub fn record_repair(id: ObjectId<Structure>, energy: u32) {
+    with_spend(|spend| *spend.entry(id).or_insert(0) += energy);
+}
+
+/// Our ramparts, as opposed to walls which have no owner.
+pub fn is_owned_defensive(structure: &StructureObject) -> bool {
+    structure.structure_type() == StructureType::Rampart
+        && structure.as_owned().map(|o| o.my()).unwrap_or(false)
+}
+
+/// Whether repairers should look after the structure at all, walls and ramparts each have
+/// their own toggle.
+pub fn should_maintain(structure: &StructureObject) -> bool {
+    maintains(structure.structure_type(), is_owned_defensive(structure))
+}
+
+/// Ramparts are only ours to repair when `owned_rampart`, walls and the rest always are.
+pub fn maintains(structure_type: StructureType, owned_rampart: bool) -> bool {
+    match structure_type {
+        StructureType::Wall => REPAIR_WALLS,
+        StructureType::Rampart => REPAIR_RAMPARTS && owned_rampart,
+        _ => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id() -> ObjectId<Structure> {
+        "5bbcab1d9099fc012e632f01".parse().unwrap()
+    }
+
+    #[test]
+    fn budget_runs_out_after_enough_repairs() {
+        let repairs = MAX_REPAIR_ENERGY_PER_TICK / TOWER_REPAIR_COST;
+        for _ in 0..repairs {
+            assert!(with_spend_at(1, |spend| budget_left(spend, id())));
+            with_spend_at(1, |spend| {
+                *spend.entry(id()).or_insert(0) += TOWER_REPAIR_COST
+            });
+        }
+        assert!(!with_spend_at(1, |spend| budget_left(spend, id())));
+    }
+
+    #[test]
+    fn budget_is_reset_every_tick() {
+        with_spend_at(1, |spend| {
+            spend.insert(id(), MAX_REPAIR_ENERGY_PER_TICK);
+        });
+        assert!(with_spend_at(2, |spend| budget_left(spend, id())));
+    }
+
+    #[test]
+    fn walls_and_ramparts_follow_their_own_toggle() {
+        assert_eq!(maintains(StructureType::Wall, false), REPAIR_WALLS);
+        assert_eq!(maintains(StructureType::Rampart, true), REPAIR_RAMPARTS);
+    }
+
+    #[test]
+    fn foreign_ramparts_are_never_repaired() {
+        assert!(!maintains(StructureType::Rampart, false));
+    }
+
+    #[test]
+    fn other_structures_are_always_maintained() {
+        assert!(maintains(StructureType::Road, false));
+        assert!(maintains(StructureType::Container, false));
+    }
+
+    fn other_id() -> ObjectId<Structure> {
+        "5bbcab1d9099fc012e632f02".parse().unwrap()
+    }
+
+    #[test]
+    fn repairer_moves_on_once_a_wall_hits_the_cap() {
+        let walls = [(id(), 1), (other_id(), 4)];
+        let mut spend = HashMap::new();
+        while budget_left(&spend, id()) {
+            assert_eq!(lowest_within_budget(&spend, &walls), Some(0));
+            *spend.entry(id()).or_insert(0) += TOWER_REPAIR_COST;
+        }
+        assert_eq!(lowest_within_budget(&spend, &walls), Some(1));
+        spend.insert(other_id(), MAX_REPAIR_ENERGY_PER_TICK);
+        assert_eq!(lowest_within_budget(&spend, &walls), None);
+    }
+}
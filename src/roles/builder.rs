@@ -1,10 +1,19 @@
 use log::*;
 use screeps::{
-    find, look, prelude::*, Look, Position, ResourceType, ReturnCode, RoomPosition, Source,
-    StructureContainer, StructureType,
+    find, look, prelude::*, Look, ObjectId, Part, Position, ResourceType, ReturnCode, RoomPosition,
+    Source, Structure, StructureContainer, StructureObject, StructureType,
 };
 
-use super::role::{CanHarvest, Deposit, Movable};
+use super::role::{CanHarvest, Deposit, Movable, Role};
+use crate::config::{
+    BOOST_UPGRADERS, BUILDER_REPAIR_UNTIL_PERCENT, BUILD_CLUSTER_RANGE, BUILD_ENERGY_PERCENT,
+    UPGRADE_BOOST,
+};
+use crate::creep::{assign_target, move_towards, structure_at};
+use crate::lab::{find_boost_lab, run_boost};
+use crate::repair::{has_repair_budget, pick_within_budget, record_repair, should_maintain};
+use crate::storage::{CreepTarget, CREEPS_ROLE};
+use std::collections::HashMap;
 
 pub struct Builder<'a> {
     pub creep: &'a screeps::Creep,
@@ -15,7 +24,7 @@ impl<'a> Movable for Builder<'a> {
     where
         T: HasPosition,
     {
-        let r = self.creep.move_to(target);
+        let r = move_towards(self.creep, target.pos());
         match r {
             ReturnCode::Ok => {}
             ReturnCode::Tired => {
@@ -28,74 +37,413 @@ impl<'a> Movable for Builder<'a> {
     }
 }
 
-// TODO: needs targets
 impl<'a> Builder<'a> {
-    pub fn run(&self) {
-        if self
+    /// Builds and repairs, sticking with one job until it's done or the store runs dry so the
+    /// energy carried goes into the closest work instead of being spread around.
+    pub fn run(&self, creep_targets: &mut HashMap<String, CreepTarget>) {
+        let name = self.creep.name();
+        if let Some(CreepTarget::Boost(lab_id)) = creep_targets.get(&name).cloned() {
+            if run_boost(self.creep, lab_id) {
+                return;
+            }
+            creep_targets.remove(&name);
+        }
+        let energy = self
             .creep
             .store()
-            .get_used_capacity(Some(ResourceType::Energy))
-            > 0
-        {
-            // Upgrade RANDOM CONSTRUCTION SITE but Controller
-            let site = self
-                .creep
-                .pos()
-                .find_closest_by_path(find::CONSTRUCTION_SITES);
-            match site {
-                Some(val) => {
-                    if self.creep.pos().is_near_to(val.pos()) {
-                        self.creep.build(&val);
+            .get_used_capacity(Some(ResourceType::Energy));
+        let step = builder_step(energy, creep_targets.contains_key(&name));
+        if step == BuilderStep::Refill {
+            creep_targets.remove(&name);
+            self.creep.say("E_OUT", false);
+            return;
+        }
+        let room = self.creep.room().unwrap();
+        let (slot, count) = self.builder_slot();
+        let max_level = room.controller().map(|c| c.level() == 8).unwrap_or(false);
+        if is_upgrade_slot(slot, count, BUILD_ENERGY_PERCENT, max_level) {
+            if let Some(controller) = room.controller() {
+                creep_targets.remove(&name);
+                // upgraders stop by a lab first, or go unboosted when no lab has the compound
+                if let Some(lab) =
+                    find_boost_lab(self.creep, BOOST_UPGRADERS, UPGRADE_BOOST, Part::Work)
+                {
+                    let target = CreepTarget::Boost(lab.id());
+                    if assign_target(creep_targets, self.creep, target) {
+                        run_boost(self.creep, lab.id());
                         return;
-                    } else {
-                        self.move_to(val.pos())
                     }
                 }
-                None => {}
+                let r = self.creep.upgrade_controller(&controller);
+                if r == ReturnCode::NotInRange {
+                    self.move_to(controller.pos());
+                } else if r != ReturnCode::Ok {
+                    warn!("couldn't upgrade controller: {:?}", r);
+                }
+                return;
             }
-            let room = self.creep.room().unwrap();
-            let object = room
-                .find(find::STRUCTURES)
-                .into_iter()
-                .filter(|o| o.as_attackable().is_some())
-                .filter(|o| o.structure_type() != StructureType::Controller)
-                .filter(|o| {
-                    o.as_attackable().unwrap().hits() < o.as_attackable().unwrap().hits_max() / 3
-                })
-                .reduce(|fewer_hp_obj, next_obj| {
-                    // here we are sure we only have only attackables
-                    if let Some(next_attackable) = next_obj.as_attackable() {
-                        if let Some(fewer_hp_atttackble) = fewer_hp_obj.as_attackable() {
-                            if next_attackable.hits() < fewer_hp_atttackble.hits() {
-                                next_obj
-                            } else {
-                                fewer_hp_obj
-                            }
-                        } else {
-                            fewer_hp_obj
-                        }
-                    } else {
-                        warn!("could not get one of the attackables");
-                        fewer_hp_obj
+        }
+        // finish the current job before looking for another one
+        if step == BuilderStep::KeepWorking {
+            if let Some(target) = creep_targets.get(&name).cloned() {
+                if self.work_on(&target) {
+                    return;
+                }
+                creep_targets.remove(&name);
+            }
+        }
+        match self.find_work(build_rank(slot, count, BUILD_ENERGY_PERCENT, max_level)) {
+            Some(target) => {
+                if assign_target(creep_targets, self.creep, target.clone()) {
+                    self.work_on(&target);
+                }
+            }
+            None => {
+                info!("could not find anything to build or repair");
+            }
+        }
+    }
+
+    /// The closest construction site of the cluster the builder's rank among those building falls
+    /// on, so that far apart projects all move forward, or else the closest structure worn down
+    /// enough to need a repair.
+    fn find_work(&self, rank: usize) -> Option<CreepTarget> {
+        let creep_pos = self.creep.pos();
+        let sites: Vec<Position> = self
+            .creep
+            .room()
+            .unwrap()
+            .find(find::CONSTRUCTION_SITES)
+            .iter()
+            .map(|s| s.pos())
+            .collect();
+        let clusters = cluster_sites(&sites, BUILD_CLUSTER_RANGE);
+        if let Some(site) = cluster_site(&clusters, rank, creep_pos) {
+            return Some(CreepTarget::Build(site));
+        }
+        let damaged: Vec<StructureObject> = self
+            .creep
+            .room()
+            .unwrap()
+            .find(find::STRUCTURES)
+            .into_iter()
+            .filter(|o| o.as_attackable().is_some())
+            .filter(|o| o.structure_type() != StructureType::Controller)
+            .filter(|o| should_maintain(o))
+            .filter(|o| {
+                let attackable = o.as_attackable().unwrap();
+                attackable.hits() < attackable.hits_max() / 3
+            })
+            .collect();
+        let candidates: Vec<(ObjectId<Structure>, u32)> = damaged
+            .iter()
+            .map(|o| (o.as_structure().id(), o.pos().get_range_to(creep_pos)))
+            .collect();
+        pick_within_budget(&candidates).map(|i| {
+            let o = &damaged[i];
+            CreepTarget::Repair(o.pos(), o.structure_type())
+        })
+    }
+
+    /// Builds or repairs the target, moving to it first. Returns whether the job is still on.
+    fn work_on(&self, target: &CreepTarget) -> bool {
+        match target {
+            // the site's room may be out of sight, it can only be looked at once there
+            CreepTarget::Build(pos) | CreepTarget::Repair(pos, _)
+                if pos.room_name() != self.creep.room().unwrap().name() =>
+            {
+                self.move_to(*pos);
+                true
+            }
+            CreepTarget::Build(pos) => {
+                let room = self.creep.room().unwrap();
+                let site = room
+                    .look_for_at(look::CONSTRUCTION_SITES, pos)
+                    .into_iter()
+                    .next();
+                // gone once it's finished
+                let site = match site {
+                    Some(s) => s,
+                    None => return false,
+                };
+                let r = self.creep.build(&site);
+                match r {
+                    ReturnCode::Ok => true,
+                    ReturnCode::NotInRange => {
+                        self.move_to(site.pos());
+                        true
                     }
-                })
-                .take();
-            match object {
-                Some(obj) => {
-                    let target = obj.as_structure();
-                    let r = self.creep.repair(target);
-                    if r == ReturnCode::NotInRange {
-                        self.move_to(target)
-                    } else if r != ReturnCode::Ok {
-                        warn!("couldn't repair: {:?}", r);
+                    _ => {
+                        warn!("couldn't build: {:?}", r);
+                        false
                     }
                 }
-                None => {
-                    info!("could not find anything to repair");
+            }
+            CreepTarget::Repair(pos, structure_type) => {
+                let room = self.creep.room().unwrap();
+                // gone, or replaced by something else on the same tile
+                let obj = match structure_at(&room, *pos, &[*structure_type]) {
+                    Some(s) => s,
+                    None => return false,
+                };
+                let structure = obj.as_structure();
+                let id = structure.id();
+                if repair_done(structure.hits(), structure.hits_max()) || !has_repair_budget(id) {
+                    return false;
+                }
+                let r = self.creep.repair(structure);
+                match r {
+                    ReturnCode::Ok => {
+                        // a repair costs one energy per work part
+                        let work_parts = self
+                            .creep
+                            .body()
+                            .iter()
+                            .filter(|p| p.part() == Part::Work && p.hits() > 0)
+                            .count();
+                        record_repair(id, work_parts as u32);
+                        true
+                    }
+                    ReturnCode::NotInRange => {
+                        self.move_to(structure.pos());
+                        true
+                    }
+                    _ => {
+                        warn!("couldn't repair: {:?}", r);
+                        false
+                    }
                 }
             }
-        } else {
-            self.creep.say("E_OUT", false);
+            _ => false,
+        }
+    }
+
+    /// Position of this builder among the room's builders, ordered by name, so it keeps the same
+    /// slot from one tick to the next, and how many builders there are.
+    fn builder_slot(&self) -> (usize, usize) {
+        let name = self.creep.name();
+        let room = self.creep.room().unwrap();
+        let mut builders: Vec<String> = CREEPS_ROLE.with(|creeps_role_refcell| {
+            let creeps_role = creeps_role_refcell.borrow();
+            room.find(find::MY_CREEPS)
+                .iter()
+                .map(|c| c.name())
+                .filter(|n| creeps_role.get(n) == Some(&Role::Builder))
+                .collect()
+        });
+        builders.sort();
+        let slot = builders.iter().position(|n| n == &name).unwrap_or(0);
+        (slot, builders.len())
+    }
+}
+
+/// What a builder does next with what it carries.
+#[derive(Debug, PartialEq)]
+pub enum BuilderStep {
+    // the store is empty, go get energy
+    Refill,
+    // carry on with the current job until it's done or the store runs dry
+    KeepWorking,
+    // pick the closest job
+    FindWork,
+}
+
+/// A builder only goes back for energy once its store is empty, spending it on the job at hand
+/// first.
+pub fn builder_step(energy: u32, has_job: bool) -> BuilderStep {
+    if energy == 0 {
+        BuilderStep::Refill
+    } else if has_job {
+        BuilderStep::KeepWorking
+    } else {
+        BuilderStep::FindWork
+    }
+}
+
+/// Decides whether the builder in `slot` goes to construction sites (true) or to the controller
+/// (false). The first `n` slots always hold `n * build_percent / 100` builders, rounded up.
+pub fn is_build_slot(slot: usize, build_percent: usize) -> bool {
+    let build_percent = build_percent.min(100);
+    let builds_up_to = |n: usize| (n * build_percent + 99) / 100;
+    builds_up_to(slot + 1) > builds_up_to(slot)
+}
+
+/// Whether the builder in `slot`, out of `count`, upgrades the controller. At RCL 8 upgrading is
+/// capped at 15 energy a tick, so only the last one does, enough to keep the controller from
+/// downgrading, and the others build. A lone builder always builds, the room spawns another one
+/// for the controller.
+pub fn is_upgrade_slot(slot: usize, count: usize, build_percent: usize, max_level: bool) -> bool {
+    if max_level {
+        count > 1 && slot + 1 == count
+    } else {
+        !is_build_slot(slot, build_percent)
+    }
+}
+
+/// The builder's rank among the room's builders that build rather than upgrade, see
+/// `is_upgrade_slot`.
+pub fn build_rank(slot: usize, count: usize, build_percent: usize, max_level: bool) -> usize {
+    (0..slot)
+        .filter(|s| !is_upgrade_slot(*s, count, build_percent, max_level))
+        .count()
+}
+
+/// The site closest to `from` in the cluster the builder's `rank` falls on.
+pub fn cluster_site(clusters: &[Vec<Position>], rank: usize, from: Position) -> Option<Position> {
+    if clusters.is_empty() {
+        return None;
+    }
+    clusters[rank % clusters.len()]
+        .iter()
+        .min_by_key(|p| p.get_range_to(from))
+        .copied()
+}
+
+/// Groups sites chained together by ranges within `range`, in a stable order (by each cluster's
+/// top-left site) so builders keep the same cluster from one tick to the next.
+pub fn cluster_sites(sites: &[Position], range: u32) -> Vec<Vec<Position>> {
+    let mut left: Vec<Position> = sites.to_vec();
+    left.sort_by_key(|p| (p.y().u8(), p.x().u8()));
+    let mut clusters = Vec::new();
+    while !left.is_empty() {
+        let mut cluster = vec![left.remove(0)];
+        let mut i = 0;
+        while i < cluster.len() {
+            let from = cluster[i];
+            let (near, far): (Vec<Position>, Vec<Position>) = left
+                .into_iter()
+                .partition(|p| p.get_range_to(from) <= range);
+            cluster.extend(near);
+            left = far;
+            i += 1;
         }
+        clusters.push(cluster);
+    }
+    clusters
+}
+
+/// A repair started below a third of the hits goes on until this percentage.
+pub fn repair_done(hits: u32, hits_max: u32) -> bool {
+    hits as u64 * 100 >= hits_max as u64 * BUILDER_REPAIR_UNTIL_PERCENT as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screeps::{RoomCoordinate, RoomName};
+
+    #[test]
+    fn build_slots_follow_the_configured_share() {
+        let builders = |percent| (0..10).filter(|&s| is_build_slot(s, percent)).count();
+        assert_eq!(builders(70), 7);
+        assert_eq!(builders(0), 0);
+        assert_eq!(builders(100), 10);
+        assert_eq!(builders(250), 10);
+    }
+
+    #[test]
+    fn build_slots_are_spread_out() {
+        // With an even split every other builder builds, so the second one already upgrades.
+        assert!(is_build_slot(0, 50));
+        assert!(!is_build_slot(1, 50));
+        assert!(is_build_slot(2, 50));
+    }
+
+    #[test]
+    fn repairs_go_on_until_the_configured_hits() {
+        let until = BUILDER_REPAIR_UNTIL_PERCENT * 10;
+        assert!(!repair_done(until - 1, 1000));
+        assert!(repair_done(until, 1000));
+        assert!(repair_done(1000, 1000));
+    }
+
+    #[test]
+    fn repair_threshold_does_not_overflow_on_walls() {
+        assert!(!repair_done(1_000_000, 300_000_000));
+        assert!(repair_done(300_000_000, 300_000_000));
+    }
+
+    #[test]
+    fn at_rcl8_only_the_last_builder_upgrades() {
+        let upgraders: Vec<usize> = (0..4)
+            .filter(|&s| is_upgrade_slot(s, 4, BUILD_ENERGY_PERCENT, true))
+            .collect();
+        assert_eq!(upgraders, vec![3]);
+    }
+
+    #[test]
+    fn below_rcl8_the_configured_split_applies() {
+        for slot in 0..10 {
+            assert_eq!(
+                is_upgrade_slot(slot, 10, 70, false),
+                !is_build_slot(slot, 70)
+            );
+        }
+    }
+
+    fn pos(x: u8, y: u8) -> Position {
+        Position::new(
+            RoomCoordinate::new(x).unwrap(),
+            RoomCoordinate::new(y).unwrap(),
+            RoomName::new("W1N1").unwrap(),
+        )
+    }
+
+    #[test]
+    fn chained_sites_form_one_cluster() {
+        let clusters = cluster_sites(&[pos(14, 10), pos(10, 10), pos(12, 10)], 2);
+        assert_eq!(clusters, vec![vec![pos(10, 10), pos(12, 10), pos(14, 10)]]);
+    }
+
+    #[test]
+    fn distant_sites_split_in_top_left_order() {
+        let clusters = cluster_sites(&[pos(40, 40), pos(30, 5), pos(10, 10)], 3);
+        assert_eq!(
+            clusters,
+            vec![vec![pos(30, 5)], vec![pos(10, 10)], vec![pos(40, 40)]]
+        );
+    }
+
+    #[test]
+    fn full_builder_finishes_its_site_before_refilling() {
+        assert_eq!(builder_step(100, true), BuilderStep::KeepWorking);
+        assert_eq!(builder_step(1, true), BuilderStep::KeepWorking);
+        assert_eq!(builder_step(0, true), BuilderStep::Refill);
+    }
+
+    #[test]
+    fn builder_with_energy_and_no_job_looks_for_one() {
+        assert_eq!(builder_step(100, false), BuilderStep::FindWork);
+    }
+
+    #[test]
+    fn at_rcl8_a_lone_builder_builds() {
+        assert!(!is_upgrade_slot(0, 1, BUILD_ENERGY_PERCENT, true));
+    }
+
+    #[test]
+    fn two_building_builders_get_different_clusters() {
+        let clusters = cluster_sites(&[pos(10, 10), pos(11, 10), pos(40, 40)], 3);
+        assert_eq!(clusters.len(), 2);
+        // half of the four builders upgrade, slots 0 and 2 build
+        let building: Vec<usize> = (0..4)
+            .filter(|&s| !is_upgrade_slot(s, 4, 50, false))
+            .collect();
+        assert_eq!(building, vec![0, 2]);
+        let sites: Vec<Position> = building
+            .iter()
+            .map(|&s| {
+                let rank = build_rank(s, 4, 50, false);
+                cluster_site(&clusters, rank, pos(13, 10)).unwrap()
+            })
+            .collect();
+        assert_eq!(sites, vec![pos(11, 10), pos(40, 40)]);
+    }
+
+    #[test]
+    fn rcl8_upgrader_does_not_take_a_cluster_rank() {
+        assert_eq!(build_rank(0, 2, 50, true), 0);
+        assert_eq!(build_rank(1, 3, 50, true), 1);
+        assert_eq!(cluster_site(&[], 0, pos(25, 25)), None);
     }
 }
@@ -1,11 +1,30 @@
+use crate::config::{
+    HARVESTER_HELP_WHEN_DEPLETED, HARVESTER_UPGRADE_WHEN_SATURATED, HARVEST_MODE,
+    PREFER_RICHER_SOURCES, SOURCE_CONTAINER_RANGE, SOURCE_CONTAINER_TOWARD_SPAWN,
+    SOURCE_DISTANCE_TOLERANCE,
+};
 use crate::creep::*;
+use crate::planner::walkable_tiles_around;
+use crate::storage::{CreepTarget, CREEPS_SOURCE};
 use log::*;
 use screeps::{
-    find, look, prelude::*, Look, Position, ResourceType, ReturnCode, Room, RoomPosition, Source,
-    StructureContainer, StructureObject, StructureType,
+    find, game, look, prelude::*, Look, ObjectId, Part, Position, ResourceType, ReturnCode, Room,
+    RoomPosition, Source, StructureContainer, StructureObject, StructureType,
 };
 
+use super::hauler::Hauler;
 use super::role::{CanHarvest, Deposit, Movable};
+use super::source_keeper::is_source_safe;
+use std::collections::HashMap;
+
+/// How harvesters get the energy out of a source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HarvestMode {
+    // sitting on a container next to the source, built when missing
+    Static,
+    // anywhere next to the source, dropping the energy for haulers to pick up
+    DropMine,
+}
 
 pub struct Harvester<'a> {
     pub creep: &'a screeps::Creep,
@@ -33,7 +52,7 @@ impl<'a> Movable for Harvester<'a> {
     where
         T: HasPosition,
     {
-        let r = self.creep.move_to(target);
+        let r = move_towards(self.creep, target.pos());
         match r {
             ReturnCode::Ok => {}
             ReturnCode::Tired => {
@@ -47,36 +66,119 @@ impl<'a> Movable for Harvester<'a> {
 }
 
 impl<'a> Harvester<'a> {
-    pub fn pick_closest_spot(&self) -> Option<(Source, Position)> {
+    /// Picks a container tile that no other creep stands on or has reserved earlier this tick,
+    /// preferring the one this harvester already holds. Without a container, drop miners take the
+    /// free tile coming first in `drop_tiles`: the first one always mans the source's designated
+    /// drop tile, and the others stand next to it, so their piles stay a step apart.
+    pub fn pick_closest_spot(
+        &self,
+        creep_targets: &HashMap<String, CreepTarget>,
+    ) -> Option<(Source, Position)> {
         let room = self.creep.room().unwrap();
         let sources = room.find(find::SOURCES);
+        let name = self.creep.name();
         let mut source_container = Vec::<(Source, Position)>::new();
+        let assigned = assigned_source(&name);
         for s in sources.iter() {
-            let deposit = self.find_closest_container_from_source(s.pos());
-            if let Some(d) = deposit {
-                let creeps = room.look_for_at(look::CREEPS, &d.pos());
+            if assigned.map(|a| a != s.id()).unwrap_or(false) || !is_source_safe(&room, s.pos()) {
+                continue;
+            }
+            let spots = match self.find_closest_container_from_source(s.pos()) {
+                Some(d) => vec![d.pos()],
+                // no container, or it was destroyed: drop mine next to the source meanwhile
+                None => {
+                    if HARVEST_MODE == HarvestMode::Static {
+                        build_container_around_source(&room, s.pos());
+                    }
+                    drop_tiles(&room, s.pos())
+                }
+            };
+            let mut first_free = None;
+            for spot in spots {
+                let creeps = room.look_for_at(look::CREEPS, &spot);
                 let objs = creeps
                     .iter()
                     .filter(|creep| creep.pos() != self.creep.pos())
                     .collect::<Vec<&screeps::Creep>>();
-                if objs.len() == 0 {
-                    source_container.push((s.clone(), d.pos()));
+                if objs.len() == 0 && !is_spot_reserved(creep_targets, &name, spot) {
+                    if let Some(CreepTarget::HarvestSpot(own)) = creep_targets.get(&name) {
+                        if *own == spot {
+                            return Some((s.clone(), spot));
+                        }
+                    }
+                    first_free = Some(spot);
+                    break;
                 }
-            } else {
-                build_container_around_source(room.clone(), s.pos());
-                warn!("did not find container near this source {:?}", s.pos());
+            }
+            if let Some(spot) = first_free {
+                source_container.push((s.clone(), spot));
             }
         }
-        if source_container.len() > 0 {
-            let val = source_container.get(0).unwrap();
-            Some((val.0.clone(), val.1))
-        } else {
-            None
-        }
+        let creep_pos = self.creep.pos();
+        let candidates: Vec<(u32, u32)> = source_container
+            .iter()
+            .map(|(s, pos)| (s.energy(), pos.get_range_to(creep_pos)))
+            .collect();
+        pick_source(&candidates, PREFER_RICHER_SOURCES).map(|i| source_container.swap_remove(i))
     }
 
-    pub fn run(self) {
-        if let Some((source, c_pos)) = self.pick_closest_spot() {
+    pub fn run(self, creep_targets: &mut HashMap<String, CreepTarget>) {
+        let name = self.creep.name();
+        if HARVESTER_UPGRADE_WHEN_SATURATED
+            && self.can_carry()
+            && sources_saturated(&self.source_containers())
+        {
+            creep_targets.remove(&name);
+            self.upgrade_with_harvested_energy();
+            return;
+        }
+        // nothing to mine until a source regenerates, lend a hand meanwhile
+        let all_depleted = HARVESTER_HELP_WHEN_DEPLETED
+            && self
+                .creep
+                .room()
+                .unwrap()
+                .find(find::SOURCES)
+                .iter()
+                .all(|s| s.energy() == 0);
+        let carried = self
+            .creep
+            .store()
+            .get_used_capacity(Some(ResourceType::Energy));
+        match depleted_task(all_depleted, self.can_carry(), carried) {
+            Some(DepletedTask::Upgrade) => {
+                creep_targets.remove(&name);
+                self.upgrade();
+                return;
+            }
+            Some(DepletedTask::Haul) => {
+                if let Some(CreepTarget::HarvestSpot(_)) = creep_targets.get(&name) {
+                    creep_targets.remove(&name);
+                }
+                let hauler = Hauler { creep: self.creep };
+                if creep_targets.get(&name).is_none() {
+                    hauler.run(false, creep_targets);
+                }
+                hauler.run_targets(creep_targets);
+                return;
+            }
+            None => {}
+        }
+        // the container it held is gone: let go of the tile, picking a spot again drop mines
+        // next to the source and places the site for a new container
+        let lost = lost_spot(creep_targets.get(&name), HARVEST_MODE, |p| {
+            self.has_container_at(p)
+        });
+        if let Some(spot) = lost {
+            debug!("{} lost its container at {:?}", name, spot);
+            creep_targets.remove(&name);
+        }
+        if let Some((source, c_pos)) = self.pick_closest_spot(creep_targets) {
+            // reserve the tile right away so harvesters running later this tick skip it
+            if !assign_target(creep_targets, self.creep, CreepTarget::HarvestSpot(c_pos)) {
+                self.creep.say("NO_WORK", false);
+                return;
+            }
             if self.creep.pos().is_equal_to(c_pos) {
                 //ignoring return code for harvest because it already logs
                 //inside
@@ -85,18 +187,97 @@ impl<'a> Harvester<'a> {
                 self.move_to(c_pos);
             }
         } else {
+            creep_targets.remove(&name);
             info!("could not find an active source");
         }
     }
+    /// For each source of the room, whether another creep stands on its container and the
+    /// container's free capacity, `None` for a source without one.
+    fn source_containers(&self) -> Vec<Option<(bool, i32)>> {
+        let room = self.creep.room().unwrap();
+        room.find(find::SOURCES)
+            .iter()
+            .map(|s| {
+                let c = self.find_closest_container_from_source(s.pos())?;
+                let taken = room
+                    .look_for_at(look::CREEPS, &c.pos())
+                    .iter()
+                    .any(|creep| creep.pos() != self.creep.pos());
+                Some((
+                    taken,
+                    c.store().get_free_capacity(Some(ResourceType::Energy)),
+                ))
+            })
+            .collect()
+    }
+
+    fn has_container_at(&self, pos: Position) -> bool {
+        self.creep
+            .room()
+            .unwrap()
+            .look_for_at(look::STRUCTURES, &pos)
+            .iter()
+            .any(|s| s.structure_type() == StructureType::Container)
+    }
+
+    fn can_carry(&self) -> bool {
+        self.creep.body().iter().any(|p| p.part() == Part::Carry)
+    }
+
+    /// Works as a temporary upgrader: fills up at the closest active source and then spends it
+    /// all on the controller.
+    fn upgrade_with_harvested_energy(&self) {
+        let room = self.creep.room().unwrap();
+        let controller = match room.controller() {
+            Some(c) => c,
+            None => return,
+        };
+        let store = self.creep.store();
+        let used = store.get_used_capacity(Some(ResourceType::Energy));
+        let at_controller = self.creep.pos().get_range_to(controller.pos()) <= 3;
+        if upgrade_now(
+            store.get_free_capacity(Some(ResourceType::Energy)),
+            used,
+            at_controller,
+        ) {
+            self.upgrade();
+            return;
+        }
+        if let Some(source) = find_closest(self.creep.pos(), find::SOURCES_ACTIVE) {
+            if self.creep.pos().is_near_to(source.pos()) {
+                let _ = self.harvest(&source);
+            } else {
+                self.move_to(source.pos());
+            }
+        }
+    }
+
+    /// Spends what it carries on the room's controller, walking there first.
+    fn upgrade(&self) {
+        let controller = match self.creep.room().unwrap().controller() {
+            Some(c) => c,
+            None => return,
+        };
+        let r = self.creep.upgrade_controller(&controller);
+        if r == ReturnCode::NotInRange {
+            self.move_to(controller.pos());
+        } else if r != ReturnCode::Ok {
+            warn!("couldn't upgrade controller: {:?}", r);
+        }
+    }
+
+    /// Finds the closest container belonging to the source, see `is_source_container`.
     fn find_closest_container_from_source(
         &self,
         source_pos: Position,
     ) -> Option<StructureContainer> {
         let room = self.creep.room().unwrap();
-        let structures = room.find(find::MY_STRUCTURES);
+        // containers have no owner, they never show up in MY_STRUCTURES
+        let structures = room.find(find::STRUCTURES);
         let container_obj = structures
             .iter()
             .filter(|o| o.structure_type() == StructureType::Container)
+            .filter(|o| is_source_container(o.pos(), source_pos))
             .reduce(|closer, next| {
                 if closer.pos().get_range_to(source_pos) > next.pos().get_range_to(source_pos) {
                     next
@@ -113,13 +294,429 @@ impl<'a> Harvester<'a> {
     }
 }
 
-fn build_container_around_source(room: Room, source_pos: Position) {
-    let area = room.look_at_area(
-        source_pos.y().u8() - 1,
-        source_pos.x().u8() - 1,
-        source_pos.y().u8() + 1,
-        source_pos.x().u8() + 1,
-        true,
-    );
-    // let objs = js_sys::Array::from(&area).map(|x: Vec<StructureObject>| x.map().collect());
+/// True when every source has a container and each one is either taken by another creep or
+/// already full, so there is nothing useful left to harvest into. See `source_containers`.
+pub fn sources_saturated(containers: &[Option<(bool, i32)>]) -> bool {
+    containers
+        .iter()
+        .all(|c| matches!(c, Some((taken, free)) if container_saturated(*taken, *free)))
+}
+
+/// A source container leaves nothing to harvest into once another creep stands on it or it's full.
+pub fn container_saturated(taken: bool, free_capacity: i32) -> bool {
+    taken || free_capacity <= 0
+}
+
+/// A harvester sent to upgrade fills up first, then spends it all before going back to refill.
+pub fn upgrade_now(free_capacity: i32, used: u32, at_controller: bool) -> bool {
+    free_capacity <= 0 || (used > 0 && at_controller)
+}
+
+/// What a harvester does while every source of its room is depleted.
+#[derive(Debug, PartialEq)]
+pub enum DepletedTask {
+    // fetch energy like a hauler would
+    Haul,
+    // spend what it carries on the controller
+    Upgrade,
+}
+
+/// Only harvesters able to carry can help, the others wait for the regeneration.
+pub fn depleted_task(all_depleted: bool, can_carry: bool, carried: u32) -> Option<DepletedTask> {
+    if !all_depleted || !can_carry {
+        None
+    } else if carried > 0 {
+        Some(DepletedTask::Upgrade)
+    } else {
+        Some(DepletedTask::Haul)
+    }
+}
+
+/// Source given to a harvester at spawn time, if any.
+fn assigned_source(name: &str) -> Option<ObjectId<Source>> {
+    CREEPS_SOURCE.with(|creeps_source_refcell| creeps_source_refcell.borrow().get(name).copied())
+}
+
+/// The room's source with the fewest work parts of living harvesters assigned to it.
+pub fn assign_source(room: &Room) -> Option<ObjectId<Source>> {
+    let assigned: Vec<(ObjectId<Source>, u32)> = CREEPS_SOURCE.with(|creeps_source_refcell| {
+        creeps_source_refcell
+            .borrow()
+            .iter()
+            .filter_map(|(name, source)| {
+                let creep = game::creeps().get(name.clone())?;
+                let work = creep
+                    .body()
+                    .iter()
+                    .filter(|p| p.part() == Part::Work && p.hits() > 0)
+                    .count() as u32;
+                Some((*source, work))
+            })
+            .collect()
+    });
+    let sources: Vec<ObjectId<Source>> = room.find(find::SOURCES).iter().map(|s| s.id()).collect();
+    pick_least_worked_source(&sources, &assigned)
+}
+
+/// Among `sources`, the one with the fewest work parts in `assigned`, the first one on a tie.
+pub fn pick_least_worked_source<T: Copy + PartialEq>(
+    sources: &[T],
+    assigned: &[(T, u32)],
+) -> Option<T> {
+    sources
+        .iter()
+        .min_by_key(|s| {
+            assigned
+                .iter()
+                .filter(|(a, _)| a == *s)
+                .map(|(_, work)| work)
+                .sum::<u32>()
+        })
+        .copied()
+}
+
+/// Index of the source to harvest among `(energy, distance)` candidates. When `prefer_richer`,
+/// the richest one about as close as the closest wins, otherwise simply the closest.
+pub fn pick_source(candidates: &[(u32, u32)], prefer_richer: bool) -> Option<usize> {
+    let closest = candidates.iter().map(|(_, d)| *d).min()?;
+    let max_distance = if prefer_richer {
+        closest + SOURCE_DISTANCE_TOLERANCE
+    } else {
+        closest
+    };
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, d))| *d <= max_distance)
+        .max_by(|(_, (e1, d1)), (_, (e2, d2))| e1.cmp(e2).then(d2.cmp(d1)))
+        .map(|(i, _)| i)
+}
+
+pub fn is_source_container(container_pos: Position, source_pos: Position) -> bool {
+    container_pos.get_range_to(source_pos) <= SOURCE_CONTAINER_RANGE
+}
+
+/// Tiles next to the source drop miners stand on, see `order_drop_tiles`.
+fn drop_tiles(room: &Room, source_pos: Position) -> Vec<Position> {
+    let spawn_pos = room.find(find::MY_SPAWNS).first().map(|s| s.pos());
+    order_drop_tiles(walkable_tiles_around(source_pos), spawn_pos)
+}
+
+/// The designated drop tile, closest to the spawn for short hauls, first. A tile fits a single
+/// miner, so the rest follow by range to it, keeping the piles of a source next to each other.
+pub fn order_drop_tiles(mut tiles: Vec<Position>, spawn_pos: Option<Position>) -> Vec<Position> {
+    if let Some(spawn_pos) = spawn_pos {
+        tiles.sort_by_key(|t| t.get_range_to(spawn_pos));
+    }
+    if let Some(designated) = tiles.first().copied() {
+        tiles[1..].sort_by_key(|t| t.get_range_to(designated));
+    }
+    tiles
+}
+
+/// Whether a living creep other than `name` holds the container tile at `pos`.
+fn is_spot_reserved(
+    creep_targets: &HashMap<String, CreepTarget>,
+    name: &str,
+    pos: Position,
+) -> bool {
+    spot_held_by_other(creep_targets, name, pos, |holder| {
+        game::creeps().get(holder.to_string()).is_some()
+    })
+}
+
+/// Whether a creep other than `name`, and still `alive`, claimed the container tile at `pos`.
+pub fn spot_held_by_other(
+    creep_targets: &HashMap<String, CreepTarget>,
+    name: &str,
+    pos: Position,
+    alive: impl Fn(&str) -> bool,
+) -> bool {
+    creep_targets.iter().any(|(holder, target)| match target {
+        CreepTarget::HarvestSpot(spot) => holder != name && *spot == pos && alive(holder),
+        _ => false,
+    })
+}
+
+/// Places a container site next to the source, unless harvesters drop mine or one is already
+/// on its way.
+fn build_container_around_source(room: &Room, source_pos: Position) {
+    let site_pending = room
+        .find(find::MY_CONSTRUCTION_SITES)
+        .iter()
+        .filter(|s| s.structure_type() == StructureType::Container)
+        .any(|s| is_source_container(s.pos(), source_pos));
+    if !should_build_source_container(HARVEST_MODE, site_pending) {
+        return;
+    }
+    let anchor = if SOURCE_CONTAINER_TOWARD_SPAWN {
+        room.find(find::MY_SPAWNS)
+            .first()
+            .map(|s| s.pos())
+            .or_else(|| room.storage().map(|s| s.pos()))
+    } else {
+        None
+    };
+    let tile = match pick_container_tile(&walkable_tiles_around(source_pos), anchor) {
+        Some(t) => t,
+        None => {
+            warn!("no free tile for a container next to {:?}", source_pos);
+            return;
+        }
+    };
+    let r =
+        room.create_construction_site(tile.x().u8(), tile.y().u8(), StructureType::Container, None);
+    if r == ReturnCode::Ok {
+        info!("placed source container site at {:?}", tile);
+    } else {
+        warn!("couldn't place source container site: {:?}", r);
+    }
+}
+
+/// The free tile closest to `anchor`, the first one on ties or without an anchor.
+pub fn pick_container_tile(tiles: &[Position], anchor: Option<Position>) -> Option<Position> {
+    match anchor {
+        Some(a) => tiles.iter().copied().min_by_key(|t| t.get_range_to(a)),
+        None => tiles.first().copied(),
+    }
+}
+
+/// The tile a static harvester holds even though its container is gone.
+pub fn lost_spot(
+    target: Option<&CreepTarget>,
+    mode: HarvestMode,
+    has_container_at: impl Fn(Position) -> bool,
+) -> Option<Position> {
+    match target {
+        Some(CreepTarget::HarvestSpot(spot))
+            if mode == HarvestMode::Static && !has_container_at(*spot) =>
+        {
+            Some(*spot)
+        }
+        _ => None,
+    }
+}
+
+/// Only static miners need a container, and one site per source is enough.
+pub fn should_build_source_container(mode: HarvestMode, site_pending: bool) -> bool {
+    mode == HarvestMode::Static && !site_pending
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::roles::role::{BodyPlan, Role};
+    use screeps::{RoomCoordinate, RoomName};
+
+    #[test]
+    fn taken_or_full_containers_are_saturated() {
+        assert!(container_saturated(true, 2000));
+        assert!(container_saturated(false, 0));
+        assert!(!container_saturated(false, 50));
+    }
+
+    #[test]
+    fn upgrading_harvester_fills_up_before_upgrading() {
+        // full: head for the controller
+        assert!(upgrade_now(0, 100, false));
+        // partly filled away from the controller: keep harvesting
+        assert!(!upgrade_now(50, 50, false));
+        // at the controller with energy left: spend it all
+        assert!(upgrade_now(50, 50, true));
+        // empty at the controller: go back to the source
+        assert!(!upgrade_now(100, 0, true));
+    }
+
+    fn pos(x: u8, y: u8) -> Position {
+        Position::new(
+            RoomCoordinate::new(x).unwrap(),
+            RoomCoordinate::new(y).unwrap(),
+            RoomName::new("W1N1").unwrap(),
+        )
+    }
+
+    #[test]
+    fn spot_claimed_this_tick_is_skipped_by_other_harvesters() {
+        let mut creep_targets = HashMap::new();
+        creep_targets.insert("first".to_string(), CreepTarget::HarvestSpot(pos(10, 10)));
+        assert!(spot_held_by_other(
+            &creep_targets,
+            "second",
+            pos(10, 10),
+            |_| true
+        ));
+        assert!(!spot_held_by_other(
+            &creep_targets,
+            "second",
+            pos(11, 10),
+            |_| true
+        ));
+    }
+
+    #[test]
+    fn own_or_dead_claims_do_not_block_a_spot() {
+        let mut creep_targets = HashMap::new();
+        creep_targets.insert("first".to_string(), CreepTarget::HarvestSpot(pos(10, 10)));
+        assert!(!spot_held_by_other(
+            &creep_targets,
+            "first",
+            pos(10, 10),
+            |_| true
+        ));
+        assert!(!spot_held_by_other(
+            &creep_targets,
+            "second",
+            pos(10, 10),
+            |_| false
+        ));
+    }
+
+    #[test]
+    fn containers_next_to_a_source_belong_to_it() {
+        assert!(is_source_container(pos(11, 10), pos(10, 10)));
+        assert!(is_source_container(pos(12, 12), pos(10, 10)));
+    }
+
+    #[test]
+    fn controller_container_is_not_a_source_container() {
+        assert!(!is_source_container(pos(20, 10), pos(10, 10)));
+        assert!(!is_source_container(pos(13, 10), pos(10, 10)));
+    }
+
+    #[test]
+    fn richest_source_within_reach_is_preferred() {
+        let candidates = [(1000, 5), (3000, 5 + SOURCE_DISTANCE_TOLERANCE)];
+        assert_eq!(pick_source(&candidates, true), Some(1));
+        assert_eq!(pick_source(&candidates, false), Some(0));
+    }
+
+    #[test]
+    fn far_rich_sources_lose_to_the_close_one() {
+        let candidates = [(1000, 5), (3000, 6 + SOURCE_DISTANCE_TOLERANCE)];
+        assert_eq!(pick_source(&candidates, true), Some(0));
+        assert_eq!(pick_source(&[], true), None);
+    }
+
+    #[test]
+    fn equally_rich_sources_go_to_the_closest() {
+        let candidates = [(3000, 5 + SOURCE_DISTANCE_TOLERANCE), (3000, 5)];
+        assert_eq!(pick_source(&candidates, true), Some(1));
+    }
+
+    #[test]
+    fn only_static_miners_get_a_source_container() {
+        assert!(should_build_source_container(HarvestMode::Static, false));
+        assert!(!should_build_source_container(HarvestMode::DropMine, false));
+    }
+
+    #[test]
+    fn one_container_site_per_source_is_enough() {
+        assert!(!should_build_source_container(HarvestMode::Static, true));
+    }
+
+    #[test]
+    fn spot_is_let_go_once_its_container_is_destroyed() {
+        let held = CreepTarget::HarvestSpot(pos(10, 10));
+        assert_eq!(
+            lost_spot(Some(&held), HarvestMode::Static, |_| false),
+            Some(pos(10, 10))
+        );
+        assert_eq!(lost_spot(Some(&held), HarvestMode::Static, |_| true), None);
+    }
+
+    #[test]
+    fn drop_miners_keep_their_spot_without_a_container() {
+        let held = CreepTarget::HarvestSpot(pos(10, 10));
+        assert_eq!(
+            lost_spot(Some(&held), HarvestMode::DropMine, |_| false),
+            None
+        );
+        assert_eq!(lost_spot(None, HarvestMode::Static, |_| false), None);
+    }
+
+    #[test]
+    fn designated_drop_tile_is_the_closest_to_the_spawn() {
+        let tiles = vec![pos(9, 10), pos(11, 11), pos(11, 10)];
+        let ordered = order_drop_tiles(tiles, Some(pos(20, 10)));
+        assert_eq!(ordered, vec![pos(11, 11), pos(11, 10), pos(9, 10)]);
+    }
+
+    #[test]
+    fn extra_miners_drop_next_to_the_designated_tile() {
+        let tiles = vec![pos(11, 9), pos(9, 11), pos(10, 9)];
+        let ordered = order_drop_tiles(tiles, None);
+        assert_eq!(ordered, vec![pos(11, 9), pos(10, 9), pos(9, 11)]);
+    }
+
+    #[test]
+    fn harvester_upgrades_while_all_sources_are_depleted() {
+        // as spawned, not a body the bot never makes
+        let can_carry = harvester_body(550).contains(&Part::Carry);
+        assert_eq!(
+            depleted_task(true, can_carry, 50),
+            Some(DepletedTask::Upgrade)
+        );
+        assert_eq!(depleted_task(true, can_carry, 0), Some(DepletedTask::Haul));
+    }
+
+    #[test]
+    fn harvester_keeps_mining_unless_all_depleted_and_able_to_carry() {
+        assert_eq!(depleted_task(false, true, 50), None);
+        assert_eq!(depleted_task(true, false, 0), None);
+    }
+
+    #[test]
+    fn two_harvesters_get_different_sources() {
+        let sources = ["a", "b"];
+        let mut assigned = Vec::new();
+        let first = pick_least_worked_source(&sources, &assigned).unwrap();
+        assigned.push((first, 5));
+        let second = pick_least_worked_source(&sources, &assigned).unwrap();
+        assert_eq!((first, second), ("a", "b"));
+    }
+
+    #[test]
+    fn source_with_fewest_work_parts_is_assigned() {
+        let assigned = [("a", 2), ("a", 2), ("b", 5)];
+        assert_eq!(pick_least_worked_source(&["a", "b"], &assigned), Some("a"));
+        assert_eq!(pick_least_worked_source::<&str>(&[], &assigned), None);
+    }
+
+    #[test]
+    fn container_goes_on_the_tile_nearest_the_spawn() {
+        let tiles = [pos(10, 9), pos(11, 11), pos(9, 11)];
+        assert_eq!(
+            pick_container_tile(&tiles, Some(pos(14, 14))),
+            Some(pos(11, 11))
+        );
+    }
+
+    #[test]
+    fn container_goes_on_the_first_free_tile_without_a_spawn() {
+        let tiles = [pos(10, 9), pos(11, 11)];
+        assert_eq!(pick_container_tile(&tiles, None), Some(pos(10, 9)));
+        assert_eq!(pick_container_tile(&[], Some(pos(14, 16))), None);
+    }
+
+    fn harvester_body(energy: u32) -> Vec<Part> {
+        match Role::Harvester.get_body(energy, energy, 5) {
+            BodyPlan::Spawn(body) => body,
+            plan => panic!("harvester can't spawn with {}: {:?}", energy, plan),
+        }
+    }
+
+    #[test]
+    fn harvester_in_a_saturated_room_upgrades() {
+        // the bot's own harvesters have to be able to carry the energy to the controller
+        assert!(harvester_body(300).contains(&Part::Carry));
+        assert!(harvester_body(800).contains(&Part::Carry));
+        assert!(sources_saturated(&[Some((true, 2000)), Some((false, 0))]));
+        assert!(upgrade_now(0, 50, false));
+    }
+
+    #[test]
+    fn room_with_room_left_to_harvest_into_is_not_saturated() {
+        assert!(!sources_saturated(&[Some((true, 2000)), Some((false, 50))]));
+        assert!(!sources_saturated(&[Some((true, 2000)), None]));
+    }
 }
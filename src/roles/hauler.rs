@@ -1,11 +1,20 @@
+use super::mineral_harvester::mineral_container;
 use super::role::{CanDeposit, Deposit, DepositCode, Movable, Role};
-use crate::creep::find_tower;
+use crate::config::{
+    BALANCE_STORAGE_TERMINAL, CONTAINER_OVERFLOW_DROPPED, CONTAINER_OVERFLOW_PERCENT,
+    FILL_PLAN_ENABLED, FILL_PLAN_RANGE, SCAVENGE_MIN_ENERGY,
+};
+use crate::creep::{
+    assign_target, continue_to_unseen_room, find_closest, find_tower, move_towards,
+    spawn_goes_first, structure_at, transfer_amount,
+};
 use crate::storage::CreepTarget;
+use crate::terminal::{decide_balance, terminal_energy_target, BalanceMove};
 use log::*;
 use screeps::{
-    find, game, prelude::*, Creep, ObjectId, Resource, ResourceType, ReturnCode,
-    RoomObjectProperties, SharedCreepProperties, StructureExtension, StructureObject,
-    StructureType,
+    find, game, prelude::*, Creep, ObjectId, Position, Resource, ResourceType, ReturnCode, Room,
+    RoomObjectProperties, SharedCreepProperties, StructureContainer, StructureExtension,
+    StructureObject, StructureType,
 };
 use std::collections::HashMap;
 
@@ -28,7 +37,7 @@ impl<'a> Movable for Hauler<'a> {
     where
         T: HasPosition,
     {
-        let r = self.creep.move_to(target);
+        let r = move_towards(self.creep, target.pos());
         match r {
             ReturnCode::Ok => {}
             ReturnCode::Tired => {
@@ -69,13 +78,23 @@ impl<'a> Hauler<'a> {
             > 0
         {
             // Creep has store with energy
+            if FILL_PLAN_ENABLED {
+                let plan = self.plan_fill_chain();
+                if plan.len() > 1
+                    && assign_target(creep_targets, self.creep, CreepTarget::FillChain(plan))
+                {
+                    return;
+                }
+            }
 
             let deposit = self.find_closest_depositable(false);
             if let Some(val) = deposit {
                 if val.is_storage() {
                     if let Some(c) = self.find_creep() {
-                        creep_targets.insert(self.creep.name(), CreepTarget::TransferToCreep(c));
-                        return;
+                        if assign_target(creep_targets, self.creep, CreepTarget::TransferToCreep(c))
+                        {
+                            return;
+                        }
                     }
                 }
                 if self.creep.pos().is_near_to(val.pos()) {
@@ -90,8 +109,14 @@ impl<'a> Hauler<'a> {
         } else {
             // Creep has empty store
             //
+            // Tombstones and ruins decay, empty them before the containers
+            if self.scavenge() {
+                return;
+            }
             // Let's empty those containers
-            let deposit = self.find_closest_container();
+            let deposit = self
+                .find_closest_container()
+                .filter(|d| is_withdraw_source(d.structure_type()));
             if let Some(val) = deposit {
                 if self.creep.pos().is_near_to(val.pos()) {
                     let target = *val.withdrawable();
@@ -109,10 +134,7 @@ impl<'a> Hauler<'a> {
             }
 
             // Containers are kind of empty, let's PICKUP energy from the floor
-            let drop = self
-                .creep
-                .pos()
-                .find_closest_by_path(find::DROPPED_RESOURCES);
+            let drop = find_closest(self.creep.pos(), find::DROPPED_RESOURCES);
             if let Some(r) = drop {
                 if self.creep.pos().is_near_to(r.pos()) {
                     let r = self.creep.pickup(&r);
@@ -131,6 +153,28 @@ impl<'a> Hauler<'a> {
                 }
             }
 
+            // The mineral harvester's container, one hauler at a time
+            if let Some(container) = self.mineral_to_haul(creep_targets) {
+                assign_target(
+                    creep_targets,
+                    self.creep,
+                    CreepTarget::HaulMineral(container.id()),
+                );
+                return;
+            }
+
+            // Idle otherwise, keep the terminal stocked, one hauler per room at a time
+            if let Some((balance, _)) = self.storage_terminal_balance() {
+                if BALANCE_STORAGE_TERMINAL && !self.room_balancing(creep_targets) {
+                    assign_target(
+                        creep_targets,
+                        self.creep,
+                        CreepTarget::BalanceTerminal(balance),
+                    );
+                    return;
+                }
+            }
+
             // No drops either. Let's see if we have energy on the storage. If we have we can fill towers if they are empty.
 
             // store
@@ -169,7 +213,7 @@ impl<'a> Hauler<'a> {
 
     pub fn run_targets(&self, creep_targets: &mut HashMap<String, CreepTarget>) {
         let name = self.creep.name();
-        let target = creep_targets.get(&name);
+        let target = creep_targets.get(&name).cloned();
         let keep_target = match target {
             Some(creep_target) => match &creep_target {
                 CreepTarget::TransferToCreep(creep) => {
@@ -193,6 +237,14 @@ impl<'a> Hauler<'a> {
                         true
                     }
                 }
+                CreepTarget::FillChain(chain) => {
+                    let mut chain = chain.clone();
+                    let keep = self.run_fill_chain(&mut chain);
+                    keep && assign_target(creep_targets, self.creep, CreepTarget::FillChain(chain))
+                }
+                CreepTarget::BalanceTerminal(balance) => self.run_balance(*balance),
+                CreepTarget::HaulMineral(id) => self.run_haul_mineral(*id),
+                _ => false,
             },
             None => false,
         };
@@ -201,6 +253,268 @@ impl<'a> Hauler<'a> {
         }
     }
 
+    /// The mineral container, once it holds a load, unless another hauler is already on it.
+    fn mineral_to_haul(
+        &self,
+        creep_targets: &HashMap<String, CreepTarget>,
+    ) -> Option<StructureContainer> {
+        let room = self.creep.room().unwrap();
+        let mineral = room.find(find::MINERALS).into_iter().next()?;
+        let container = mineral_container(&room, &mineral)?;
+        let load = self.creep.store().get_capacity(None);
+        if container
+            .store()
+            .get_used_capacity(Some(mineral.mineral_type()))
+            < load
+        {
+            return None;
+        }
+        let name = self.creep.name();
+        let taken = creep_targets.iter().any(|(holder, target)| {
+            holder != &name
+                && matches!(target, CreepTarget::HaulMineral(id) if *id == container.id())
+        });
+        if taken {
+            None
+        } else {
+            Some(container)
+        }
+    }
+
+    /// Empties the mineral container into the storage, or the terminal without one, load after
+    /// load. Returns whether there's more to carry.
+    fn run_haul_mineral(&self, container_id: ObjectId<StructureContainer>) -> bool {
+        let room = self.creep.room().unwrap();
+        let carried = self.creep.store().store_types().into_iter().next();
+        if let Some(resource) = carried {
+            let (r, pos) = match (room.storage(), room.terminal()) {
+                (Some(s), _) => (self.creep.transfer(&s, resource, None), s.pos()),
+                (None, Some(t)) => (self.creep.transfer(&t, resource, None), t.pos()),
+                (None, None) => {
+                    warn!("nowhere to bring {:?} to", resource);
+                    return false;
+                }
+            };
+            return match r {
+                ReturnCode::Ok => true,
+                ReturnCode::NotInRange => {
+                    self.move_to(pos);
+                    true
+                }
+                _ => {
+                    warn!("couldn't store {:?}: {:?}", resource, r);
+                    false
+                }
+            };
+        }
+        let container = match container_id.resolve() {
+            Some(c) => c,
+            None => return continue_to_unseen_room(self.creep),
+        };
+        // energy in there is left to the other haulers
+        let mineral = container
+            .store()
+            .store_types()
+            .into_iter()
+            .find(|r| *r != ResourceType::Energy);
+        let mineral = match mineral {
+            Some(m) => m,
+            None => return false,
+        };
+        let r = self.creep.withdraw(&container, mineral, None);
+        match r {
+            ReturnCode::Ok => true,
+            ReturnCode::NotInRange => {
+                self.move_to(container.pos());
+                true
+            }
+            _ => {
+                warn!("couldn't withdraw {:?}: {:?}", mineral, r);
+                false
+            }
+        }
+    }
+
+    /// How energy should move between the room's storage and terminal, if it has both.
+    fn storage_terminal_balance(&self) -> Option<(BalanceMove, u32)> {
+        let room = self.creep.room().unwrap();
+        let (storage, terminal) = match (room.storage(), room.terminal()) {
+            (Some(s), Some(t)) => (s, t),
+            _ => return None,
+        };
+        decide_balance(
+            storage
+                .store()
+                .get_used_capacity(Some(ResourceType::Energy)),
+            terminal
+                .store()
+                .get_used_capacity(Some(ResourceType::Energy)),
+            terminal_energy_target(),
+        )
+    }
+
+    /// Whether another hauler of the room is already moving energy between storage and terminal.
+    fn room_balancing(&self, creep_targets: &HashMap<String, CreepTarget>) -> bool {
+        let room_name = self.creep.room().unwrap().name();
+        creep_targets.iter().any(|(name, target)| match target {
+            CreepTarget::BalanceTerminal(_) => game::creeps()
+                .get(name.clone())
+                .and_then(|c| c.room())
+                .map(|r| r.name() == room_name)
+                .unwrap_or(false),
+            _ => false,
+        })
+    }
+
+    /// Fetches energy from one end and brings it to the other. Returns whether there's more to
+    /// do, a single trip at most.
+    fn run_balance(&self, balance: BalanceMove) -> bool {
+        let room = self.creep.room().unwrap();
+        let (storage, terminal) = match (room.storage(), room.terminal()) {
+            (Some(s), Some(t)) => (s, t),
+            _ => return false,
+        };
+        let carried = self
+            .creep
+            .store()
+            .get_used_capacity(Some(ResourceType::Energy));
+        if carried > 0 {
+            let (r, pos) = match balance {
+                BalanceMove::ToTerminal => (
+                    self.creep.transfer(&terminal, ResourceType::Energy, None),
+                    terminal.pos(),
+                ),
+                BalanceMove::ToStorage => (
+                    self.creep.transfer(&storage, ResourceType::Energy, None),
+                    storage.pos(),
+                ),
+            };
+            return match r {
+                ReturnCode::NotInRange => {
+                    self.move_to(pos);
+                    true
+                }
+                ReturnCode::Ok => false,
+                _ => {
+                    warn!("couldn't move energy {:?}: {:?}", balance, r);
+                    false
+                }
+            };
+        }
+        // it may have balanced out meanwhile
+        let amount = match self.storage_terminal_balance() {
+            Some((b, amount)) if b == balance => amount,
+            _ => return false,
+        };
+        let free = self
+            .creep
+            .store()
+            .get_free_capacity(Some(ResourceType::Energy)) as u32;
+        let amount = amount.min(free);
+        let (r, pos) = match balance {
+            BalanceMove::ToTerminal => (
+                self.creep
+                    .withdraw(&storage, ResourceType::Energy, Some(amount)),
+                storage.pos(),
+            ),
+            BalanceMove::ToStorage => (
+                self.creep
+                    .withdraw(&terminal, ResourceType::Energy, Some(amount)),
+                terminal.pos(),
+            ),
+        };
+        match r {
+            ReturnCode::NotInRange => {
+                self.move_to(pos);
+                true
+            }
+            ReturnCode::Ok => true,
+            _ => {
+                warn!("couldn't fetch energy {:?}: {:?}", balance, r);
+                false
+            }
+        }
+    }
+
+    /// Plans which nearby spawns and extensions to fill, in order, with what the hauler carries
+    fn plan_fill_chain(&self) -> Vec<Position> {
+        let creep_pos = self.creep.pos();
+        let candidates = self
+            .creep
+            .room()
+            .unwrap()
+            .find(find::MY_STRUCTURES)
+            .iter()
+            .filter(|s| {
+                s.structure_type() == StructureType::Spawn
+                    || s.structure_type() == StructureType::Extension
+            })
+            .filter(|s| s.pos().get_range_to(creep_pos) <= FILL_PLAN_RANGE)
+            .filter_map(|s| {
+                let free = s
+                    .as_has_store()?
+                    .store()
+                    .get_free_capacity(Some(ResourceType::Energy));
+                if free > 0 {
+                    Some((s.pos(), free as u32))
+                } else {
+                    None
+                }
+            })
+            .collect();
+        let carried = self
+            .creep
+            .store()
+            .get_used_capacity(Some(ResourceType::Energy));
+        plan_fill(carried, creep_pos, candidates)
+    }
+
+    /// Works through the chain, one transfer per tick. Targets that were filled by someone else
+    /// in the meantime are skipped. Returns whether there's still work left in the chain.
+    fn run_fill_chain(&self, chain: &mut Vec<Position>) -> bool {
+        let room = self.creep.room().unwrap();
+        while let Some(pos) = chain.first().cloned() {
+            let target = structure_at(
+                &room,
+                pos,
+                &[StructureType::Spawn, StructureType::Extension],
+            )
+            .filter(|s| {
+                s.as_has_store()
+                    .map(|h| h.store().get_free_capacity(Some(ResourceType::Energy)) > 0)
+                    .unwrap_or(false)
+            });
+            let target = match target {
+                Some(t) => t,
+                None => {
+                    chain.remove(0);
+                    continue;
+                }
+            };
+            if !self.creep.pos().is_near_to(pos) {
+                self.move_to(pos);
+                return true;
+            }
+            let carried = self
+                .creep
+                .store()
+                .get_used_capacity(Some(ResourceType::Energy));
+            let amount = self.get_value_to_transfer(&target.as_has_store().unwrap().store());
+            let r = self.creep.transfer(
+                target.as_transferable().unwrap(),
+                ResourceType::Energy,
+                Some(amount),
+            );
+            chain.remove(0);
+            if r != ReturnCode::Ok {
+                warn!("could not deposit energy, {:?}", r);
+                return false;
+            }
+            return !chain.is_empty() && carried > amount;
+        }
+        false
+    }
+
     /// Will find the nearest unfilled extension
     /// Returns an option because it may not find an extension
     pub fn find_unfilled_extension(&self) -> Option<StructureExtension> {
@@ -236,7 +550,7 @@ impl<'a> Hauler<'a> {
         }
     }
     pub fn get_value_to_transfer(&self, target_store: &screeps::Store) -> u32 {
-        let mut value_to_transfer = self
+        let carried = self
             .creep
             .store()
             .get_used_capacity(Some(ResourceType::Energy));
@@ -244,12 +558,50 @@ impl<'a> Hauler<'a> {
             .get_free_capacity(Some(ResourceType::Energy))
             .try_into()
             .expect("could not convert i32 to u32");
+        transfer_amount(carried, target_free_store)
+    }
+    /// Heads for the closest tombstone or ruin holding enough energy to be worth the trip.
+    /// Returns whether there was one.
+    fn scavenge(&self) -> bool {
+        let room = self.creep.room().unwrap();
+        let creep_pos = self.creep.pos();
+        let energy_in = |store: screeps::Store| store.get_used_capacity(Some(ResourceType::Energy));
+        let tombstones = room.find(find::TOMBSTONES);
+        let ruins = room.find(find::RUINS);
+        let candidates: Vec<(u32, u32)> = tombstones
+            .iter()
+            .map(|t| (energy_in(t.store()), t.pos().get_range_to(creep_pos)))
+            .chain(
+                ruins
+                    .iter()
+                    .map(|r| (energy_in(r.store()), r.pos().get_range_to(creep_pos))),
+            )
+            .collect();
+        match pick_scavenge(&candidates) {
+            Some(i) if i < tombstones.len() => self.withdraw_energy(&tombstones[i]),
+            Some(i) => self.withdraw_energy(&ruins[i - tombstones.len()]),
+            None => return false,
+        }
+        true
+    }
 
-        if target_free_store < value_to_transfer {
-            value_to_transfer = target_free_store;
+    fn withdraw_energy<T>(&self, target: &T)
+    where
+        T: Withdrawable + HasStore + HasPosition,
+    {
+        if self.creep.pos().is_near_to(target.pos()) {
+            let amount = self.get_value_to_withdraw(&target.store());
+            let r = self
+                .creep
+                .withdraw(target, ResourceType::Energy, Some(amount));
+            if r != ReturnCode::Ok {
+                warn!("couldn't withdraw: {:?}", r);
+            }
+        } else {
+            self.move_to(target.pos());
         }
-        value_to_transfer
     }
+
     pub fn get_value_to_withdraw(&self, target_store: &screeps::Store) -> u32 {
         let mut value_to_transfer: u32 = self
             .creep
@@ -300,6 +652,70 @@ impl<'a> Hauler<'a> {
     }
 }
 
+/// Greedily chains the closest unfilled target from wherever the previous one leaves the hauler,
+/// until everything carried is spoken for. `candidates` are positions with their free capacity.
+pub fn plan_fill(
+    carried: u32,
+    start: Position,
+    mut candidates: Vec<(Position, u32)>,
+) -> Vec<Position> {
+    let mut plan = Vec::new();
+    let mut left = carried;
+    let mut from = start;
+    while left > 0 && !candidates.is_empty() {
+        let closest = candidates
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (pos, _))| pos.get_range_to(from))
+            .map(|(i, _)| i)
+            .unwrap();
+        let (pos, free) = candidates.swap_remove(closest);
+        plan.push(pos);
+        left = left.saturating_sub(free);
+        from = pos;
+    }
+    plan
+}
+
+/// Among decaying `(energy, range)` candidates, the closest holding enough energy to be worth the
+/// trip. Earlier candidates win ties.
+pub fn pick_scavenge(candidates: &[(u32, u32)]) -> Option<usize> {
+    candidates
+        .iter()
+        .enumerate()
+        .filter(|(_, (energy, _))| *energy >= SCAVENGE_MIN_ENERGY)
+        .min_by_key(|(_, (_, range))| *range)
+        .map(|(i, _)| i)
+}
+
+/// Whether harvesters fill the room's containers faster than haulers empty them: one is nearly
+/// full and energy spills on the ground around it.
+pub fn containers_overflowing(room: &Room) -> bool {
+    let drops = room.find(find::DROPPED_RESOURCES);
+    room.find(find::STRUCTURES)
+        .iter()
+        .filter(|s| s.structure_type() == StructureType::Container)
+        .any(|c| {
+            let store = c.as_has_store().unwrap().store();
+            let dropped = drops
+                .iter()
+                .filter(|d| d.resource_type() == ResourceType::Energy)
+                .filter(|d| d.pos().get_range_to(c.pos()) <= 1)
+                .map(|d| d.amount())
+                .sum();
+            is_overflowing(
+                store.get_used_capacity(Some(ResourceType::Energy)),
+                store.get_capacity(Some(ResourceType::Energy)),
+                dropped,
+            )
+        })
+}
+
+pub fn is_overflowing(used: u32, capacity: u32, dropped_nearby: u32) -> bool {
+    used as u64 * 100 >= capacity as u64 * CONTAINER_OVERFLOW_PERCENT as u64
+        && dropped_nearby >= CONTAINER_OVERFLOW_DROPPED
+}
+
 impl<'a> CanDeposit for Hauler<'a> {
     /// It will find and return the first depositable on the following precedence:
     /// Spawn > extension > tower > storage
@@ -307,10 +723,11 @@ impl<'a> CanDeposit for Hauler<'a> {
         let room = self.creep.room().unwrap();
         let spawns = room.find(find::MY_SPAWNS);
 
+        // a spawn busy spawning is topped up first
         let spawn = spawns
             .iter()
             .filter(|s| s.store().get_free_capacity(Some(ResourceType::Energy)) > 0)
-            .last();
+            .max_by_key(|s| spawn_goes_first(s));
         if let Some(s) = spawn {
             let target_store = s.store();
             let value_to_transfer = self.get_value_to_transfer(&target_store);
@@ -450,10 +867,12 @@ impl<'a> CanDeposit for Hauler<'a> {
             > 0
         {
             if self.creep.pos().is_near_to(deposit.pos()) {
+                // what was picked at selection time may no longer be carried, or fit
+                let amount = self.get_value_to_transfer(&deposit.store());
                 let target = *deposit.transferable();
                 let r = self
                     .creep
-                    .transfer(target, ResourceType::Energy, Some(deposit.amount()));
+                    .transfer(target, ResourceType::Energy, Some(amount));
                 info!("deposit code: {:?}", r);
                 match r {
                     ReturnCode::Ok => DepositCode::NotDone,
@@ -467,7 +886,7 @@ impl<'a> CanDeposit for Hauler<'a> {
                     }
                 }
             } else {
-                let r = self.creep.move_to(deposit.pos());
+                let r = move_towards(self.creep, deposit.pos());
                 match r {
                     ReturnCode::Ok => DepositCode::NotNear,
 
@@ -486,3 +905,87 @@ impl<'a> CanDeposit for Hauler<'a> {
         }
     }
 }
+
+/// Structures haulers take energy from. Spawns and extensions are what they fill, taking from them
+/// would only move the energy around.
+pub fn is_withdraw_source(structure_type: StructureType) -> bool {
+    matches!(
+        structure_type,
+        StructureType::Container
+            | StructureType::Storage
+            | StructureType::Terminal
+            | StructureType::Link
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screeps::{RoomCoordinate, RoomName};
+
+    fn pos(x: u8, y: u8) -> Position {
+        Position::new(
+            RoomCoordinate::new(x).unwrap(),
+            RoomCoordinate::new(y).unwrap(),
+            RoomName::new("W1N1").unwrap(),
+        )
+    }
+
+    #[test]
+    fn fill_plan_chains_closest_targets() {
+        let candidates = vec![(pos(20, 10), 50), (pos(12, 10), 50), (pos(15, 10), 50)];
+        let plan = plan_fill(150, pos(10, 10), candidates);
+        assert_eq!(plan, vec![pos(12, 10), pos(15, 10), pos(20, 10)]);
+    }
+
+    #[test]
+    fn fill_plan_stops_once_the_load_is_spoken_for() {
+        let candidates = vec![(pos(11, 10), 50), (pos(12, 10), 50), (pos(13, 10), 50)];
+        assert_eq!(plan_fill(100, pos(10, 10), candidates.clone()).len(), 2);
+        assert_eq!(plan_fill(60, pos(10, 10), candidates.clone()).len(), 2);
+        assert!(plan_fill(0, pos(10, 10), candidates).is_empty());
+    }
+
+    #[test]
+    fn full_tombstone_is_emptied_before_the_containers() {
+        assert_eq!(pick_scavenge(&[(800, 15)]), Some(0));
+    }
+
+    #[test]
+    fn scavenging_skips_scraps_and_takes_the_closest() {
+        let candidates = [
+            (SCAVENGE_MIN_ENERGY - 1, 1),
+            (800, 9),
+            (SCAVENGE_MIN_ENERGY, 4),
+        ];
+        assert_eq!(pick_scavenge(&candidates), Some(2));
+        assert_eq!(pick_scavenge(&[(SCAVENGE_MIN_ENERGY - 1, 1)]), None);
+    }
+
+    #[test]
+    fn full_container_spilling_energy_is_overflowing() {
+        let used = CONTAINER_OVERFLOW_PERCENT * 20;
+        assert!(is_overflowing(used, 2000, CONTAINER_OVERFLOW_DROPPED));
+        assert!(!is_overflowing(used - 1, 2000, CONTAINER_OVERFLOW_DROPPED));
+        assert!(!is_overflowing(2000, 2000, CONTAINER_OVERFLOW_DROPPED - 1));
+    }
+
+    #[test]
+    fn hauler_never_withdraws_from_spawns_or_extensions() {
+        assert!(!is_withdraw_source(StructureType::Spawn));
+        assert!(!is_withdraw_source(StructureType::Extension));
+        assert!(!is_withdraw_source(StructureType::Tower));
+    }
+
+    #[test]
+    fn hauler_withdraws_from_storage_structures() {
+        for t in [
+            StructureType::Container,
+            StructureType::Storage,
+            StructureType::Terminal,
+            StructureType::Link,
+        ] {
+            assert!(is_withdraw_source(t));
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use log::*;
+use screeps::{
+    find, look, prelude::*, Mineral, ReturnCode, Room, StructureContainer, StructureExtractor,
+    StructureObject,
+};
+
+use super::role::{Movable, Role};
+use crate::creep::move_towards;
+
+pub struct MineralHarvester<'a> {
+    pub creep: &'a screeps::Creep,
+}
+
+impl<'a> Movable for MineralHarvester<'a> {
+    fn move_to<T>(&self, target: T)
+    where
+        T: HasPosition,
+    {
+        let r = move_towards(self.creep, target.pos());
+        match r {
+            ReturnCode::Ok => {}
+            ReturnCode::Tired => {
+                self.creep.say("TIRED", false);
+            }
+            _ => {
+                warn!("couldn't move: {:?}", r);
+            }
+        }
+    }
+}
+
+impl<'a> MineralHarvester<'a> {
+    /// Stands on the container next to the room's mineral and mines it whenever the extractor is
+    /// ready. Having no carry part, what it mines falls into the container under it. It waits while
+    /// there's no container.
+    pub fn run(&self) {
+        let room = self.creep.room().unwrap();
+        let mineral = match room.find(find::MINERALS).into_iter().next() {
+            Some(m) => m,
+            None => return,
+        };
+        // mined onto the ground, the mineral would end up in haulers that can't get rid of it
+        let container = match mineral_container(&room, &mineral) {
+            Some(c) => c,
+            None => {
+                self.creep.say("NO_CONTAINER", false);
+                return;
+            }
+        };
+        if !self.creep.pos().is_equal_to(container.pos()) {
+            self.move_to(container.pos());
+            return;
+        }
+        let extractor_ready = extractor(&room).map(|e| e.cooldown() == 0).unwrap_or(false);
+        if !extractor_ready || mineral.mineral_amount() == 0 {
+            return;
+        }
+        let r = self.creep.harvest(&mineral);
+        if r != ReturnCode::Ok {
+            warn!("couldn't harvest {:?}: {:?}", mineral.mineral_type(), r);
+        }
+    }
+}
+
+/// Container next to the mineral, where the mineral harvester stands and haulers pick up from.
+pub fn mineral_container(room: &Room, mineral: &Mineral) -> Option<StructureContainer> {
+    room.find(find::STRUCTURES)
+        .into_iter()
+        .filter(|s| s.pos().is_near_to(mineral.pos()))
+        .find_map(|s| match s {
+            StructureObject::StructureContainer(c) => Some(c),
+            _ => None,
+        })
+}
+
+pub fn extractor(room: &Room) -> Option<StructureExtractor> {
+    let mineral = room.find(find::MINERALS).into_iter().next()?;
+    room.look_for_at(look::STRUCTURES, &mineral.pos())
+        .into_iter()
+        .find_map(|s| match s {
+            StructureObject::StructureExtractor(e) => Some(e),
+            _ => None,
+        })
+}
+
+/// Role a room needs for its mineral, if any: a harvester first, then a hauler on top of the
+/// desired ones to carry the mineral away. Only with an extractor, a container to mine into and
+/// something left to mine.
+pub fn mineral_role_to_spawn(
+    has_extractor: bool,
+    has_container: bool,
+    mineral_amount: u32,
+    roles: &[Role],
+    desired_haulers: usize,
+) -> Option<Role> {
+    if !has_extractor || !has_container || mineral_amount == 0 {
+        return None;
+    }
+    let count = |role: Role| roles.iter().filter(|r| **r == role).count();
+    if count(Role::MineralHarvester) == 0 {
+        Some(Role::MineralHarvester)
+    } else if count(Role::Hauler) <= desired_haulers {
+        Some(Role::Hauler)
+    } else {
+        None
+    }
+}
+
+/// The mineral role to spawn in the room, see `mineral_role_to_spawn`.
+pub fn room_mineral_role(room: &Room, roles: &[Role]) -> Option<Role> {
+    let mineral = room.find(find::MINERALS).into_iter().next();
+    let mineral_amount = mineral.as_ref().map(|m| m.mineral_amount()).unwrap_or(0);
+    let has_container = mineral
+        .as_ref()
+        .map(|m| mineral_container(room, m).is_some())
+        .unwrap_or(false);
+    let desired_haulers = Role::desired_counts()
+        .get(&Role::Hauler)
+        .copied()
+        .unwrap_or(0);
+    mineral_role_to_spawn(
+        extractor(room).is_some(),
+        has_container,
+        mineral_amount,
+        roles,
+        desired_haulers,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extractor_room_gets_a_mineral_harvester_then_an_extra_hauler() {
+        assert_eq!(
+            mineral_role_to_spawn(true, true, 1000, &[Role::Hauler], 1),
+            Some(Role::MineralHarvester)
+        );
+        let roles = [Role::MineralHarvester, Role::Hauler];
+        assert_eq!(
+            mineral_role_to_spawn(true, true, 1000, &roles, 1),
+            Some(Role::Hauler)
+        );
+        let roles = [Role::MineralHarvester, Role::Hauler, Role::Hauler];
+        assert_eq!(mineral_role_to_spawn(true, true, 1000, &roles, 1), None);
+    }
+
+    #[test]
+    fn no_mineral_role_without_extractor_container_or_mineral() {
+        assert_eq!(mineral_role_to_spawn(false, true, 1000, &[], 1), None);
+        assert_eq!(mineral_role_to_spawn(true, false, 1000, &[], 1), None);
+        assert_eq!(mineral_role_to_spawn(true, true, 0, &[], 1), None);
+    }
+}
@@ -1,4 +1,8 @@
 pub mod builder;
 pub mod harvester;
 pub mod hauler;
+pub mod mineral_harvester;
+pub mod reserver;
 pub mod role;
+pub mod source_keeper;
+pub mod warrior;
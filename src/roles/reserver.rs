@@ -0,0 +1,189 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{game, prelude::*, Part, Position, ReturnCode, RoomCoordinate, RoomName};
+
+use super::role::{Movable, Role};
+use crate::config::{RESERVATION_REFRESH_BELOW, RESERVER_MIN_CLAIM_PARTS};
+use crate::creep::{assign_target, home_room, move_towards};
+use crate::storage::{CreepTarget, CREEPS_ROLE};
+
+// Remote rooms by home room are read from memory every tick, kept here for the reservers
+thread_local! {
+    static REMOTE_ROOMS: RefCell<HashMap<RoomName, Vec<RoomName>>> = RefCell::new(HashMap::new());
+}
+
+pub struct Reserver<'a> {
+    pub creep: &'a screeps::Creep,
+}
+
+impl<'a> Movable for Reserver<'a> {
+    fn move_to<T>(&self, target: T)
+    where
+        T: HasPosition,
+    {
+        let r = move_towards(self.creep, target.pos());
+        match r {
+            ReturnCode::Ok => {}
+            ReturnCode::Tired => {
+                self.creep.say("TIRED", false);
+            }
+            _ => {
+                warn!("couldn't move: {:?}", r);
+            }
+        }
+    }
+}
+
+impl<'a> Reserver<'a> {
+    /// Travels to a remote room of its home and keeps its controller reserved until it dies.
+    pub fn run(&self, creep_targets: &mut HashMap<String, CreepTarget>) {
+        let name = self.creep.name();
+        let room_name = match creep_targets.get(&name) {
+            Some(CreepTarget::Reserve(r)) => Some(*r),
+            _ => self.pick_room(creep_targets),
+        };
+        let room_name = match room_name {
+            Some(r) => r,
+            None => {
+                self.creep.say("NO_WORK", false);
+                return;
+            }
+        };
+        if !assign_target(creep_targets, self.creep, CreepTarget::Reserve(room_name)) {
+            return;
+        }
+        let room = self.creep.room().unwrap();
+        if room.name() != room_name {
+            let center = RoomCoordinate::new(25).unwrap();
+            self.move_to(Position::new(center, center, room_name));
+            return;
+        }
+        let controller = match room.controller() {
+            Some(c) => c,
+            None => {
+                warn!("{} has no controller to reserve", room_name);
+                creep_targets.remove(&name);
+                return;
+            }
+        };
+        let r = self.creep.reserve_controller(&controller);
+        match r {
+            ReturnCode::Ok => {}
+            ReturnCode::NotInRange => self.move_to(controller.pos()),
+            _ => warn!("couldn't reserve {}: {:?}", room_name, r),
+        }
+    }
+
+    /// The first remote room of its home that needs a reservation and no other reserver heads
+    /// to.
+    fn pick_room(&self, creep_targets: &HashMap<String, CreepTarget>) -> Option<RoomName> {
+        let name = self.creep.name();
+        let home = home_room(&name)?;
+        let username = self.creep.owner().username();
+        remote_rooms(home)
+            .into_iter()
+            .filter(|r| room_needs_reservation(*r, &username))
+            .find(|r| {
+                !creep_targets.iter().any(|(holder, target)| {
+                    holder != &name && matches!(target, CreepTarget::Reserve(t) if t == r)
+                })
+            })
+    }
+}
+
+pub fn set_remote_rooms(remote_rooms: &HashMap<String, Vec<String>>) {
+    let parsed = remote_rooms
+        .iter()
+        .filter_map(|(home, remotes)| {
+            let home = RoomName::new(home).ok()?;
+            let remotes = remotes
+                .iter()
+                .filter_map(|r| match RoomName::new(r) {
+                    Ok(r) => Some(r),
+                    Err(_) => {
+                        warn!("invalid remote room name {}", r);
+                        None
+                    }
+                })
+                .collect();
+            Some((home, remotes))
+        })
+        .collect();
+    REMOTE_ROOMS.with(|r| *r.borrow_mut() = parsed);
+}
+
+pub fn remote_rooms(home: RoomName) -> Vec<RoomName> {
+    REMOTE_ROOMS.with(|r| r.borrow().get(&home).cloned().unwrap_or_default())
+}
+
+/// Whether the home room should spawn one more reserver: some of its remote rooms need a
+/// reservation and there are fewer reservers from it than such rooms.
+pub fn reserver_wanted(home: RoomName, username: &str) -> bool {
+    let needing = remote_rooms(home)
+        .into_iter()
+        .filter(|r| room_needs_reservation(*r, username))
+        .count();
+    let reservers = CREEPS_ROLE.with(|creeps_role_refcell| {
+        creeps_role_refcell
+            .borrow()
+            .iter()
+            .filter(|(name, role)| **role == Role::Reserver && home_room(name) == Some(home))
+            .count()
+    });
+    reservers < needing
+}
+
+/// Whether a room with this energy capacity can spawn a reserver with enough claim parts to build
+/// the reservation up rather than just hold it.
+pub fn reserver_affordable(energy_capacity: u32) -> bool {
+    let pair_cost = Part::Claim.cost() + Part::Move.cost();
+    energy_capacity >= RESERVER_MIN_CLAIM_PARTS * pair_cost
+}
+
+/// Without vision the reservation is unknown, a reserver on its way gives vision back.
+fn room_needs_reservation(room_name: RoomName, username: &str) -> bool {
+    let controller = match game::rooms().get(room_name).and_then(|r| r.controller()) {
+        Some(c) => c,
+        None => return game::rooms().get(room_name).is_none(),
+    };
+    if controller.owner().is_some() {
+        return false;
+    }
+    match controller.reservation() {
+        Some(res) => needs_reservation(Some(res.ticks_to_end()), res.username() == username),
+        None => needs_reservation(None, true),
+    }
+}
+
+/// A reservation gets refreshed once it runs below `RESERVATION_REFRESH_BELOW` ticks. One held
+/// by someone else can't be.
+pub fn needs_reservation(ticks_to_end: Option<u32>, mine: bool) -> bool {
+    match ticks_to_end {
+        None => true,
+        Some(ticks) => mine && ticks < RESERVATION_REFRESH_BELOW,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reserver_needs_room_for_the_minimum_claim_parts() {
+        let pair_cost = Part::Claim.cost() + Part::Move.cost();
+        assert!(reserver_affordable(RESERVER_MIN_CLAIM_PARTS * pair_cost));
+        assert!(!reserver_affordable(
+            RESERVER_MIN_CLAIM_PARTS * pair_cost - 1
+        ));
+    }
+
+    #[test]
+    fn reservation_is_refreshed_only_when_ours_and_running_out() {
+        assert!(needs_reservation(None, false));
+        assert!(needs_reservation(Some(RESERVATION_REFRESH_BELOW - 1), true));
+        assert!(!needs_reservation(Some(RESERVATION_REFRESH_BELOW), true));
+        assert!(!needs_reservation(Some(10), false));
+    }
+}
@@ -2,12 +2,18 @@ use std::{collections::HashMap, hash::Hash};
 
 use log::*;
 use screeps::{
-    game, prelude::*, ObjectId, Part, Position, ResourceType, ReturnCode, Source, Store, Structure,
-    StructureObject, StructureSpawn, StructureType,
+    game, prelude::*, ObjectId, Part, Position, ResourceType, ReturnCode, RoomName, Source, Store,
+    Structure, StructureObject, StructureSpawn, StructureType,
 };
 use serde::{Deserialize, Serialize};
 use std::fmt::Display;
 
+use super::warrior::{defenders_for, ThreatLevel};
+use crate::config::{
+    BODIES_ON_ROADS, EMERGENCY_HAULERS_MAX, HARVESTER_HELP_WHEN_DEPLETED,
+    HARVESTER_UPGRADE_WHEN_SATURATED, RESERVER_MAX_CLAIM_PARTS, RESERVER_MIN_CLAIM_PARTS,
+    WAIT_FOR_CAPACITY_ROLES,
+};
 use crate::creep::*;
 
 #[derive(PartialEq, Eq, Hash, Clone, Debug, Serialize, Deserialize)]
@@ -21,6 +27,8 @@ pub enum Role {
     Free,
     Tank,
     General,
+    Reserver,
+    MineralHarvester,
 }
 
 pub trait Movable {
@@ -69,6 +77,9 @@ impl Deposit {
     pub fn is_storage(&self) -> bool {
         self.is_storage
     }
+    pub fn structure_type(&self) -> StructureType {
+        self.obj.structure_type()
+    }
 }
 pub trait CanDeposit {
     fn find_closest_depositable(&self, danger: bool) -> Option<Deposit>;
@@ -76,6 +87,49 @@ pub trait CanDeposit {
     fn deposit(&self, target: Deposit) -> DepositCode;
 }
 
+/// What the spawn should do about the body a role wants.
+#[derive(Debug, PartialEq)]
+pub enum BodyPlan {
+    // affordable right now
+    Spawn(Vec<Part>),
+    // costs this much, more than the room holds yet, so the spawn holds until it does
+    WaitForEnergy(u32),
+}
+
+impl BodyPlan {
+    pub fn for_body(body: Vec<Part>, energy_available: u32) -> Self {
+        let cost = body_cost(&body);
+        if cost > energy_available {
+            BodyPlan::WaitForEnergy(cost)
+        } else {
+            BodyPlan::Spawn(body)
+        }
+    }
+}
+
+/// Most energy a room can ever hold for a spawn, at RCL 8.
+const MAX_BODY_COST: u32 = 12_900;
+
+/// What spawning the body costs. Saturates instead of overflowing, and any body costing more
+/// than a room can ever hold is logged as it can only come from a bug.
+pub fn body_cost(body: &[Part]) -> u32 {
+    let cost = body
+        .iter()
+        .try_fold(0u32, |sum, p| sum.checked_add(p.cost()))
+        .unwrap_or(u32::MAX);
+    if cost > MAX_BODY_COST {
+        warn!(
+            "body of {} parts costs {}, more than any room can hold",
+            body.len(),
+            cost
+        );
+    }
+    cost
+}
+
+/// Cheapest body worth spawning.
+const MIN_BODY_ENERGY: u32 = 300;
+
 #[derive(PartialEq)]
 pub enum DepositCode {
     Done = 0,
@@ -85,15 +139,6 @@ pub enum DepositCode {
     NotDone = 4,
 }
 
-const MOVE_POS: usize = 0;
-const WORK_POS: usize = 1;
-const CARRY_POS: usize = 2;
-const RANGED_ATTACK_POS: usize = 5;
-const ATTACK_POS: usize = 4;
-const TOUGH_POS: usize = 5;
-const HEAL_POS: usize = 6;
-const CLAIM_POS: usize = 7;
-
 const HARVESTER_POS: usize = 0;
 const HAULER_POS: usize = 1;
 const CLAIMER_POS: usize = 2;
@@ -103,6 +148,8 @@ const BUILDER_POS: usize = 5;
 const FREE_POS: usize = 6;
 const TANK_POS: usize = 7;
 const GENERAL_POS: usize = 8;
+const RESERVER_POS: usize = 9;
+const MINERAL_HARVESTER_POS: usize = 10;
 
 impl Role {
     pub fn to_string(&self) -> &str {
@@ -116,72 +163,74 @@ impl Role {
             Role::Free => "WILDLING",
             Role::Tank => "TANK",
             Role::General => "GENERAL",
+            Role::Reserver => "RESERVER",
+            Role::MineralHarvester => "MINERAL_HARVESTER",
         }
     }
-    pub fn find_role(c: &screeps::Creep) -> Option<Role> {
-        let index_to_role: HashMap<usize, Role> = [
-            (WORK_POS, Role::Harvester),
-            (CARRY_POS, Role::Hauler),
-            (HEAL_POS, Role::Healer),
-            (ATTACK_POS, Role::Warrior),
-            (CLAIM_POS, Role::Claimer),
-            (TOUGH_POS, Role::Tank),
+    /// Recovers the role from the name given at spawn time, `<ROLE>-<time>-<n>`.
+    pub fn from_creep_name(name: &str) -> Option<Role> {
+        let prefix = name.split('-').next()?;
+        [
+            Role::Harvester,
+            Role::Hauler,
+            Role::Claimer,
+            Role::Warrior,
+            Role::Healer,
+            Role::Builder,
+            Role::Free,
+            Role::Tank,
+            Role::General,
+            Role::Reserver,
+            Role::MineralHarvester,
         ]
-        .iter()
-        .cloned()
-        .collect();
+        .into_iter()
+        .find(|r| r.to_string() == prefix)
+    }
+    /// Role of a creep we hold none for: the one persisted in memory, then the one in its name,
+    /// and only then a guess from a body that may have lost parts.
+    pub fn recover(
+        memory_role: Option<Role>,
+        creep_name: &str,
+        body: impl FnOnce() -> Vec<Part>,
+    ) -> Role {
+        memory_role
+            .or_else(|| Role::from_creep_name(creep_name))
+            .or_else(|| Role::from_body(&body()))
+            .unwrap_or(Role::General)
+    }
+    pub fn find_role(c: &screeps::Creep) -> Option<Role> {
+        let body: Vec<Part> = c.body().iter().map(|p| p.part()).collect();
+        Role::from_body(&body)
+    }
 
-        let mut counters = [0; 8];
-        for p in c.body().iter() {
-            match p.part() {
-                screeps::Part::Move => {
-                    counters[MOVE_POS] += 1;
-                }
-                screeps::Part::Work => {
-                    counters[WORK_POS] += 1;
-                }
-                screeps::Part::Carry => {
-                    counters[CARRY_POS] += 1;
-                }
-                screeps::Part::Attack => {
-                    counters[ATTACK_POS] += 1;
-                }
-                screeps::Part::RangedAttack => {
-                    counters[RANGED_ATTACK_POS] += 1;
-                }
-                screeps::Part::Tough => {
-                    counters[TOUGH_POS] += 1;
-                }
-                screeps::Part::Heal => {
-                    counters[HEAL_POS] += 1;
-                }
-                screeps::Part::Claim => {
-                    counters[CARRY_POS] += 1;
-                }
-                part => {
-                    warn!("did not expect this part {:?}", part);
-                }
-            }
-        }
-        if counters[MOVE_POS] == 1 {
-            return Some(Role::Harvester);
-        };
-        if c.body().len() == 15 {
-            let rnd_number = rnd_source_idx(5);
-            if rnd_number < 1 {
-                return Some(Role::Hauler);
-            } else {
-                return Some(Role::Builder);
-            }
-        }
-        if counters[WORK_POS] > 1 {
-            return Some(Role::Builder);
+    /// Guesses the role from the functional part the body has most of, move parts don't count.
+    /// Work parts make a builder when the body can carry, a harvester otherwise. Ties go to the
+    /// part listed first. `None` for a body with nothing but move parts.
+    pub fn from_body(body: &[Part]) -> Option<Role> {
+        let count = |parts: &[Part]| body.iter().filter(|p| parts.contains(p)).count();
+        // harvesters have a single carry part next to their work parts, builders one for each
+        let work_role = if count(&[Part::Carry]) >= count(&[Part::Work]) {
+            Role::Builder
+        } else {
+            Role::Harvester
         };
-        Some(Role::Hauler)
+        [
+            (Role::Claimer, count(&[Part::Claim])),
+            (Role::Healer, count(&[Part::Heal])),
+            (Role::Warrior, count(&[Part::Attack, Part::RangedAttack])),
+            (work_role, count(&[Part::Work])),
+            (Role::Hauler, count(&[Part::Carry])),
+            (Role::Tank, count(&[Part::Tough])),
+        ]
+        .into_iter()
+        .filter(|(_, n)| *n > 0)
+        .reduce(|best, next| if next.1 > best.1 { next } else { best })
+        .map(|(role, _)| role)
     }
 
-    pub fn find_role_to_spawn(roles: &Vec<Role>, num_of_creeps: u32) -> Option<Role> {
-        let ordered_roles = vec![
+    /// Roles in the order their deficits get filled.
+    pub fn spawn_order() -> Vec<Role> {
+        vec![
             Role::Harvester,
             Role::Hauler,
             Role::Warrior,
@@ -190,8 +239,13 @@ impl Role {
             Role::Tank,
             Role::General,
             Role::Claimer,
-        ];
-        let role_to_desired_num: HashMap<Role, usize> = [
+            Role::Reserver,
+        ]
+    }
+
+    /// How many creeps of each role a room wants.
+    pub fn desired_counts() -> HashMap<Role, usize> {
+        [
             (Role::Harvester, 2),
             (Role::Hauler, 5),
             (Role::Warrior, 0),
@@ -200,12 +254,73 @@ impl Role {
             (Role::Tank, 0),
             (Role::General, 0),
             (Role::Claimer, 0),
+            (Role::Reserver, 0),
             // (Role::Free, 0),
         ]
         .iter()
         .cloned()
-        .collect();
-        let mut counters = [0 as usize; 9];
+        .collect()
+    }
+
+    /// Groups the roles of living creeps by the room they are in.
+    pub fn tally_by_room(
+        creep_rooms: impl IntoIterator<Item = (RoomName, Role)>,
+    ) -> HashMap<RoomName, Vec<Role>> {
+        let mut roles = HashMap::<RoomName, Vec<Role>>::new();
+        for (room, role) in creep_rooms {
+            roles.entry(room).or_insert_with(Vec::new).push(role);
+        }
+        roles
+    }
+
+    /// Creeps each role of the room is short of, in spawn order, leaving out roles at their count.
+    pub fn deficits(roles: &[Role]) -> Vec<(Role, usize)> {
+        let desired = Role::desired_counts();
+        Role::spawn_order()
+            .into_iter()
+            .filter_map(|role| {
+                let have = roles.iter().filter(|r| **r == role).count();
+                let missing = desired
+                    .get(&role)
+                    .copied()
+                    .unwrap_or(0)
+                    .saturating_sub(have);
+                (missing > 0).then(|| (role, missing))
+            })
+            .collect()
+    }
+
+    /// Whether a creep with this body can do the role's job.
+    pub fn body_supports(&self, body: &[Part]) -> bool {
+        let has = |part: Part| body.contains(&part);
+        match self {
+            Role::Harvester | Role::MineralHarvester => has(Part::Work),
+            Role::Hauler => has(Part::Carry),
+            Role::Builder => has(Part::Work) && has(Part::Carry),
+            Role::Warrior => has(Part::Attack) || has(Part::RangedAttack),
+            Role::Healer => has(Part::Heal),
+            Role::Claimer | Role::Reserver => has(Part::Claim),
+            Role::Tank => has(Part::Tough),
+            Role::Free | Role::General => true,
+        }
+    }
+
+    /// Next role the room needs. While its containers overflow, haulers go up to
+    /// `EMERGENCY_HAULERS_MAX` past the desired count, and defenders go up with the room's
+    /// threat. At `max_level` a single builder is added to keep the controller, see
+    /// `is_upgrade_slot`. Roles `disabled` in the room are skipped. `num_spawned` leaves out
+    /// creeps still spawning.
+    pub fn find_role_to_spawn(
+        roles: &Vec<Role>,
+        num_spawned: u32,
+        containers_overflowing: bool,
+        threat: ThreatLevel,
+        max_level: bool,
+        disabled: &[Role],
+    ) -> Option<Role> {
+        let ordered_roles = Role::spawn_order();
+        let role_to_desired_num = Role::desired_counts();
+        let mut counters = [0 as usize; 11];
         for role in roles.iter() {
             match role {
                 Role::Harvester => {
@@ -235,19 +350,30 @@ impl Role {
                 Role::General => {
                     counters[GENERAL_POS] += 1;
                 }
+                Role::Reserver => {
+                    counters[RESERVER_POS] += 1;
+                }
+                Role::MineralHarvester => {
+                    counters[MINERAL_HARVESTER_POS] += 1;
+                }
             }
         }
         info!("counters: {:?}", counters);
-        for r in ordered_roles.iter() {
+        for r in ordered_roles.iter().filter(|r| !disabled.contains(r)) {
             let desired_num = role_to_desired_num.get(r).unwrap();
             match r {
                 Role::Harvester => {
-                    if *desired_num > counters[HARVESTER_POS] && num_of_creeps > 2 {
+                    if *desired_num > counters[HARVESTER_POS] && num_spawned > 2 {
                         return Some(r.clone());
                     }
                 }
                 Role::Hauler => {
-                    if *desired_num > counters[HAULER_POS] {
+                    let extra = if containers_overflowing {
+                        EMERGENCY_HAULERS_MAX
+                    } else {
+                        0
+                    };
+                    if *desired_num + extra > counters[HAULER_POS] {
                         return Some(r.clone());
                     }
                 }
@@ -257,7 +383,8 @@ impl Role {
                     }
                 }
                 Role::Warrior => {
-                    if *desired_num > counters[WARRIOR_POS] {
+                    // more defenders come out as the threat grows
+                    if (*desired_num).max(defenders_for(threat)) > counters[WARRIOR_POS] {
                         return Some(r.clone());
                     }
                 }
@@ -267,7 +394,8 @@ impl Role {
                     }
                 }
                 Role::Builder => {
-                    if *desired_num > counters[BUILDER_POS] {
+                    let upgrader = if max_level && *desired_num > 0 { 1 } else { 0 };
+                    if *desired_num + upgrader > counters[BUILDER_POS] {
                         return Some(r.clone());
                     }
                 }
@@ -286,35 +414,70 @@ impl Role {
                         return Some(r.clone());
                     }
                 }
+                Role::Reserver => {
+                    if *desired_num > counters[RESERVER_POS] {
+                        return Some(r.clone());
+                    }
+                }
+                Role::MineralHarvester => {
+                    if *desired_num > counters[MINERAL_HARVESTER_POS] {
+                        return Some(r.clone());
+                    }
+                }
             }
         }
 
         None
     }
 
-    pub fn get_body(
-        &self,
-        energy_available: u32,
-        capacity: u32,
-        num_creeps: u32,
-    ) -> Option<Vec<Part>> {
-        if energy_available < 300 {
-            return None;
+    /// Whether this role holds the spawn until the room is at full energy capacity.
+    pub fn waits_for_capacity(&self) -> bool {
+        WAIT_FOR_CAPACITY_ROLES.contains(self)
+    }
+
+    /// Sizes the role's body to the energy available, or to the capacity for roles that wait for
+    /// it, and tells the spawn to hold while the room can't pay for it yet. `num_spawned` leaves
+    /// out creeps still spawning.
+    pub fn get_body(&self, energy_available: u32, capacity: u32, num_spawned: u32) -> BodyPlan {
+        if energy_available < MIN_BODY_ENERGY {
+            return BodyPlan::WaitForEnergy(MIN_BODY_ENERGY);
         }
 
         let mut energy_to_use = energy_available;
-        if capacity > energy_available && num_creeps > 3 {
+        if capacity > energy_available && num_spawned > 3 && self.waits_for_capacity() {
             energy_to_use = capacity;
         }
 
+        BodyPlan::for_body(self.body_for(energy_to_use), energy_available)
+    }
+
+    fn body_for(&self, energy_to_use: u32) -> Vec<Part> {
         match self {
+            // harvesters sit on their container, a single move part is all they need. The carry
+            // part lets them upgrade or haul once there's nothing left to harvest into or from.
             Role::Harvester => {
-                let mut parts = [Part::Work, Part::Work, Part::Move].to_vec();
-                let missing_parts = (energy_to_use - 250) / 100;
+                let mut parts = if HARVESTER_UPGRADE_WHEN_SATURATED || HARVESTER_HELP_WHEN_DEPLETED
+                {
+                    [Part::Work, Part::Work, Part::Carry, Part::Move].to_vec()
+                } else {
+                    [Part::Work, Part::Work, Part::Move].to_vec()
+                };
+                let missing_parts = (energy_to_use - body_cost(&parts)) / 100;
                 for _ in 0..missing_parts {
                     parts.push(Part::Work);
                 }
-                Some(parts)
+                parts
+            }
+            // the extractor only lets it mine every few ticks, it needs all the work parts it
+            // can get
+            Role::MineralHarvester => {
+                let mut parts = Vec::new();
+                for _ in 0..(energy_to_use / 250).min(16) {
+                    parts.push(Part::Work);
+                    parts.push(Part::Work);
+                    parts.push(Part::Move);
+                }
+                parts
             }
             Role::Hauler => {
                 let mut parts = [Part::Carry, Part::Move, Part::Move].to_vec();
@@ -324,7 +487,19 @@ impl Role {
                     parts.push(Part::Move);
                     parts.push(Part::Move);
                 }
-                Some(parts)
+                with_enough_move_parts(parts, energy_to_use, BODIES_ON_ROADS)
+            }
+            Role::Claimer => [Part::Claim, Part::Move].to_vec(),
+            // more claim parts reserve faster, past a few the reservation maxes out anyway
+            Role::Reserver => {
+                let pairs =
+                    (energy_to_use / 650).clamp(RESERVER_MIN_CLAIM_PARTS, RESERVER_MAX_CLAIM_PARTS);
+                let mut parts = Vec::new();
+                for _ in 0..pairs {
+                    parts.push(Part::Claim);
+                    parts.push(Part::Move);
+                }
+                parts
             }
             Role::Builder | _ => {
                 let mut parts = [Part::Carry, Part::Move, Part::Work].to_vec();
@@ -334,11 +509,356 @@ impl Role {
                     parts.push(Part::Work);
                     parts.push(Part::Move);
                 }
-                Some(parts)
+                with_enough_move_parts(parts, energy_to_use, BODIES_ON_ROADS)
+            }
+        }
+    }
+}
+/// A claimer is only worth spawning while the GCL allows one more room. Past that a reserver
+/// holds the controller instead, or nothing is spawned when reserving isn't wanted.
+pub fn claimer_or_reserver(
+    gcl_level: u32,
+    owned_rooms: u32,
+    reserve_when_maxed: bool,
+) -> Option<Role> {
+    if owned_rooms < gcl_level {
+        Some(Role::Claimer)
+    } else if reserve_when_maxed {
+        Some(Role::Reserver)
+    } else {
+        None
+    }
+}
+
+/// Whether the body moves a tile every tick without piling up fatigue. On plains each non-move
+/// part adds 2 fatigue per tile, on roads 1, and each move part takes 2 away.
+pub fn move_parts_sufficient(body: &[Part], on_roads: bool) -> bool {
+    let moves = body.iter().filter(|p| **p == Part::Move).count();
+    let fatigue_per_part = if on_roads { 1 } else { 2 };
+    (body.len() - moves) * fatigue_per_part <= moves * 2
+}
+
+/// Adds move parts with whatever is left of `energy`, trading the last non-move parts for more
+/// move parts when that isn't enough.
+fn with_enough_move_parts(mut body: Vec<Part>, energy: u32, on_roads: bool) -> Vec<Part> {
+    while !move_parts_sufficient(&body, on_roads) {
+        if body_cost(&body) + Part::Move.cost() <= energy && body.len() < 50 {
+            body.push(Part::Move);
+            continue;
+        }
+        match body.iter().rposition(|p| *p != Part::Move) {
+            Some(i) if body.iter().filter(|p| **p != Part::Move).count() > 1 => {
+                body.remove(i);
+            }
+            _ => {
+                warn!("could not give enough move parts to {:?}", body);
+                break;
             }
         }
     }
+    body
 }
-fn rnd_source_idx(max: usize) -> usize {
-    js_sys::Math::floor(js_sys::Math::random() * max as f64) as usize
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn role_is_recovered_from_spawn_name() {
+        assert_eq!(Role::from_creep_name("HAULER-12345-0"), Some(Role::Hauler));
+        assert_eq!(Role::from_creep_name("WILDLING-12345-2"), Some(Role::Free));
+        assert_eq!(
+            Role::from_creep_name("MINERAL_HARVESTER-12345-0"),
+            Some(Role::MineralHarvester)
+        );
+    }
+
+    #[test]
+    fn foreign_names_fall_back_to_the_body() {
+        assert_eq!(Role::from_creep_name("34656950-0"), None);
+        assert_eq!(Role::from_creep_name("hauler-12345-0"), None);
+        assert_eq!(Role::from_creep_name(""), None);
+    }
+
+    #[test]
+    fn waiting_roles_are_sized_to_capacity() {
+        assert!(Role::Harvester.waits_for_capacity());
+        assert!(!Role::Hauler.waits_for_capacity());
+        match Role::Harvester.get_body(400, 800, 5) {
+            BodyPlan::WaitForEnergy(cost) => assert!(cost > 400 && cost <= 800),
+            plan => panic!("harvester should wait for capacity, got {:?}", plan),
+        }
+    }
+
+    #[test]
+    fn other_roles_spawn_with_what_is_available() {
+        match Role::Hauler.get_body(400, 800, 5) {
+            BodyPlan::Spawn(body) => assert!(body_cost(&body) <= 400),
+            plan => panic!("hauler should spawn right away, got {:?}", plan),
+        }
+    }
+
+    #[test]
+    fn first_creeps_never_wait_for_capacity() {
+        assert!(matches!(
+            Role::Harvester.get_body(400, 800, 2),
+            BodyPlan::Spawn(_)
+        ));
+    }
+
+    fn full_room() -> Vec<Role> {
+        let mut roles = vec![Role::Harvester; 2];
+        roles.extend(vec![Role::Hauler; 5]);
+        roles.push(Role::Builder);
+        roles
+    }
+
+    #[test]
+    fn roles_are_tallied_per_room() {
+        let home = RoomName::new("W1N1").unwrap();
+        let remote = RoomName::new("W2N1").unwrap();
+        let roles = Role::tally_by_room(vec![
+            (home, Role::Hauler),
+            (remote, Role::Builder),
+            (home, Role::Harvester),
+        ]);
+        assert_eq!(roles[&home], vec![Role::Hauler, Role::Harvester]);
+        assert_eq!(roles[&remote], vec![Role::Builder]);
+    }
+
+    #[test]
+    fn a_full_room_does_not_hide_another_rooms_needs() {
+        let full = full_room();
+        assert_eq!(
+            Role::find_role_to_spawn(&full, 10, false, ThreatLevel::None, false, &[]),
+            None
+        );
+        // counted together the second room would look covered too
+        assert_eq!(
+            Role::find_role_to_spawn(
+                &vec![Role::Harvester; 2],
+                10,
+                false,
+                ThreatLevel::None,
+                false,
+                &[]
+            ),
+            Some(Role::Hauler)
+        );
+    }
+
+    #[test]
+    fn plains_need_a_move_part_per_other_part() {
+        assert!(move_parts_sufficient(&[Part::Work, Part::Move], false));
+        assert!(!move_parts_sufficient(
+            &[Part::Work, Part::Carry, Part::Move],
+            false
+        ));
+        assert!(move_parts_sufficient(
+            &[Part::Work, Part::Carry, Part::Move],
+            true
+        ));
+    }
+
+    #[test]
+    fn missing_move_parts_are_added_when_affordable() {
+        let body = with_enough_move_parts(vec![Part::Work, Part::Carry, Part::Move], 300, false);
+        assert!(move_parts_sufficient(&body, false));
+        assert_eq!(body.iter().filter(|p| **p == Part::Move).count(), 2);
+    }
+
+    #[test]
+    fn other_parts_are_traded_for_move_parts_when_short() {
+        let body = with_enough_move_parts(
+            vec![Part::Work, Part::Work, Part::Work, Part::Move],
+            350,
+            false,
+        );
+        assert!(move_parts_sufficient(&body, false));
+        assert!(body_cost(&body) <= 350);
+        assert!(body.contains(&Part::Work));
+    }
+
+    #[test]
+    fn persisted_role_survives_a_damaged_body() {
+        // a hauler that lost its carry parts would look like a tank
+        let body = || vec![Part::Tough, Part::Move];
+        assert_eq!(
+            Role::recover(Some(Role::Hauler), "HAULER-1-0", body),
+            Role::Hauler
+        );
+        assert_eq!(Role::recover(None, "HAULER-1-0", body), Role::Hauler);
+        assert_eq!(Role::recover(None, "34656950-0", body), Role::Tank);
+    }
+
+    #[test]
+    fn bodies_are_classified_by_their_main_part() {
+        assert_eq!(
+            Role::from_body(&[Part::Work, Part::Work, Part::Move]),
+            Some(Role::Harvester)
+        );
+        assert_eq!(
+            Role::from_body(&[Part::Work, Part::Carry, Part::Move]),
+            Some(Role::Builder)
+        );
+        assert_eq!(Role::from_body(&[Part::Move]), None);
+        assert_eq!(Role::recover(None, "", || vec![Part::Move]), Role::General);
+    }
+
+    #[test]
+    fn spawn_waits_below_the_cheapest_body() {
+        assert_eq!(
+            Role::Hauler.get_body(MIN_BODY_ENERGY - 1, 800, 5),
+            BodyPlan::WaitForEnergy(MIN_BODY_ENERGY)
+        );
+    }
+
+    #[test]
+    fn bodies_never_cost_more_than_the_room_can_pay() {
+        for energy in [300, 550, 800, 1300, 5600] {
+            for role in [Role::Harvester, Role::Hauler, Role::Builder] {
+                match role.get_body(energy, energy, 1) {
+                    BodyPlan::Spawn(body) => assert!(body_cost(&body) <= energy),
+                    plan => panic!("{:?} can't spawn with {}: {:?}", role, energy, plan),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn unaffordable_body_reports_its_cost() {
+        assert_eq!(
+            BodyPlan::for_body(vec![Part::Work, Part::Move], 100),
+            BodyPlan::WaitForEnergy(150)
+        );
+        assert_eq!(
+            BodyPlan::for_body(vec![Part::Work, Part::Move], 150),
+            BodyPlan::Spawn(vec![Part::Work, Part::Move])
+        );
+    }
+
+    #[test]
+    fn claimers_spawn_while_the_gcl_allows_another_room() {
+        assert_eq!(claimer_or_reserver(3, 2, true), Some(Role::Claimer));
+        assert_eq!(claimer_or_reserver(3, 2, false), Some(Role::Claimer));
+    }
+
+    #[test]
+    fn at_the_gcl_limit_a_reserver_goes_instead() {
+        assert_eq!(claimer_or_reserver(2, 2, true), Some(Role::Reserver));
+        assert_eq!(claimer_or_reserver(2, 2, false), None);
+    }
+
+    #[test]
+    fn move_parts_do_not_decide_the_role() {
+        assert_eq!(
+            Role::from_body(&[Part::Carry, Part::Move, Part::Move, Part::Move]),
+            Some(Role::Hauler)
+        );
+        // a single move part used to make any body a harvester
+        assert_eq!(
+            Role::from_body(&[Part::Heal, Part::Heal, Part::Move]),
+            Some(Role::Healer)
+        );
+    }
+
+    #[test]
+    fn every_functional_part_maps_to_its_role() {
+        assert_eq!(
+            Role::from_body(&[Part::Claim, Part::Move]),
+            Some(Role::Claimer)
+        );
+        assert_eq!(
+            Role::from_body(&[Part::RangedAttack, Part::Move]),
+            Some(Role::Warrior)
+        );
+        assert_eq!(
+            Role::from_body(&[Part::Tough, Part::Tough, Part::Attack]),
+            Some(Role::Tank)
+        );
+    }
+
+    #[test]
+    fn body_cost_adds_up_the_parts() {
+        assert_eq!(body_cost(&[Part::Work, Part::Carry, Part::Move]), 200);
+        assert_eq!(body_cost(&[]), 0);
+    }
+
+    #[test]
+    fn body_cost_saturates_instead_of_overflowing() {
+        // 600 each, just over u32::MAX in total
+        let body = vec![Part::Claim; 7_200_000];
+        assert_eq!(body_cost(&body), u32::MAX);
+    }
+
+    #[test]
+    fn overflowing_containers_call_for_emergency_haulers() {
+        let mut roles = full_room();
+        assert_eq!(
+            Role::find_role_to_spawn(&roles, 10, true, ThreatLevel::None, false, &[]),
+            Some(Role::Hauler)
+        );
+        roles.extend(vec![Role::Hauler; EMERGENCY_HAULERS_MAX]);
+        assert_eq!(
+            Role::find_role_to_spawn(&roles, 10, true, ThreatLevel::None, false, &[]),
+            None
+        );
+    }
+
+    #[test]
+    fn deficits_list_missing_creeps_in_spawn_order() {
+        let roles = vec![Role::Hauler, Role::Harvester, Role::Hauler];
+        assert_eq!(
+            Role::deficits(&roles),
+            vec![(Role::Harvester, 1), (Role::Hauler, 3), (Role::Builder, 1)]
+        );
+        assert!(Role::deficits(&full_room()).is_empty());
+    }
+
+    #[test]
+    fn major_threat_spawns_more_defenders_than_minor() {
+        let mut roles = full_room();
+        roles.push(Role::Warrior);
+        assert_eq!(
+            Role::find_role_to_spawn(&roles, 10, false, ThreatLevel::Minor, false, &[]),
+            None
+        );
+        assert_eq!(
+            Role::find_role_to_spawn(&roles, 10, false, ThreatLevel::Major, false, &[]),
+            Some(Role::Warrior)
+        );
+    }
+
+    #[test]
+    fn harvesters_with_a_carry_part_are_still_harvesters() {
+        assert_eq!(
+            Role::from_body(&[Part::Work, Part::Work, Part::Carry, Part::Move]),
+            Some(Role::Harvester)
+        );
+    }
+
+    #[test]
+    fn at_rcl8_one_builder_is_added_to_keep_the_controller() {
+        let mut roles = full_room();
+        assert_eq!(
+            Role::find_role_to_spawn(&roles, 10, false, ThreatLevel::None, true, &[]),
+            Some(Role::Builder)
+        );
+        roles.push(Role::Builder);
+        assert_eq!(
+            Role::find_role_to_spawn(&roles, 10, false, ThreatLevel::None, true, &[]),
+            None
+        );
+    }
+
+    #[test]
+    fn reserver_claim_parts_grow_with_energy_up_to_the_cap() {
+        let claims = |energy| match Role::Reserver.get_body(energy, energy, 5) {
+            BodyPlan::Spawn(body) => body.iter().filter(|p| **p == Part::Claim).count() as u32,
+            plan => panic!("reserver can't spawn with {}: {:?}", energy, plan),
+        };
+        assert_eq!(claims(1300), RESERVER_MIN_CLAIM_PARTS);
+        assert_eq!(claims(1950), 3);
+        assert_eq!(claims(20_000), RESERVER_MAX_CLAIM_PARTS);
+    }
 }
@@ -0,0 +1,69 @@
+use screeps::{find, prelude::*, Position, Room, StructureObject};
+
+use crate::config::{KEEPER_LAIR_SAFE_TICKS, KEEPER_SAFE_RANGE};
+
+const SOURCE_KEEPER_USERNAME: &str = "Source Keeper";
+
+pub fn is_source_keeper(creep: &screeps::Creep) -> bool {
+    creep.owner().username() == SOURCE_KEEPER_USERNAME
+}
+
+/// Whether harvesters can mine the source at `source_pos` without being torn apart.
+/// Rooms without keepers are always safe.
+pub fn is_source_safe(room: &Room, source_pos: Position) -> bool {
+    let keeper_nearby = room
+        .find(find::HOSTILE_CREEPS)
+        .iter()
+        .filter(|c| is_source_keeper(c))
+        .any(|c| c.pos().get_range_to(source_pos) <= KEEPER_SAFE_RANGE);
+    let lair_ticks_to_spawn = room
+        .find(find::STRUCTURES)
+        .into_iter()
+        .filter_map(|s| match s {
+            StructureObject::StructureKeeperLair(l) => Some(l),
+            _ => None,
+        })
+        .reduce(|closer, next| {
+            if next.pos().get_range_to(source_pos) < closer.pos().get_range_to(source_pos) {
+                next
+            } else {
+                closer
+            }
+        })
+        .and_then(|l| l.ticks_to_spawn());
+    safe_to_mine(keeper_nearby, lair_ticks_to_spawn)
+}
+
+/// The lair has no timer while its keeper is alive, so with no keeper around that's safe too.
+pub fn safe_to_mine(keeper_nearby: bool, lair_ticks_to_spawn: Option<u32>) -> bool {
+    if keeper_nearby {
+        return false;
+    }
+    match lair_ticks_to_spawn {
+        Some(ticks) => ticks > KEEPER_LAIR_SAFE_TICKS,
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeper_next_to_the_source_blocks_mining() {
+        assert!(!safe_to_mine(true, None));
+        assert!(!safe_to_mine(true, Some(KEEPER_LAIR_SAFE_TICKS + 100)));
+    }
+
+    #[test]
+    fn mining_stops_before_the_lair_respawns() {
+        assert!(safe_to_mine(false, Some(KEEPER_LAIR_SAFE_TICKS + 1)));
+        assert!(!safe_to_mine(false, Some(KEEPER_LAIR_SAFE_TICKS)));
+        assert!(!safe_to_mine(false, Some(0)));
+    }
+
+    #[test]
+    fn cleared_keeper_without_timer_is_safe() {
+        assert!(safe_to_mine(false, None));
+    }
+}
@@ -0,0 +1,613 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{
+    find, game,
+    pathfinder::{self, SearchOptions},
+    prelude::*,
+    Flag, Part, Position, ReturnCode, Room, RoomCoordinate, RoomName, StructureObject,
+    StructureType,
+};
+use serde::{Deserialize, Serialize};
+
+use super::role::{Movable, Role};
+use super::source_keeper::is_source_keeper;
+use crate::config::{
+    ATTACK_FLAG, DEFENDERS_MAX, PATROL_FLAG_PREFIX, SOURCE_KEEPER_FLAG, THREAT_MAJOR_PARTS,
+};
+use crate::creep::{assign_target, move_towards, structure_at};
+use crate::recycle::recycle_now;
+use crate::storage::{CreepTarget, THREAT_DEFENDERS};
+
+// Garrison ramparts by room are read from memory every tick, kept here for the defenders
+thread_local! {
+    static GARRISONS: RefCell<HashMap<RoomName, Vec<Position>>> = RefCell::new(HashMap::new());
+}
+
+/// How dangerous the hostiles in a room are, going by the attack and heal parts they bring.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ThreatLevel {
+    None,
+    Minor,
+    Major,
+}
+
+/// A rampart a defender holds while the room is under attack, read from the `garrisons` key in
+/// memory by room.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GarrisonPoint {
+    pub x: u8,
+    pub y: u8,
+}
+
+pub struct Warrior<'a> {
+    pub creep: &'a screeps::Creep,
+}
+
+impl<'a> Movable for Warrior<'a> {
+    fn move_to<T>(&self, target: T)
+    where
+        T: HasPosition,
+    {
+        let r = move_towards(self.creep, target.pos());
+        match r {
+            ReturnCode::Ok => {}
+            ReturnCode::Tired => {
+                self.creep.say("TIRED", false);
+            }
+            _ => {
+                warn!("couldn't move: {:?}", r);
+            }
+        }
+    }
+}
+
+impl<'a> Warrior<'a> {
+    pub fn run(&self, creep_targets: &mut HashMap<String, CreepTarget>, paused: bool) {
+        // Only go out when a target room is flagged and we're not paused, raiding takes
+        // precedence
+        if !paused {
+            if let Some(flag) = find_flag(ATTACK_FLAG) {
+                self.raid(flag);
+                return;
+            }
+            if let Some(flag) = find_flag(SOURCE_KEEPER_FLAG) {
+                self.hunt_keepers(flag);
+                return;
+            }
+        }
+        self.defend(creep_targets, paused);
+    }
+
+    /// Holds its garrison rampart, if the room has one left for it, or else engages the closest
+    /// hostile in the room. Patrols the waypoints while there's none, or stands down when it was
+    /// only spawned for the attack.
+    fn defend(&self, creep_targets: &mut HashMap<String, CreepTarget>, paused: bool) {
+        let name = self.creep.name();
+        let room = self.creep.room().unwrap();
+        if room.find(find::HOSTILE_CREEPS).len() > 0 && self.garrison(creep_targets) {
+            return;
+        }
+        let hostiles: Vec<screeps::Creep> = room
+            .find(find::HOSTILE_CREEPS)
+            .into_iter()
+            .filter(|c| !is_source_keeper(c))
+            .collect();
+        let ranges: Vec<u32> = hostiles
+            .iter()
+            .map(|h| h.pos().get_range_to(self.creep.pos()))
+            .collect();
+        let mut waypoints: Vec<Flag> = game::flags()
+            .values()
+            .filter(|f| f.name().starts_with(PATROL_FLAG_PREFIX))
+            .filter(|f| f.pos().room_name() == room.name())
+            .collect();
+        waypoints.sort_by_key(|f| f.name());
+        let current = match creep_targets.get(&name) {
+            Some(CreepTarget::Patrol(i)) if !waypoints.is_empty() => *i % waypoints.len(),
+            _ => 0,
+        };
+        let arrived = waypoints
+            .get(current)
+            .map_or(false, |f| self.creep.pos().is_near_to(f.pos()));
+        let next = match defender_order(&ranges, current, arrived, waypoints.len()) {
+            DefenderOrder::Engage(i) => {
+                // drop the patrol, it restarts from the first waypoint once the room is clear
+                creep_targets.remove(&name);
+                let h = &hostiles[i];
+                let r = self.creep.attack(h);
+                if r == ReturnCode::NotInRange {
+                    self.move_to(h.pos());
+                } else if r != ReturnCode::Ok {
+                    warn!("couldn't attack hostile: {:?}", r);
+                }
+                return;
+            }
+            DefenderOrder::Patrol(next) => Some(next),
+            DefenderOrder::StandBy => None,
+        };
+        // the ones spawned for an attack stand down once it's over
+        let threat_spawned = THREAT_DEFENDERS.with(|t| t.borrow().contains(&name));
+        if !paused
+            && surplus_defender(threat_spawned, room_threat(&room), !waypoints.is_empty())
+            && recycle_now(self.creep, creep_targets)
+        {
+            info!("{} standing down", name);
+            return;
+        }
+        if let Some(next) = next {
+            assign_target(creep_targets, self.creep, CreepTarget::Patrol(next));
+            self.move_to(waypoints[next].pos());
+        }
+    }
+
+    /// Walks onto its rampart and stays there, hitting whatever hostile comes next to it. Returns
+    /// whether it has a rampart to hold.
+    fn garrison(&self, creep_targets: &mut HashMap<String, CreepTarget>) -> bool {
+        let name = self.creep.name();
+        let room = self.creep.room().unwrap();
+        // a rampart that was lost isn't worth holding
+        let points: Vec<Position> = garrison_points(room.name())
+            .into_iter()
+            .filter(|p| structure_at(&room, *p, &[StructureType::Rampart]).is_some())
+            .collect();
+        let taken: Vec<Position> = creep_targets
+            .iter()
+            .filter(|(holder, _)| **holder != name)
+            .filter_map(|(_, target)| match target {
+                CreepTarget::Garrison(p) => Some(*p),
+                _ => None,
+            })
+            .collect();
+        let current = match creep_targets.get(&name) {
+            Some(CreepTarget::Garrison(p)) => Some(*p),
+            _ => None,
+        };
+        let point = match pick_garrison(&points, &taken, current) {
+            Some(p) => p,
+            None => return false,
+        };
+        if !assign_target(creep_targets, self.creep, CreepTarget::Garrison(point)) {
+            return false;
+        }
+        if !self.creep.pos().is_equal_to(point) {
+            self.move_to(point);
+            return true;
+        }
+        let adjacent = self
+            .creep
+            .pos()
+            .find_in_range(find::HOSTILE_CREEPS, 1)
+            .into_iter()
+            .next();
+        if let Some(h) = adjacent {
+            let r = self.creep.attack(&h);
+            if r != ReturnCode::Ok {
+                warn!("couldn't attack hostile from {:?}: {:?}", point, r);
+            }
+        }
+        true
+    }
+
+    /// Patrols the flagged source keeper room and kills the closest keeper in sight.
+    fn hunt_keepers(&self, flag: Flag) {
+        let room = self.creep.room().unwrap();
+        if room.name() != flag.pos().room_name() {
+            self.move_to(flag.pos());
+            return;
+        }
+        let keeper = room
+            .find(find::HOSTILE_CREEPS)
+            .into_iter()
+            .filter(|c| is_source_keeper(c))
+            .reduce(|closer, next| {
+                if next.pos().get_range_to(self.creep.pos())
+                    < closer.pos().get_range_to(self.creep.pos())
+                {
+                    next
+                } else {
+                    closer
+                }
+            });
+        match keeper {
+            Some(k) => {
+                let r = self.creep.attack(&k);
+                if r == ReturnCode::NotInRange {
+                    self.move_to(k.pos());
+                } else if r != ReturnCode::Ok {
+                    warn!("couldn't attack keeper: {:?}", r);
+                }
+            }
+            None => {
+                // wait for the next keeper at the flag
+                if !self.creep.pos().is_near_to(flag.pos()) {
+                    self.move_to(flag.pos());
+                }
+            }
+        }
+    }
+
+    fn raid(&self, flag: Flag) {
+        let room = self.creep.room().unwrap();
+        if room.name() != flag.pos().room_name() {
+            self.move_to(flag.pos());
+            return;
+        }
+
+        let target = self.find_demolition_target();
+        match target {
+            Some(obj) => {
+                let structure = obj.as_structure();
+                // work parts take structures down faster than attack parts
+                let r = if self.has_part(Part::Work) {
+                    self.creep.dismantle(structure)
+                } else {
+                    self.creep.attack(structure)
+                };
+                if r == ReturnCode::NotInRange {
+                    self.move_to(structure);
+                } else if r != ReturnCode::Ok {
+                    warn!("couldn't demolish {:?}: {:?}", obj.structure_type(), r);
+                }
+            }
+            None => {
+                info!("nothing left to demolish in {}", room.name());
+            }
+        }
+    }
+
+    /// Picks the hostile structure with the best demolition priority, the closest one on ties.
+    /// A wall standing on the way to it is taken down first.
+    fn find_demolition_target(&self) -> Option<StructureObject> {
+        let room = self.creep.room().unwrap();
+        let creep_pos = self.creep.pos();
+        let mut structures = room.find(find::HOSTILE_STRUCTURES);
+        let candidates: Vec<(StructureType, u32)> = structures
+            .iter()
+            .map(|s| (s.structure_type(), s.pos().get_range_to(creep_pos)))
+            .collect();
+        let target = structures.swap_remove(pick_demolition_target(&candidates)?);
+        // the novice and respawn area walls have no hits, they can't be taken down
+        let mut walls: Vec<StructureObject> = room
+            .find(find::STRUCTURES)
+            .into_iter()
+            .filter(|s| s.structure_type() == StructureType::Wall)
+            .filter(|s| s.as_attackable().map(|a| a.hits() > 0).unwrap_or(false))
+            .collect();
+        if walls.is_empty() {
+            return Some(target);
+        }
+        // without a cost matrix the search only knows the terrain, so it goes through the walls
+        let search = pathfinder::search(creep_pos, target.pos(), 1, Some(SearchOptions::default()));
+        let wall_positions: Vec<Position> = walls.iter().map(|w| w.pos()).collect();
+        match first_blocking_wall(&search.path(), &wall_positions) {
+            Some(i) => Some(walls.swap_remove(i)),
+            None => Some(target),
+        }
+    }
+
+    fn has_part(&self, part: Part) -> bool {
+        self.creep
+            .body()
+            .iter()
+            .any(|p| p.part() == part && p.hits() > 0)
+    }
+}
+
+/// What a defender without a rampart to hold does this tick.
+#[derive(Debug, PartialEq)]
+pub enum DefenderOrder {
+    // attack the hostile at this index
+    Engage(usize),
+    // head to the waypoint at this index
+    Patrol(usize),
+    // no hostile and no waypoint
+    StandBy,
+}
+
+/// Engages the closest hostile, given their `ranges`, breaking off the patrol as soon as one shows
+/// up. Otherwise keeps going from the `current` waypoint of the `waypoints` there are.
+pub fn defender_order(
+    ranges: &[u32],
+    current: usize,
+    arrived: bool,
+    waypoints: usize,
+) -> DefenderOrder {
+    if let Some((i, _)) = ranges.iter().enumerate().min_by_key(|(_, r)| **r) {
+        DefenderOrder::Engage(i)
+    } else if waypoints == 0 {
+        DefenderOrder::StandBy
+    } else {
+        DefenderOrder::Patrol(next_waypoint(current, arrived, waypoints))
+    }
+}
+
+/// Index of the waypoint to head to, moving on to the next one once the current is reached.
+pub fn next_waypoint(current: usize, arrived: bool, count: usize) -> usize {
+    if arrived {
+        (current + 1) % count
+    } else {
+        current
+    }
+}
+
+/// The rampart a defender holds: the one it already has, or else the first nobody holds.
+pub fn pick_garrison(
+    points: &[Position],
+    taken: &[Position],
+    current: Option<Position>,
+) -> Option<Position> {
+    if let Some(p) = current {
+        if points.contains(&p) {
+            return Some(p);
+        }
+    }
+    points.iter().find(|p| !taken.contains(p)).copied()
+}
+
+pub fn set_garrisons(garrisons: &HashMap<String, Vec<GarrisonPoint>>) {
+    let parsed = garrisons
+        .iter()
+        .filter_map(|(room_name, points)| {
+            let room_name = RoomName::new(room_name).ok()?;
+            let points = points
+                .iter()
+                .filter_map(
+                    |p| match (RoomCoordinate::new(p.x), RoomCoordinate::new(p.y)) {
+                        (Ok(x), Ok(y)) => Some(Position::new(x, y, room_name)),
+                        _ => {
+                            warn!("invalid garrison point {:?} in {}", p, room_name);
+                            None
+                        }
+                    },
+                )
+                .collect();
+            Some((room_name, points))
+        })
+        .collect();
+    GARRISONS.with(|g| *g.borrow_mut() = parsed);
+}
+
+fn garrison_points(room_name: RoomName) -> Vec<Position> {
+    GARRISONS.with(|g| g.borrow().get(&room_name).cloned().unwrap_or_default())
+}
+
+/// The threat posed by the hostiles in the room, source keepers left out.
+pub fn room_threat(room: &Room) -> ThreatLevel {
+    let combat_parts = room
+        .find(find::HOSTILE_CREEPS)
+        .iter()
+        .filter(|c| !is_source_keeper(c))
+        .flat_map(|c| c.body())
+        .filter(|p| p.hits() > 0)
+        .filter(|p| matches!(p.part(), Part::Attack | Part::RangedAttack | Part::Heal))
+        .count() as u32;
+    assess_threat(combat_parts)
+}
+
+pub fn assess_threat(combat_parts: u32) -> ThreatLevel {
+    if combat_parts == 0 {
+        ThreatLevel::None
+    } else if combat_parts < THREAT_MAJOR_PARTS {
+        ThreatLevel::Minor
+    } else {
+        ThreatLevel::Major
+    }
+}
+
+/// Defenders a room wants against the threat, on top of none in peace time.
+pub fn defenders_for(threat: ThreatLevel) -> usize {
+    match threat {
+        ThreatLevel::None => 0,
+        ThreatLevel::Minor => 1,
+        ThreatLevel::Major => DEFENDERS_MAX,
+    }
+}
+
+/// Whether a new warrior is spawned for a threat: not forced, and the room already has the
+/// warriors it keeps in peace time.
+pub fn spawned_for_threat(forced: bool, warriors: usize) -> bool {
+    let desired = Role::desired_counts()
+        .get(&Role::Warrior)
+        .copied()
+        .unwrap_or(0);
+    !forced && warriors >= desired
+}
+
+/// A defender spawned for a threat is surplus once the threat is gone, unless the room has
+/// waypoints for it to patrol.
+pub fn surplus_defender(threat_spawned: bool, threat: ThreatLevel, patrolled: bool) -> bool {
+    threat_spawned && threat == ThreatLevel::None && !patrolled
+}
+
+fn find_flag(name: &str) -> Option<Flag> {
+    game::flags().values().find(|f| f.name() == name)
+}
+
+/// Lower is more urgent: towers shoot back, spawns replace defenders, extensions feed the spawns
+/// and walls/ramparts are only in the way. `None` for structures that can't be attacked.
+pub fn demolition_priority(structure_type: StructureType) -> Option<u8> {
+    match structure_type {
+        StructureType::Tower => Some(0),
+        StructureType::Spawn => Some(1),
+        StructureType::Extension => Some(2),
+        StructureType::Wall | StructureType::Rampart => Some(3),
+        StructureType::Controller | StructureType::KeeperLair | StructureType::Portal => None,
+        _ => Some(4),
+    }
+}
+
+/// Index of the `(structure type, range)` candidate with the best demolition priority, the
+/// closest one on ties.
+pub fn pick_demolition_target(candidates: &[(StructureType, u32)]) -> Option<usize> {
+    candidates
+        .iter()
+        .enumerate()
+        .filter_map(|(i, (t, range))| demolition_priority(*t).map(|p| (p, *range, i)))
+        .min()
+        .map(|(_, _, i)| i)
+}
+
+/// Index of the first of `walls` met along `path`.
+pub fn first_blocking_wall(path: &[Position], walls: &[Position]) -> Option<usize> {
+    path.iter()
+        .find_map(|step| walls.iter().position(|w| w == step))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn towers_are_demolished_first() {
+        let order = [
+            StructureType::Tower,
+            StructureType::Spawn,
+            StructureType::Extension,
+            StructureType::Storage,
+        ];
+        let priorities: Vec<_> = order
+            .iter()
+            .map(|t| demolition_priority(*t).unwrap())
+            .collect();
+        assert!(priorities.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(
+            demolition_priority(StructureType::Wall),
+            demolition_priority(StructureType::Rampart)
+        );
+    }
+
+    #[test]
+    fn unattackable_structures_are_skipped() {
+        assert_eq!(demolition_priority(StructureType::Controller), None);
+        assert_eq!(demolition_priority(StructureType::KeeperLair), None);
+        assert_eq!(demolition_priority(StructureType::Portal), None);
+    }
+
+    #[test]
+    fn patrol_moves_on_once_a_waypoint_is_reached() {
+        assert_eq!(next_waypoint(0, false, 3), 0);
+        assert_eq!(next_waypoint(0, true, 3), 1);
+    }
+
+    #[test]
+    fn patrol_loops_back_to_the_first_waypoint() {
+        assert_eq!(next_waypoint(2, true, 3), 0);
+        assert_eq!(next_waypoint(0, true, 1), 0);
+    }
+
+    fn pos(x: u8, y: u8) -> Position {
+        Position::new(
+            RoomCoordinate::new(x).unwrap(),
+            RoomCoordinate::new(y).unwrap(),
+            RoomName::new("W1N1").unwrap(),
+        )
+    }
+
+    #[test]
+    fn defender_holds_its_garrison_rampart() {
+        let points = [pos(10, 10), pos(12, 10)];
+        assert_eq!(
+            pick_garrison(&points, &[pos(12, 10)], Some(pos(12, 10))),
+            Some(pos(12, 10))
+        );
+        assert_eq!(
+            pick_garrison(&points, &[pos(10, 10)], None),
+            Some(pos(12, 10))
+        );
+        assert_eq!(pick_garrison(&points, &points, None), None);
+    }
+
+    #[test]
+    fn garrison_dropped_from_config_is_given_up() {
+        let points = [pos(10, 10)];
+        assert_eq!(
+            pick_garrison(&points, &[], Some(pos(30, 30))),
+            Some(pos(10, 10))
+        );
+    }
+
+    #[test]
+    fn garrisons_are_read_by_room_skipping_invalid_points() {
+        let mut garrisons = HashMap::new();
+        garrisons.insert(
+            "W1N1".to_string(),
+            vec![
+                GarrisonPoint { x: 10, y: 10 },
+                GarrisonPoint { x: 50, y: 10 },
+            ],
+        );
+        set_garrisons(&garrisons);
+        assert_eq!(
+            garrison_points(RoomName::new("W1N1").unwrap()),
+            vec![pos(10, 10)]
+        );
+        assert!(garrison_points(RoomName::new("W2N1").unwrap()).is_empty());
+    }
+
+    #[test]
+    fn threat_grows_with_combat_parts() {
+        assert_eq!(assess_threat(0), ThreatLevel::None);
+        assert_eq!(assess_threat(THREAT_MAJOR_PARTS - 1), ThreatLevel::Minor);
+        assert_eq!(assess_threat(THREAT_MAJOR_PARTS), ThreatLevel::Major);
+    }
+
+    #[test]
+    fn major_threat_wants_more_defenders_than_minor() {
+        assert_eq!(defenders_for(ThreatLevel::None), 0);
+        assert!(defenders_for(ThreatLevel::Major) > defenders_for(ThreatLevel::Minor));
+        assert_eq!(defenders_for(ThreatLevel::Major), DEFENDERS_MAX);
+    }
+
+    #[test]
+    fn threat_defenders_are_recycled_once_it_clears() {
+        assert!(spawned_for_threat(false, 0));
+        assert!(!spawned_for_threat(true, 0));
+        assert!(surplus_defender(true, ThreatLevel::None, false));
+        assert!(!surplus_defender(true, ThreatLevel::Minor, false));
+        assert!(!surplus_defender(true, ThreatLevel::None, true));
+        assert!(!surplus_defender(false, ThreatLevel::None, false));
+    }
+
+    #[test]
+    fn demolition_picks_the_best_priority_then_the_closest() {
+        let candidates = [
+            (StructureType::Extension, 2),
+            (StructureType::Controller, 1),
+            (StructureType::Spawn, 12),
+            (StructureType::Tower, 20),
+            (StructureType::Tower, 8),
+            (StructureType::Rampart, 1),
+        ];
+        assert_eq!(pick_demolition_target(&candidates), Some(4));
+        assert_eq!(pick_demolition_target(&candidates[..3]), Some(2));
+        assert_eq!(
+            pick_demolition_target(&[(StructureType::Controller, 1)]),
+            None
+        );
+    }
+
+    #[test]
+    fn only_walls_on_the_path_are_demolished() {
+        let path = [pos(11, 10), pos(12, 10), pos(13, 10)];
+        let walls = [pos(20, 20), pos(13, 10), pos(12, 10)];
+        assert_eq!(first_blocking_wall(&path, &walls), Some(2));
+        assert_eq!(first_blocking_wall(&path, &walls[..1]), None);
+    }
+
+    #[test]
+    fn patrolling_defender_engages_a_hostile_that_shows_up() {
+        assert_eq!(defender_order(&[], 1, false, 3), DefenderOrder::Patrol(1));
+        assert_eq!(
+            defender_order(&[12, 4, 9], 1, false, 3),
+            DefenderOrder::Engage(1)
+        );
+    }
+
+    #[test]
+    fn defender_without_hostiles_or_waypoints_stands_by() {
+        assert_eq!(defender_order(&[], 0, false, 0), DefenderOrder::StandBy);
+        assert_eq!(defender_order(&[], 2, true, 3), DefenderOrder::Patrol(0));
+    }
+}
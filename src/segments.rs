@@ -0,0 +1,132 @@
+use js_sys::{JsString, Reflect};
+use log::*;
+use screeps::RawMemory;
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen::JsValue;
+
+use crate::config::{INTEL_SEGMENT, STATS_SEGMENT};
+use crate::storage::*;
+
+/// The game refuses segments longer than 100 KB.
+const SEGMENT_MAX_LEN: usize = 100 * 1024;
+
+/// Asks for the segments this bot uses, they become readable on the next tick.
+pub fn request_active() {
+    let ids = js_sys::Array::new();
+    ids.push(&JsValue::from(INTEL_SEGMENT));
+    ids.push(&JsValue::from(STATS_SEGMENT));
+    RawMemory::set_active_segments(&ids);
+}
+
+/// Loads room snapshots and stats back after a global reset, once their segments are readable.
+pub fn restore() {
+    if SEGMENTS_LOADED.with(|loaded| *loaded.borrow()) {
+        return;
+    }
+    let (intel, stats) = match (read(INTEL_SEGMENT), read(STATS_SEGMENT)) {
+        (Some(intel), Some(stats)) => (intel, stats),
+        // not active yet
+        _ => return,
+    };
+    if let Some(snapshots) = decode(&intel) {
+        ROOMS_SNAPSHOT.with(|rooms_snapshot_refcell| {
+            *rooms_snapshot_refcell.borrow_mut() = snapshots;
+        });
+    }
+    if let Some(saved) = decode(&stats) {
+        STATS.with(|stats_refcell| {
+            *stats_refcell.borrow_mut() = saved;
+        });
+    }
+    info!("restored intel and stats from segments");
+    SEGMENTS_LOADED.with(|loaded| *loaded.borrow_mut() = true);
+}
+
+/// Writes room snapshots and stats to their segments. Does nothing until they were restored, so
+/// an empty heap never overwrites what's saved.
+pub fn persist() {
+    if !SEGMENTS_LOADED.with(|loaded| *loaded.borrow()) {
+        return;
+    }
+    ROOMS_SNAPSHOT.with(|rooms_snapshot_refcell| {
+        write(INTEL_SEGMENT, &*rooms_snapshot_refcell.borrow());
+    });
+    STATS.with(|stats_refcell| {
+        write(STATS_SEGMENT, &*stats_refcell.borrow());
+    });
+}
+
+/// Contents of an active segment, `None` while it isn't.
+fn read(id: u8) -> Option<String> {
+    Reflect::get(&RawMemory::segments(), &JsValue::from(id))
+        .ok()
+        .and_then(|v| v.as_string())
+}
+
+fn write<T: Serialize>(id: u8, value: &T) {
+    match encode(value) {
+        Some(s) => {
+            let _ = Reflect::set(
+                &RawMemory::segments(),
+                &JsValue::from(id),
+                &JsString::from(s),
+            );
+        }
+        None => {
+            warn!("could not save segment {}", id);
+        }
+    }
+}
+
+/// Serializes a value for a segment, `None` when it doesn't fit in one.
+pub fn encode<T: Serialize>(value: &T) -> Option<String> {
+    match serde_json::to_string(value) {
+        Ok(s) if s.len() <= SEGMENT_MAX_LEN => Some(s),
+        Ok(s) => {
+            warn!("{} bytes don't fit in a segment", s.len());
+            None
+        }
+        Err(e) => {
+            warn!("could not serialize segment: {}", e);
+            None
+        }
+    }
+}
+
+/// Deserializes a segment, `None` for an empty or unreadable one.
+pub fn decode<T: DeserializeOwned>(segment: &str) -> Option<T> {
+    if segment.is_empty() {
+        return None;
+    }
+    match serde_json::from_str(segment) {
+        Ok(value) => Some(value),
+        Err(e) => {
+            info!("could not deserialize segment: {}", e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_round_trip_through_a_segment() {
+        let stats = Stats { structures_lost: 3 };
+        let saved: Stats = decode(&encode(&stats).unwrap()).unwrap();
+        assert_eq!(saved.structures_lost, 3);
+    }
+
+    #[test]
+    fn oversized_data_is_not_written() {
+        let big = "x".repeat(SEGMENT_MAX_LEN);
+        assert!(encode(&big).is_none());
+    }
+
+    #[test]
+    fn empty_or_garbled_segments_are_ignored() {
+        assert!(decode::<Stats>("").is_none());
+        assert!(decode::<Stats>("{oops").is_none());
+    }
+}
@@ -1,30 +1,104 @@
+use crate::intel::RoomSnapshot;
+use crate::link::LinkConfig;
+use crate::market::MarketConfig;
 use crate::roles::role::Role;
+use crate::roles::warrior::GarrisonPoint;
+use crate::terminal::BalanceMove;
 use screeps::{
-    Attackable, ConstructionSite, Creep, Healable, ObjectId, Position, Resource, Source, Structure,
-    StructureController, StructureTower,
+    Attackable, ConstructionSite, Creep, Healable, ObjectId, Part, Position, Resource,
+    ResourceType, RoomName, Source, Structure, StructureContainer, StructureController,
+    StructureLab, StructureSpawn, StructureTower, StructureType,
 };
 use serde::{Deserialize, Serialize};
 // this is one way to persist data between ticks within Rust's memory, as opposed to
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 // keeping state in memory on game objects - but will be lost on global resets!
 thread_local! {
     pub static CREEPS_TARGET: RefCell<HashMap<String, CreepTarget>> = RefCell::new(HashMap::new());
     pub static TOWERS_TARGET: RefCell<HashMap<Position, TowerTarget>> = RefCell::new(HashMap::new());
     pub static CREEPS_ROLE: RefCell<HashMap<String, Role>> = RefCell::new(HashMap::new());
+    pub static CREEPS_HOME: RefCell<HashMap<String, RoomName>> = RefCell::new(HashMap::new());
+    pub static CREEPS_DESTINATION: RefCell<HashMap<String, Position>> = RefCell::new(HashMap::new());
+    pub static CREEPS_SOURCE: RefCell<HashMap<String, ObjectId<Source>>> = RefCell::new(HashMap::new());
+    pub static THREAT_DEFENDERS: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
     static CREEPS_MEMORY: RefCell<HashMap<String, CreepMemory>> = RefCell::new(HashMap::new());
+    pub static ROOMS_SNAPSHOT: RefCell<HashMap<RoomName, RoomSnapshot>> = RefCell::new(HashMap::new());
+    pub static STATS: RefCell<Stats> = RefCell::new(Stats::default());
+    pub static SEGMENTS_LOADED: RefCell<bool> = RefCell::new(false);
 }
 
 // this enum will represent a creep's lock on a specific target object, storing a js reference to the object id so that we can grab a fresh reference to the object each successive tick, since screeps game objects become 'stale' and shouldn't be used beyond the tick they were fetched
 #[derive(Clone)]
 pub enum CreepTarget {
-    TransferToCreep(Creep), // UpgradeController(ObjectId<StructureController>),
-                            // UpgradeConstructionSite(ConstructionSite),
-                            // Harvest(ObjectId<Source>),
-                            // Deposit(),
-                            // Pickup(Resource),
-                            // Harvester(Option<ObjectId<Source>>, Option<StructureObject>),
-                            // Repair(ObjectId<Structure>)
+    TransferToCreep(Creep),
+    // container tile a harvester has claimed
+    HarvestSpot(Position),
+    // override set by the starvation watchdog, beats whatever the role would do
+    FeedSpawn(ObjectId<StructureSpawn>),
+    // spawns/extensions a hauler fills one after the other, by position
+    FillChain(Vec<Position>),
+    // index of the patrol waypoint a defender is heading to
+    Patrol(usize),
+    // construction site a builder works on until it's finished, by position
+    Build(Position),
+    // structure a builder repairs until it's back to shape, by position and type
+    Repair(Position, StructureType),
+    // lab a creep walks to for a boost before getting to work
+    Boost(ObjectId<StructureLab>),
+    // energy a hauler moves between the storage and the terminal of its room
+    BalanceTerminal(BalanceMove),
+    // remote room whose controller a reserver keeps reserved
+    Reserve(RoomName),
+    // spawn a dying creep walks to for its energy back
+    Recycle(ObjectId<StructureSpawn>),
+    // mineral container a hauler empties into the storage
+    HaulMineral(ObjectId<StructureContainer>),
+    // rampart a defender holds during an attack
+    Garrison(Position),
+    // UpgradeController(ObjectId<StructureController>),
+    // UpgradeConstructionSite(ConstructionSite),
+    // Harvest(ObjectId<Source>),
+    // Deposit(),
+    // Pickup(Resource),
+    // Harvester(Option<ObjectId<Source>>, Option<StructureObject>),
+    // Repair(ObjectId<Structure>)
+}
+impl CreepTarget {
+    pub fn name(&self) -> &str {
+        match self {
+            CreepTarget::TransferToCreep(_) => "TransferToCreep",
+            CreepTarget::HarvestSpot(_) => "HarvestSpot",
+            CreepTarget::FeedSpawn(_) => "FeedSpawn",
+            CreepTarget::FillChain(_) => "FillChain",
+            CreepTarget::Patrol(_) => "Patrol",
+            CreepTarget::Build(_) => "Build",
+            CreepTarget::Repair(_, _) => "Repair",
+            CreepTarget::Boost(_) => "Boost",
+            CreepTarget::BalanceTerminal(_) => "BalanceTerminal",
+            CreepTarget::Reserve(_) => "Reserve",
+            CreepTarget::Recycle(_) => "Recycle",
+            CreepTarget::HaulMineral(_) => "HaulMineral",
+            CreepTarget::Garrison(_) => "Garrison",
+        }
+    }
+
+    /// Body part a creep can't carry out the task without.
+    pub fn required_part(&self) -> Option<Part> {
+        match self {
+            CreepTarget::TransferToCreep(_)
+            | CreepTarget::FeedSpawn(_)
+            | CreepTarget::FillChain(_)
+            | CreepTarget::BalanceTerminal(_)
+            | CreepTarget::HaulMineral(_) => Some(Part::Carry),
+            CreepTarget::HarvestSpot(_) | CreepTarget::Build(_) | CreepTarget::Repair(_, _) => {
+                Some(Part::Work)
+            }
+            CreepTarget::Reserve(_) => Some(Part::Claim),
+            CreepTarget::Garrison(_) => Some(Part::Attack),
+            CreepTarget::Patrol(_) | CreepTarget::Boost(_) | CreepTarget::Recycle(_) => None,
+        }
+    }
 }
 // this enum will represent a creep's lock on a specific target object, storing a js reference to the object id so that we can grab a fresh reference to the object each successive tick, since screeps game objects become 'stale' and shouldn't be used beyond the tick they were fetched
 pub enum TowerTarget {
@@ -32,14 +106,82 @@ pub enum TowerTarget {
     Heal(Box<dyn Healable>),
     Repair(ObjectId<Structure>),
 }
+// counters kept across global resets in their own memory segment
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Stats {
+    pub structures_lost: u32,
+}
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Root {
     pub creeps: HashMap<String, CreepMemory>,
+    // role to spawn next regardless of deficits, cleared once spawned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub force_spawn: Option<Role>,
+    // consecutive ticks each spawn, by name, has been starving for energy
+    #[serde(default)]
+    pub spawn_starvation: HashMap<String, u32>,
+    // per-module log levels, e.g. {"roles::hauler": "debug", "tower": "warn"}
+    #[serde(default)]
+    pub log_filters: HashMap<String, String>,
+    #[serde(default)]
+    pub market: MarketConfig,
+    // amount of each resource terminals never sell or send away
+    #[serde(default)]
+    pub terminal_reserves: HashMap<ResourceType, u32>,
+    // maintenance mode: no spawning, building, raiding or trading
+    #[serde(default)]
+    pub paused: bool,
+    // link roles by room name, e.g. {"W1N1": [{"x": 10, "y": 12, "role": "Source"}]}
+    #[serde(default)]
+    pub links: HashMap<String, Vec<LinkConfig>>,
+    // named bodies, e.g. {"big_hauler": ["carry", "carry", "move", "move"]}
+    #[serde(default)]
+    pub body_presets: HashMap<String, Vec<Part>>,
+    // preset each role spawns with, by room name, e.g. {"W1N1": {"Hauler": "big_hauler"}}
+    #[serde(default)]
+    pub room_body_presets: HashMap<String, HashMap<Role, String>>,
+    // rooms reserved for remote mining, by home room name, e.g. {"W1N1": ["W2N1"]}
+    #[serde(default)]
+    pub remote_rooms: HashMap<String, Vec<String>>,
+    // ramparts defenders hold during attacks, by room name, e.g. {"W1N1": [{"x": 20, "y": 8}]}
+    #[serde(default)]
+    pub garrisons: HashMap<String, Vec<GarrisonPoint>>,
+    // roles never spawned in a room whatever its deficits, e.g. {"W1N1": ["Builder"]}
+    #[serde(default)]
+    pub disabled_roles: HashMap<String, Vec<Role>>,
 }
 #[derive(Debug, Serialize, Deserialize)]
 pub struct CreepMemory {
     _move: Option<Move>,
     pub role: Option<Role>,
+    // room the creep was spawned in, unset for creeps spawned before it was tracked
+    #[serde(default)]
+    pub home_room: Option<String>,
+    // source a harvester was given at spawn time and sticks to
+    #[serde(default)]
+    pub source: Option<String>,
+    // defender spawned above the peace time count for an attack, recycled once it's over
+    #[serde(default)]
+    pub threat_spawned: bool,
+}
+impl CreepMemory {
+    pub fn new(role: Role, home_room: RoomName) -> Self {
+        Self {
+            _move: None,
+            role: Some(role),
+            home_room: Some(home_room.to_string()),
+            source: None,
+            threat_spawned: false,
+        }
+    }
+
+    pub fn home_room(&self) -> Option<RoomName> {
+        self.home_room.as_ref().and_then(|r| RoomName::new(r).ok())
+    }
+
+    pub fn source(&self) -> Option<ObjectId<Source>> {
+        self.source.as_ref().and_then(|s| s.parse().ok())
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -55,3 +197,84 @@ pub struct DestJson {
     y: u64,
     room: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use screeps::RoomCoordinate;
+
+    #[test]
+    fn force_spawn_is_read_from_memory() {
+        let root: Root =
+            serde_json::from_str(r#"{"creeps": {}, "force_spawn": "Hauler"}"#).unwrap();
+        assert_eq!(root.force_spawn, Some(Role::Hauler));
+    }
+
+    #[test]
+    fn cleared_force_spawn_is_left_out_of_memory() {
+        let mut root: Root =
+            serde_json::from_str(r#"{"creeps": {}, "force_spawn": "Warrior"}"#).unwrap();
+        root.force_spawn = None;
+        let written = serde_json::to_string(&root).unwrap();
+        assert!(!written.contains("force_spawn"));
+    }
+
+    #[test]
+    fn colony_runs_unless_paused_in_memory() {
+        let root: Root = serde_json::from_str(r#"{"creeps": {}}"#).unwrap();
+        assert!(!root.paused);
+        let root: Root = serde_json::from_str(r#"{"creeps": {}, "paused": true}"#).unwrap();
+        assert!(root.paused);
+    }
+
+    fn pos(x: u8, y: u8) -> Position {
+        Position::new(
+            RoomCoordinate::new(x).unwrap(),
+            RoomCoordinate::new(y).unwrap(),
+            RoomName::new("W1N1").unwrap(),
+        )
+    }
+
+    #[test]
+    fn deposit_tasks_need_a_carry_part() {
+        assert_eq!(
+            CreepTarget::FillChain(vec![pos(10, 10)]).required_part(),
+            Some(Part::Carry)
+        );
+    }
+
+    #[test]
+    fn work_tasks_need_a_work_part() {
+        assert_eq!(
+            CreepTarget::HarvestSpot(pos(10, 10)).required_part(),
+            Some(Part::Work)
+        );
+        assert_eq!(
+            CreepTarget::Repair(pos(10, 10), StructureType::Road).required_part(),
+            Some(Part::Work)
+        );
+        assert_eq!(CreepTarget::Patrol(0).required_part(), None);
+    }
+
+    #[test]
+    fn home_room_is_the_spawning_room() {
+        let spawn_room = RoomName::new("W1N1").unwrap();
+        let memory = CreepMemory::new(Role::Hauler, spawn_room);
+        assert_eq!(memory.home_room(), Some(spawn_room));
+    }
+
+    #[test]
+    fn home_room_round_trips_through_memory() {
+        let memory = CreepMemory::new(Role::Claimer, RoomName::new("W1N1").unwrap());
+        let json = serde_json::to_string(&memory).unwrap();
+        let read: CreepMemory = serde_json::from_str(&json).unwrap();
+        assert_eq!(read.home_room(), Some(RoomName::new("W1N1").unwrap()));
+        assert_eq!(read.role, Some(Role::Claimer));
+    }
+
+    #[test]
+    fn creeps_spawned_before_home_rooms_have_none() {
+        let read: CreepMemory = serde_json::from_str(r#"{"role": "Hauler"}"#).unwrap();
+        assert_eq!(read.home_room(), None);
+    }
+}
@@ -0,0 +1,314 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{game, prelude::*, ResourceType, ReturnCode, RoomName, StructureTerminal};
+
+use crate::config::{
+    TERMINAL_BALANCE_AMOUNT, TERMINAL_BALANCE_TOLERANCE, TERMINAL_ENERGY_BUFFER,
+    TERMINAL_ENERGY_DEFICIT_BELOW, TERMINAL_ENERGY_SURPLUS_ABOVE,
+};
+
+// Reserves are read from memory every tick, kept here for the haulers stocking terminals
+thread_local! {
+    static RESERVES: RefCell<HashMap<ResourceType, u32>> = RefCell::new(HashMap::new());
+}
+
+/// Which way a hauler moves energy between a room's storage and terminal.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BalanceMove {
+    ToTerminal,
+    ToStorage,
+}
+
+pub fn set_reserves(reserves: &HashMap<ResourceType, u32>) {
+    RESERVES.with(|r| *r.borrow_mut() = reserves.clone());
+}
+
+/// Energy a terminal is kept stocked with: its reserve plus a buffer for sends and deals.
+pub fn terminal_energy_target() -> u32 {
+    let reserve = RESERVES.with(|r| r.borrow().get(&ResourceType::Energy).copied().unwrap_or(0));
+    reserve + TERMINAL_ENERGY_BUFFER
+}
+
+/// Which way, and how much, energy should move for the terminal to get back to `target`. Nothing
+/// moves while it's within the tolerance.
+pub fn decide_balance(
+    storage_energy: u32,
+    terminal_energy: u32,
+    target: u32,
+) -> Option<(BalanceMove, u32)> {
+    if terminal_energy + TERMINAL_BALANCE_TOLERANCE < target && storage_energy > 0 {
+        Some((
+            BalanceMove::ToTerminal,
+            (target - terminal_energy).min(storage_energy),
+        ))
+    } else if terminal_energy > target + TERMINAL_BALANCE_TOLERANCE {
+        Some((BalanceMove::ToStorage, terminal_energy - target))
+    } else {
+        None
+    }
+}
+
+/// How much of `resource` can leave a terminal holding `in_terminal` of it without going below
+/// its reserve. Resources without a reserve can go entirely.
+pub fn spare_amount(
+    resource: ResourceType,
+    in_terminal: u32,
+    reserves: &HashMap<ResourceType, u32>,
+) -> u32 {
+    in_terminal.saturating_sub(reserves.get(&resource).copied().unwrap_or(0))
+}
+
+/// Sends resources to another room's terminal, refusing anything that would eat into the
+/// reserves. The transfer cost in energy counts against the energy reserve as well.
+pub fn send(
+    terminal: &StructureTerminal,
+    resource: ResourceType,
+    amount: u32,
+    destination: RoomName,
+    reserves: &HashMap<ResourceType, u32>,
+) -> ReturnCode {
+    let store = terminal.store();
+    let cost = screeps::game::market::calc_transaction_cost(
+        amount,
+        terminal.room().unwrap().name(),
+        destination,
+    );
+    let in_terminal = store.get_used_capacity(Some(resource));
+    let energy_in_terminal = store.get_used_capacity(Some(ResourceType::Energy));
+    if !can_send(
+        resource,
+        amount,
+        cost,
+        in_terminal,
+        energy_in_terminal,
+        reserves,
+    ) {
+        info!(
+            "not sending {} {:?} to {}, it would break the reserve",
+            amount, resource, destination
+        );
+        return ReturnCode::NotEnough;
+    }
+    terminal.send(resource, amount, destination, None)
+}
+
+/// Whether `amount` of the resource and the energy the transfer costs can both leave the terminal
+/// without going below their reserves. For energy both come out of the same stock.
+pub fn can_send(
+    resource: ResourceType,
+    amount: u32,
+    cost: u32,
+    in_terminal: u32,
+    energy_in_terminal: u32,
+    reserves: &HashMap<ResourceType, u32>,
+) -> bool {
+    let spare_energy = spare_amount(ResourceType::Energy, energy_in_terminal, reserves);
+    if resource == ResourceType::Energy {
+        amount + cost <= spare_energy
+    } else {
+        amount <= spare_amount(resource, in_terminal, reserves) && cost <= spare_energy
+    }
+}
+
+/// Sends energy from the room with the most stored to the one with the least, when the first
+/// has a surplus and the second runs short.
+pub fn balance_energy(reserves: &HashMap<ResourceType, u32>) {
+    let mut terminals = HashMap::<RoomName, StructureTerminal>::new();
+    let mut levels = Vec::<(RoomName, u32)>::new();
+    for room in game::rooms().values() {
+        let terminal = match room.terminal() {
+            Some(t) if t.my() => t,
+            _ => continue,
+        };
+        let mut energy = terminal
+            .store()
+            .get_used_capacity(Some(ResourceType::Energy));
+        if let Some(storage) = room.storage() {
+            energy += storage
+                .store()
+                .get_used_capacity(Some(ResourceType::Energy));
+        }
+        levels.push((room.name(), energy));
+        terminals.insert(room.name(), terminal);
+    }
+    let (from, to) = match pick_balance_pair(&levels) {
+        Some(pair) => pair,
+        None => return,
+    };
+    let terminal = &terminals[&from];
+    if terminal.cooldown() > 0 {
+        return;
+    }
+    let r = send(
+        terminal,
+        ResourceType::Energy,
+        TERMINAL_BALANCE_AMOUNT,
+        to,
+        reserves,
+    );
+    if r == ReturnCode::Ok {
+        info!(
+            "sent {} energy from {} to {}",
+            TERMINAL_BALANCE_AMOUNT, from, to
+        );
+    } else if r != ReturnCode::NotEnough {
+        warn!("couldn't send energy from {} to {}: {:?}", from, to, r);
+    }
+}
+
+/// Among `(room, stored energy)`, the richest room above the surplus threshold and the poorest
+/// below the deficit threshold, in that order.
+pub fn pick_balance_pair(levels: &[(RoomName, u32)]) -> Option<(RoomName, RoomName)> {
+    let richest = levels
+        .iter()
+        .filter(|(_, e)| *e > TERMINAL_ENERGY_SURPLUS_ABOVE)
+        .max_by_key(|(_, e)| *e)?;
+    let poorest = levels
+        .iter()
+        .filter(|(_, e)| *e < TERMINAL_ENERGY_DEFICIT_BELOW)
+        .min_by_key(|(_, e)| *e)?;
+    Some((richest.0, poorest.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reserves() -> HashMap<ResourceType, u32> {
+        [(ResourceType::Energy, 1000), (ResourceType::Oxygen, 500)]
+            .into_iter()
+            .collect()
+    }
+
+    #[test]
+    fn reserves_are_kept_in_the_terminal() {
+        assert_eq!(spare_amount(ResourceType::Oxygen, 800, &reserves()), 300);
+        assert_eq!(spare_amount(ResourceType::Oxygen, 400, &reserves()), 0);
+        assert_eq!(spare_amount(ResourceType::Hydrogen, 400, &reserves()), 400);
+    }
+
+    #[test]
+    fn energy_sends_pay_the_transfer_cost_from_the_same_stock() {
+        assert!(can_send(
+            ResourceType::Energy,
+            400,
+            100,
+            1500,
+            1500,
+            &reserves()
+        ));
+        assert!(!can_send(
+            ResourceType::Energy,
+            450,
+            100,
+            1500,
+            1500,
+            &reserves()
+        ));
+    }
+
+    #[test]
+    fn other_sends_need_energy_above_its_reserve_for_the_cost() {
+        assert!(can_send(
+            ResourceType::Oxygen,
+            300,
+            100,
+            800,
+            1100,
+            &reserves()
+        ));
+        assert!(!can_send(
+            ResourceType::Oxygen,
+            300,
+            100,
+            800,
+            1050,
+            &reserves()
+        ));
+        assert!(!can_send(
+            ResourceType::Oxygen,
+            301,
+            100,
+            800,
+            2000,
+            &reserves()
+        ));
+    }
+
+    #[test]
+    fn energy_goes_from_the_richest_to_the_poorest_room() {
+        let rich = RoomName::new("W1N1").unwrap();
+        let richer = RoomName::new("W2N1").unwrap();
+        let poor = RoomName::new("W3N1").unwrap();
+        let levels = [
+            (rich, TERMINAL_ENERGY_SURPLUS_ABOVE + 1),
+            (richer, TERMINAL_ENERGY_SURPLUS_ABOVE + 10),
+            (poor, TERMINAL_ENERGY_DEFICIT_BELOW - 1),
+        ];
+        assert_eq!(pick_balance_pair(&levels), Some((richer, poor)));
+    }
+
+    #[test]
+    fn no_transfer_without_both_a_surplus_and_a_deficit() {
+        let a = RoomName::new("W1N1").unwrap();
+        let b = RoomName::new("W2N1").unwrap();
+        assert_eq!(
+            pick_balance_pair(&[
+                (a, TERMINAL_ENERGY_SURPLUS_ABOVE + 1),
+                (b, TERMINAL_ENERGY_DEFICIT_BELOW)
+            ]),
+            None
+        );
+        assert_eq!(
+            pick_balance_pair(&[
+                (a, TERMINAL_ENERGY_SURPLUS_ABOVE),
+                (b, TERMINAL_ENERGY_DEFICIT_BELOW - 1)
+            ]),
+            None
+        );
+    }
+
+    #[test]
+    fn storage_tops_the_terminal_up_to_its_target() {
+        assert_eq!(
+            decide_balance(50_000, 0, 20_000),
+            Some((BalanceMove::ToTerminal, 20_000))
+        );
+        assert_eq!(
+            decide_balance(5_000, 0, 20_000),
+            Some((BalanceMove::ToTerminal, 5_000))
+        );
+    }
+
+    #[test]
+    fn terminal_surplus_goes_back_to_storage() {
+        let target = 20_000;
+        let terminal = target + TERMINAL_BALANCE_TOLERANCE + 1;
+        assert_eq!(
+            decide_balance(0, terminal, target),
+            Some((BalanceMove::ToStorage, terminal - target))
+        );
+    }
+
+    #[test]
+    fn nothing_moves_within_the_tolerance() {
+        let target = 20_000;
+        assert_eq!(
+            decide_balance(50_000, target - TERMINAL_BALANCE_TOLERANCE, target),
+            None
+        );
+        assert_eq!(
+            decide_balance(50_000, target + TERMINAL_BALANCE_TOLERANCE, target),
+            None
+        );
+        assert_eq!(decide_balance(0, 0, target), None);
+    }
+
+    #[test]
+    fn terminal_target_keeps_the_reserve_and_a_buffer() {
+        set_reserves(&reserves());
+        assert_eq!(terminal_energy_target(), 1000 + TERMINAL_ENERGY_BUFFER);
+    }
+}
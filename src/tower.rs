@@ -1,11 +1,18 @@
 use std::collections::HashMap;
 
+use crate::config::{
+    TOWER_CRITICAL_HITS_PERCENT, TOWER_EFFECTIVE_RANGE, TOWER_HOLD_REPAIR_SITES,
+    TOWER_SURPLUS_ENERGY_PERCENT,
+};
+use crate::repair::{
+    has_repair_budget, pick_within_budget, record_repair, should_maintain, TOWER_REPAIR_COST,
+};
 use crate::storage::*;
 use log::*;
 use screeps::{
-    find, game, Attackable, Creep as ScreepsCreep, HasPosition, HasTypedId, MaybeHasNativeId, Part,
-    Position, ResourceType, ReturnCode, Room, RoomPosition, Store, Structure, StructureProperties,
-    StructureTower, StructureType,
+    find, game, Attackable, Creep as ScreepsCreep, HasPosition, HasTypedId, MaybeHasNativeId,
+    ObjectId, Part, Position, ResourceType, ReturnCode, Room, RoomPosition, Store, Structure,
+    StructureObject, StructureProperties, StructureTower, StructureType,
 };
 pub struct Tower<'a> {
     inner_tower: &'a StructureTower,
@@ -32,6 +39,18 @@ impl<'a> Tower<'a> {
     {
         self.inner_tower.attack(target)
     }
+    pub fn heal(&self, target: &ScreepsCreep) -> ReturnCode {
+        self.inner_tower.heal(target)
+    }
+    /// Heals a critically wounded creep ahead of anything else this tick. Returns whether it
+    /// did, so another tower tries when this one couldn't.
+    pub fn save(&self, wounded: &ScreepsCreep) -> bool {
+        let r = self.heal(wounded);
+        if r != ReturnCode::Ok {
+            warn!("couldn't heal: {:?}", r);
+        }
+        r == ReturnCode::Ok
+    }
     pub fn run(
         &self,
         towers_target: &mut HashMap<Position, TowerTarget>,
@@ -40,12 +59,27 @@ impl<'a> Tower<'a> {
         let room = self.room().unwrap();
         let tower_pos = self.pos();
         let creeps: Vec<ScreepsCreep> = game::creeps().values().collect();
+        let construction_surge = holds_repairs(
+            room.find(find::MY_CONSTRUCTION_SITES).len(),
+            !hostiles.is_empty(),
+        );
 
         let target = towers_target.get(&self.pos());
         match target {
             Some(tower_target) => match &tower_target {
                 TowerTarget::Repair(structure_id) => match structure_id.resolve() {
+                    Some(_) if construction_surge => {
+                        debug!("holding repairs, energy goes to the builders");
+                        towers_target.remove(&tower_pos);
+                    }
                     Some(obj) => {
+                        let id = *structure_id;
+                        if !has_repair_budget(id) {
+                            // spent enough on this one for now, move on to another right away
+                            towers_target.remove(&tower_pos);
+                            self.run(towers_target, hostiles);
+                            return;
+                        }
                         if self.store().get_free_capacity(Some(ResourceType::Energy))
                             > self.store().get_capacity(Some(ResourceType::Energy)) as i32 / 2
                             && creeps.len() < 12
@@ -57,7 +91,9 @@ impl<'a> Tower<'a> {
                             towers_target.remove(&tower_pos);
                         }
                         let r = self.repair(&obj);
-                        if r != ReturnCode::Ok {
+                        if r == ReturnCode::Ok {
+                            record_repair(id, TOWER_REPAIR_COST);
+                        } else {
                             warn!("couldn't repair: {:?}", r);
                             towers_target.remove(&tower_pos);
                         }
@@ -78,7 +114,14 @@ impl<'a> Tower<'a> {
             },
             None => {
                 if hostiles.len() > 0 {
+                    let energy = self.store().get_used_capacity(Some(ResourceType::Energy));
+                    let capacity = self.store().get_capacity(Some(ResourceType::Energy));
                     for h in hostiles.iter() {
+                        let range = h.pos().get_range_to(tower_pos);
+                        if !worth_attacking(range, energy, capacity) {
+                            debug!("saving energy, hostile {} tiles away", range);
+                            continue;
+                        }
                         let mut found = false;
                         for body in h.body().iter() {
                             if body.part() == Part::Heal {
@@ -100,39 +143,35 @@ impl<'a> Tower<'a> {
                     //used too much energy already, need to save in case of an attack
                     return;
                 }
-                let object = room
+                if construction_surge {
+                    return;
+                }
+                let damaged: Vec<StructureObject> = room
                     .find(find::STRUCTURES)
                     .into_iter()
                     .filter(|o| o.as_attackable().is_some())
                     .filter(|o| o.structure_type() != StructureType::Controller)
+                    .filter(|o| should_maintain(o))
                     .filter(|o| {
                         o.as_attackable().unwrap().hits()
                             < o.as_attackable().unwrap().hits_max() / 3
                     })
                     .filter(|o| o.pos().get_range_to(self.pos()) <= 5)
-                    .reduce(|fewer_hp_obj, next_obj| {
-                        // here we are sure we only have attackables so we are free to use
-                        // unwrap. Here we pick the most closer object
-                        if let Some(next_attackable) = next_obj.as_attackable() {
-                            if let Some(fewer_hp_atttackble) = fewer_hp_obj.as_attackable() {
-                                if next_attackable.hits() < fewer_hp_atttackble.hits() {
-                                    next_obj
-                                } else {
-                                    fewer_hp_obj
-                                }
-                            } else {
-                                fewer_hp_obj
-                            }
-                        } else {
-                            warn!("could not get one of the attackables");
-                            fewer_hp_obj
-                        }
-                    })
-                    .take();
+                    .collect();
+                // the one with the fewest hits, skipping those that took enough repairs already
+                let candidates: Vec<(ObjectId<Structure>, u32)> = damaged
+                    .iter()
+                    .map(|o| (o.as_structure().id(), o.as_attackable().unwrap().hits()))
+                    .collect();
+                let object = pick_within_budget(&candidates).map(|i| &damaged[i]);
                 match object {
                     Some(obj) => {
-                        towers_target
-                            .insert(tower_pos, TowerTarget::Repair(obj.as_structure().id()));
+                        let id = obj.as_structure().id();
+                        towers_target.insert(tower_pos, TowerTarget::Repair(id));
+                        // start on it this tick rather than the next
+                        if self.repair(obj.as_structure()) == ReturnCode::Ok {
+                            record_repair(id, TOWER_REPAIR_COST);
+                        }
                         return;
                     }
                     None => {
@@ -143,3 +182,134 @@ impl<'a> Tower<'a> {
         }
     }
 }
+
+/// Damage drops with range, so a far hostile is only worth the energy when the tower has plenty.
+pub fn worth_attacking(range: u32, energy: u32, capacity: u32) -> bool {
+    range <= TOWER_EFFECTIVE_RANGE || energy * 100 >= capacity * TOWER_SURPLUS_ENERGY_PERCENT
+}
+
+/// During a construction surge in peace time, repairs wait so the energy haulers bring goes to
+/// the builders.
+pub fn holds_repairs(construction_sites: usize, hostiles: bool) -> bool {
+    TOWER_HOLD_REPAIR_SITES > 0 && construction_sites >= TOWER_HOLD_REPAIR_SITES && !hostiles
+}
+
+/// Hands the wounded creep to the towers in turn until one heals it, the others go on with
+/// their usual job.
+pub fn triage<T, W>(
+    towers: &[T],
+    mut wounded: Option<&W>,
+    mut save: impl FnMut(&T, &W) -> bool,
+    mut run: impl FnMut(&T),
+) {
+    for tower in towers {
+        if let Some(w) = wounded {
+            if save(tower, w) {
+                wounded = None;
+                continue;
+            }
+        }
+        run(tower);
+    }
+}
+
+/// Finds the most wounded of my creeps in the room, if it is below the critical hits threshold.
+pub fn find_critically_wounded(room: &Room) -> Option<ScreepsCreep> {
+    let mut creeps = room.find(find::MY_CREEPS);
+    let hits: Vec<(u32, u32)> = creeps.iter().map(|c| (c.hits(), c.hits_max())).collect();
+    pick_most_wounded(&hits).map(|i| creeps.swap_remove(i))
+}
+
+/// Index of the creep with the lowest share of hits left, among those below
+/// `TOWER_CRITICAL_HITS_PERCENT`.
+pub fn pick_most_wounded(hits: &[(u32, u32)]) -> Option<usize> {
+    hits.iter()
+        .enumerate()
+        .filter(|(_, (hits, hits_max))| hits * 100 < hits_max * TOWER_CRITICAL_HITS_PERCENT)
+        .min_by(|(_, (a, a_max)), (_, (b, b_max))| (a * b_max).cmp(&(b * a_max)))
+        .map(|(i, _)| i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn healthy_creeps_are_left_to_the_healers() {
+        assert_eq!(pick_most_wounded(&[(1000, 1000), (500, 1000)]), None);
+        assert_eq!(pick_most_wounded(&[]), None);
+    }
+
+    #[test]
+    fn the_most_wounded_share_gets_healed() {
+        // 300/1000 is worse off than 100/200 despite having more hits
+        assert_eq!(
+            pick_most_wounded(&[(100, 200), (300, 1000), (900, 1000)]),
+            Some(1)
+        );
+        assert_eq!(pick_most_wounded(&[(50, 1000), (300, 1000)]), Some(0));
+    }
+
+    #[test]
+    fn close_hostiles_are_always_attacked() {
+        assert!(worth_attacking(TOWER_EFFECTIVE_RANGE, 10, 1000));
+    }
+
+    #[test]
+    fn far_hostiles_only_with_energy_to_spare() {
+        let plenty = TOWER_SURPLUS_ENERGY_PERCENT * 10;
+        assert!(!worth_attacking(
+            TOWER_EFFECTIVE_RANGE + 1,
+            plenty - 1,
+            1000
+        ));
+        assert!(worth_attacking(TOWER_EFFECTIVE_RANGE + 1, plenty, 1000));
+    }
+
+    #[test]
+    fn construction_surge_holds_peacetime_repairs() {
+        assert!(holds_repairs(TOWER_HOLD_REPAIR_SITES, false));
+        assert!(!holds_repairs(TOWER_HOLD_REPAIR_SITES - 1, false));
+    }
+
+    #[test]
+    fn hostiles_lift_the_repair_hold() {
+        assert!(!holds_repairs(TOWER_HOLD_REPAIR_SITES, true));
+    }
+
+    #[test]
+    fn one_tower_heals_while_the_others_attack() {
+        let mut healed = Vec::new();
+        let mut attacked = Vec::new();
+        triage(
+            &[1, 2, 3],
+            Some(&"defender"),
+            |t, _| {
+                healed.push(*t);
+                true
+            },
+            |t| attacked.push(*t),
+        );
+        assert_eq!(healed, vec![1]);
+        assert_eq!(attacked, vec![2, 3]);
+    }
+
+    #[test]
+    fn next_tower_heals_when_the_first_one_cannot() {
+        let mut attacked = Vec::new();
+        triage(
+            &[1, 2, 3],
+            Some(&"defender"),
+            |t, _| *t == 2,
+            |t| attacked.push(*t),
+        );
+        assert_eq!(attacked, vec![1, 3]);
+    }
+
+    #[test]
+    fn every_tower_attacks_without_a_wounded_creep() {
+        let mut attacked = Vec::new();
+        triage(&[1, 2], None::<&&str>, |_, _| true, |t| attacked.push(*t));
+        assert_eq!(attacked, vec![1, 2]);
+    }
+}
@@ -0,0 +1,83 @@
+use screeps::{prelude::*, RoomVisual, StructureSpawn, TextAlign, TextStyle};
+
+use crate::roles::role::Role;
+
+/// What the spawn overlay shows: why the room spawns what it does, or nothing at all.
+#[derive(Debug, PartialEq)]
+pub struct SpawnOverlay {
+    pub deficits: Vec<(Role, usize)>,
+    pub next: Option<Role>,
+    // cost of the next creep, when the room is still gathering energy for it
+    pub energy_needed: Option<u32>,
+    pub energy_available: u32,
+}
+
+impl SpawnOverlay {
+    pub fn lines(&self) -> Vec<String> {
+        let next = match &self.next {
+            Some(role) => role.to_string(),
+            None => "nothing".to_string(),
+        };
+        let energy = match self.energy_needed {
+            Some(needed) => format!("energy: {}/{}", self.energy_available, needed),
+            None => format!("energy: {}", self.energy_available),
+        };
+        let mut lines = vec![format!("next: {}", next), energy];
+        for (role, missing) in self.deficits.iter() {
+            lines.push(format!("{}: {} missing", role.to_string(), missing));
+        }
+        lines
+    }
+}
+
+/// Writes the overlay to the right of the spawn, a line per row.
+pub fn show_spawn_queue(spawn: &StructureSpawn, overlay: &SpawnOverlay) {
+    let pos = spawn.pos();
+    let visual = RoomVisual::new(Some(pos.room_name()));
+    let x = pos.x().u8() as f32 + 1.0;
+    for (i, line) in overlay.lines().into_iter().enumerate() {
+        let y = pos.y().u8() as f32 + i as f32 * 0.6;
+        visual.text(
+            x,
+            y,
+            line,
+            Some(TextStyle::default().font(0.5).align(TextAlign::Left)),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn overlay_shows_the_computed_deficits() {
+        let roles = vec![Role::Harvester, Role::Harvester, Role::Hauler];
+        let overlay = SpawnOverlay {
+            deficits: Role::deficits(&roles),
+            next: Some(Role::Hauler),
+            energy_needed: Some(550),
+            energy_available: 300,
+        };
+        assert_eq!(
+            overlay.lines(),
+            vec![
+                "next: HAULER",
+                "energy: 300/550",
+                "HAULER: 4 missing",
+                "BUILDER: 1 missing",
+            ]
+        );
+    }
+
+    #[test]
+    fn overlay_says_when_nothing_is_queued() {
+        let overlay = SpawnOverlay {
+            deficits: vec![],
+            next: None,
+            energy_needed: None,
+            energy_available: 800,
+        };
+        assert_eq!(overlay.lines(), vec!["next: nothing", "energy: 800"]);
+    }
+}
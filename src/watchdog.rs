@@ -0,0 +1,172 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use log::*;
+use screeps::{find, game, prelude::*, Part, ResourceType, StructureSpawn};
+
+use crate::config::{SPAWN_STARVING_ENERGY, SPAWN_STARVING_TICKS};
+use crate::creep::{assign_target, has_active_part};
+use crate::roles::role::Role;
+use crate::storage::*;
+
+// Starving ticks are counted here, memory only gets them when a spawn turns or stops starving
+thread_local! {
+    static STARVATION: RefCell<HashMap<String, u32>> = RefCell::new(HashMap::new());
+}
+
+/// Counts, per spawn name, the consecutive ticks a spawn has been starving, and once that lasts
+/// too long with no hauler carrying energy in the room, hands the closest creep carrying energy
+/// the job of feeding it. `persisted` holds the spawns found starving, so that survives a global
+/// reset. Returns whether it changed and needs writing to memory.
+pub fn check_spawn_starvation(
+    persisted: &mut HashMap<String, u32>,
+    creeps_target: &mut HashMap<String, CreepTarget>,
+) -> bool {
+    let mut changed = false;
+    let spawn_names: Vec<String> = game::spawns().values().map(|s| s.name().into()).collect();
+    let before = persisted.len();
+    persisted.retain(|name, _| spawn_names.contains(name));
+    changed |= before != persisted.len();
+    STARVATION.with(|s| s.borrow_mut().retain(|name, _| spawn_names.contains(name)));
+
+    for spawn in game::spawns().values() {
+        let name: String = spawn.name().into();
+        let energy = spawn.store().get_used_capacity(Some(ResourceType::Energy));
+        let starving = energy < SPAWN_STARVING_ENERGY && spawn.spawning().is_none();
+        let counted = STARVATION
+            .with(|s| s.borrow().get(&name).copied())
+            .or_else(|| persisted.get(&name).copied())
+            .unwrap_or(0);
+        let (ticks, persist) = starvation_step(counted, starving);
+        STARVATION.with(|s| s.borrow_mut().insert(name.clone(), ticks));
+        if persist {
+            if ticks == 0 {
+                persisted.remove(&name);
+            } else {
+                persisted.insert(name.clone(), ticks);
+            }
+            changed = true;
+        }
+        let already_fed = creeps_target.values().any(|t| match t {
+            CreepTarget::FeedSpawn(id) => *id == spawn.id(),
+            _ => false,
+        });
+        if !needs_feeder(ticks, already_fed, || hauler_has_energy(&spawn)) {
+            continue;
+        }
+        match find_feeder(&spawn) {
+            Some(feeder) => {
+                warn!(
+                    "spawn {} starving for {} ticks, {} will feed it",
+                    name,
+                    ticks,
+                    feeder.name()
+                );
+                assign_target(creeps_target, &feeder, CreepTarget::FeedSpawn(spawn.id()));
+            }
+            None => {
+                warn!(
+                    "spawn {} starving for {} ticks and nobody carries energy",
+                    name, ticks
+                );
+            }
+        }
+    }
+    changed
+}
+
+/// The starving ticks after this one, and whether memory needs them: only when the spawn crosses
+/// `SPAWN_STARVING_TICKS` or recovers after having crossed it.
+pub fn starvation_step(ticks: u32, starving: bool) -> (u32, bool) {
+    if starving {
+        let ticks = ticks + 1;
+        (ticks, ticks == SPAWN_STARVING_TICKS)
+    } else {
+        (0, ticks >= SPAWN_STARVING_TICKS)
+    }
+}
+
+/// A creep is sent to feed the spawn once it starved for `SPAWN_STARVING_TICKS`, unless one is
+/// already on it or a hauler carrying energy is around to do it.
+pub fn needs_feeder(
+    ticks: u32,
+    already_fed: bool,
+    hauler_has_energy: impl FnOnce() -> bool,
+) -> bool {
+    ticks >= SPAWN_STARVING_TICKS && !already_fed && !hauler_has_energy()
+}
+
+fn hauler_has_energy(spawn: &StructureSpawn) -> bool {
+    let creeps = spawn.room().unwrap().find(find::MY_CREEPS);
+    CREEPS_ROLE.with(|creeps_role_refcell| {
+        let creeps_role = creeps_role_refcell.borrow();
+        creeps.iter().any(|c| {
+            creeps_role.get(&c.name()) == Some(&Role::Hauler)
+                && c.store().get_used_capacity(Some(ResourceType::Energy)) > 0
+        })
+    })
+}
+
+/// The closest creep of the spawn's room that can carry energy and has some.
+fn find_feeder(spawn: &StructureSpawn) -> Option<screeps::Creep> {
+    let spawn_pos = spawn.pos();
+    spawn
+        .room()
+        .unwrap()
+        .find(find::MY_CREEPS)
+        .into_iter()
+        .filter(|c| !c.spawning())
+        .filter(|c| has_active_part(c, Part::Carry))
+        .filter(|c| c.store().get_used_capacity(Some(ResourceType::Energy)) > 0)
+        .reduce(|closer, next| {
+            if next.pos().get_range_to(spawn_pos) < closer.pos().get_range_to(spawn_pos) {
+                next
+            } else {
+                closer
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starving_ticks_are_counted_and_reset() {
+        assert_eq!(starvation_step(0, true).0, 1);
+        assert_eq!(starvation_step(7, true).0, 8);
+        assert_eq!(starvation_step(7, false).0, 0);
+    }
+
+    #[test]
+    fn memory_is_only_written_when_crossing_the_threshold() {
+        let mut ticks = 0;
+        let mut writes = 0;
+        for _ in 0..SPAWN_STARVING_TICKS * 2 {
+            let (next, persist) = starvation_step(ticks, true);
+            ticks = next;
+            writes += persist as u32;
+        }
+        assert_eq!(writes, 1);
+        // recovering after being reported clears it from memory
+        assert_eq!(starvation_step(ticks, false), (0, true));
+        // recovering before that leaves memory alone
+        assert_eq!(starvation_step(SPAWN_STARVING_TICKS - 1, false), (0, false));
+    }
+
+    #[test]
+    fn long_starvation_without_a_loaded_hauler_sends_a_feeder() {
+        let mut ticks = 0;
+        for _ in 0..SPAWN_STARVING_TICKS {
+            ticks = starvation_step(ticks, true).0;
+        }
+        assert!(needs_feeder(ticks, false, || false));
+        assert!(!needs_feeder(ticks - 1, false, || false));
+    }
+
+    #[test]
+    fn fed_spawn_or_loaded_hauler_needs_no_feeder() {
+        assert!(!needs_feeder(SPAWN_STARVING_TICKS, true, || false));
+        assert!(!needs_feeder(SPAWN_STARVING_TICKS, false, || true));
+    }
+}